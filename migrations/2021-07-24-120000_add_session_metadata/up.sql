@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE sessions
+    ADD COLUMN user_agent VARCHAR(255),
+    ADD COLUMN ip_address VARCHAR(45),
+    ADD COLUMN last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
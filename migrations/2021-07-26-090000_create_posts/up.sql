@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE posts (
+    id INT NOT NULL AUTO_INCREMENT,
+    author_id INT NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    slug VARCHAR(255) NOT NULL UNIQUE,
+    body TEXT NOT NULL,
+    status VARCHAR(32) NOT NULL DEFAULT 'draft',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    FOREIGN KEY (author_id) REFERENCES users (id) ON DELETE CASCADE
+);
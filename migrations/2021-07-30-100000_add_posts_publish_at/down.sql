@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX posts_status_publish_at ON posts;
+ALTER TABLE posts DROP COLUMN publish_at;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE posts ADD COLUMN publish_at TIMESTAMP NULL DEFAULT NULL;
+CREATE INDEX posts_status_publish_at ON posts (status, publish_at);
@@ -0,0 +1,53 @@
+-- Your SQL goes here
+CREATE TABLE roles (
+    id INT NOT NULL AUTO_INCREMENT,
+    name VARCHAR(32) NOT NULL UNIQUE,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE permissions (
+    id INT NOT NULL AUTO_INCREMENT,
+    name VARCHAR(64) NOT NULL UNIQUE,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE role_permissions (
+    role_id INT NOT NULL,
+    permission_id INT NOT NULL,
+    PRIMARY KEY (role_id, permission_id),
+    FOREIGN KEY (role_id) REFERENCES roles (id) ON DELETE CASCADE,
+    FOREIGN KEY (permission_id) REFERENCES permissions (id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_roles (
+    user_id INT NOT NULL,
+    role_id INT NOT NULL,
+    PRIMARY KEY (user_id, role_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (role_id) REFERENCES roles (id) ON DELETE CASCADE
+);
+
+INSERT INTO roles (name) VALUES ('reader'), ('author'), ('editor'), ('admin');
+
+INSERT INTO permissions (name) VALUES
+    ('comments:write'),
+    ('posts:write'),
+    ('posts:edit_any'),
+    ('comments:moderate'),
+    ('users:read'),
+    ('users:manage');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT r.id, p.id FROM roles r JOIN permissions p
+WHERE (r.name = 'reader' AND p.name IN ('comments:write'))
+   OR (r.name = 'author' AND p.name IN ('comments:write', 'posts:write'))
+   OR (r.name = 'editor' AND p.name IN ('comments:write', 'posts:write', 'posts:edit_any',
+                                        'comments:moderate'))
+   OR r.name = 'admin';
+
+-- Everyone may keep commenting; whoever already wrote posts may keep writing.
+INSERT INTO user_roles (user_id, role_id)
+SELECT u.id, r.id FROM users u JOIN roles r WHERE r.name = 'reader';
+
+INSERT INTO user_roles (user_id, role_id)
+SELECT DISTINCT p.author_id, r.id FROM posts p JOIN roles r WHERE r.name = 'author';
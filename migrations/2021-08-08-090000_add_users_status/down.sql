@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    DROP COLUMN disabled_reason,
+    DROP COLUMN locked_until,
+    DROP COLUMN is_active;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE users
+    ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN locked_until TIMESTAMP NULL DEFAULT NULL,
+    ADD COLUMN disabled_reason VARCHAR(255) NULL DEFAULT NULL;
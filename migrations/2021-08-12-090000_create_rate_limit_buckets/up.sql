@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE rate_limit_buckets (
+    bucket_key VARCHAR(255) NOT NULL,
+    tokens DOUBLE NOT NULL,
+    updated_at TIMESTAMP(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    PRIMARY KEY (bucket_key)
+);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users
+    ADD COLUMN email VARCHAR(255) NULL DEFAULT NULL UNIQUE;
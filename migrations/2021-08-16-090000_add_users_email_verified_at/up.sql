@@ -0,0 +1,4 @@
+-- Your SQL goes here
+UPDATE users SET email = LOWER(email) WHERE email IS NOT NULL;
+ALTER TABLE users
+    ADD COLUMN email_verified_at TIMESTAMP NULL DEFAULT NULL;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE sessions
+    ADD COLUMN pending_2fa BOOLEAN NOT NULL DEFAULT FALSE;
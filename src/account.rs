@@ -0,0 +1,106 @@
+//! Changes logged-in users make to their own account.
+//!
+//! Both changes ask for the current password, checked by `lockout::authenticate`
+//! like a login, so a stolen session can't be used to guess it.  Changing the
+//! password ends every other session of the account and voids its password
+//! reset links.
+
+use super::auth::hash_password;
+use super::db::{
+    end_other_user_sessions, get_user_by_id, get_user_by_username, remove_password_reset_tokens,
+    update_user_password, update_user_username,
+};
+use super::errors::{AccountError, AuthError};
+use super::forms::{ChangePasswordForm, ChangeUsernameForm, UserLogin};
+use super::lockout;
+use super::models::User;
+use super::sessions::CurrentSession;
+
+use diesel::{Connection, MysqlConnection, OptionalExtension};
+
+/// Fails unless `password` is the current password of `current`'s account.
+fn confirm_password(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    password: &str,
+    ip: Option<&str>,
+) -> Result<(), AccountError> {
+    let credentials = UserLogin {
+        username: current.user.username.clone(),
+        password: password.to_string(),
+    };
+    lockout::authenticate(conn, &credentials, ip)?;
+    Ok(())
+}
+
+/// Set the password of `current`'s account from `form`, ending all of its
+/// sessions but `current`.
+pub fn change_password(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    form: &ChangePasswordForm,
+    ip: Option<&str>,
+) -> Result<(), AccountError> {
+    form.validate()?;
+    confirm_password(conn, current, &form.current_password, ip)?;
+    let hashed = hash_password(&form.password)?;
+    conn.transaction(|| {
+        update_user_password(conn, current.user.id, &hashed)?;
+        end_other_user_sessions(conn, current.user.id, &current.session_key)?;
+        remove_password_reset_tokens(conn, current.user.id)?;
+        Ok(())
+    })
+}
+
+/// Rename `current`'s account as asked in `form` and return it.  Fails with
+/// `AuthError::UserAlreadyExists` if another user has the name, as at signup.
+pub fn change_username(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    form: &ChangeUsernameForm,
+    ip: Option<&str>,
+) -> Result<User, AccountError> {
+    form.validate()?;
+    confirm_password(conn, current, &form.password, ip)?;
+    let username = form.username.trim();
+    conn.transaction(|| {
+        let taken = get_user_by_username(conn, username)
+            .optional()?
+            .is_some_and(|usr| usr.id != current.user.id);
+        if taken {
+            return Err(AuthError::UserAlreadyExists.into());
+        }
+        update_user_username(conn, current.user.id, username)?;
+        Ok(get_user_by_id(conn, current.user.id)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::FormError;
+
+    #[test]
+    fn changes_validated_like_signup() {
+        let mut form = ChangePasswordForm {
+            current_password: String::from("password123"),
+            password: String::from("short"),
+            password_confirm: String::from("short"),
+        };
+        assert!(matches!(form.validate(), Err(FormError::FieldTooShort(_))));
+        form.password = String::from("password456");
+        assert!(matches!(form.validate(), Err(FormError::MismatchPasswords)));
+        form.password_confirm = String::from("password456");
+        assert!(form.validate().is_ok());
+
+        let mut form = ChangeUsernameForm {
+            username: String::from("ben"),
+            password: String::from("password123"),
+        };
+        assert!(form.validate().is_err());
+        form.username = String::from("bender@example.com");
+        assert!(form.validate().is_err());
+        form.username = String::from(" bender3001 ");
+        assert!(form.validate().is_ok());
+    }
+}
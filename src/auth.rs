@@ -1,11 +1,39 @@
-use super::db::get_user_by_username;
+use super::db::{get_user_by_login, get_user_permissions, get_user_roles, update_user_password};
 use super::errors::AuthError;
-use super::forms::UserSignup;
+use super::hashers::{self, is_password_hash};
+use super::models::User;
 use super::users::BaseUser;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use chrono::{NaiveDateTime, Utc};
 use diesel::MysqlConnection;
 use serde::{Deserialize, Serialize};
 
+/// Hash `password` with the configured hasher so it can be stored in `users.password`.
+///
+/// Example:
+///     let hashed = hash_password("password123").unwrap();
+///     assert!(is_password_hash(&hashed));
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    hashers::configured().hash(password)
+}
+
+/// Fails with `AccountLocked` if `account` is locked at `now`.
+pub fn ensure_unlocked(account: &User, now: NaiveDateTime) -> Result<(), AuthError> {
+    match account.locked_until {
+        Some(until) if account.is_locked(now) => Err(AuthError::AccountLocked { until }),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with `AccountDisabled` if `account` has been disabled.
+pub fn ensure_active(account: &User) -> Result<(), AuthError> {
+    if account.is_active {
+        return Ok(());
+    }
+    Err(AuthError::AccountDisabled {
+        reason: account.disabled_reason.clone(),
+    })
+}
+
 pub trait Auth<T = BaseUser, C = MysqlConnection>
 where
     T: Serialize + Deserialize<'static>,
@@ -15,13 +43,27 @@ where
     /// Getter method for `password`
     fn get_password(&self) -> &String;
 
-    /// Authenticate by verifying username and password
+    /// Authenticate by verifying username (or email address) and password,
+    /// returning the user with their roles and permissions.
+    ///
+    /// Locked accounts are turned away before the password is checked, so
+    /// guessing is pointless while the lock lasts.  Whether an account was
+    /// disabled is only revealed to someone who knows its password.
     fn authenticate(&self, conn: &MysqlConnection) -> Result<BaseUser, AuthError> {
-        self.verify_user(conn)
-            .and_then(|_| self.verify_password(conn))
+        let account =
+            get_user_by_login(conn, self.get_username()).map_err(|_| AuthError::UserNotFound)?;
+        ensure_unlocked(&account, Utc::now().naive_utc())?;
+        let usr = self.verify_password(conn)?;
+        ensure_active(&account)?;
+        Ok(BaseUser {
+            roles: get_user_roles(conn, usr.id)?,
+            permissions: get_user_permissions(conn, usr.id)?,
+            ..usr
+        })
     }
 
-    /// Check if user already exists in db.
+    /// Check if user already exists in db, looking `username` up as email
+    /// address if no user has that name.
     /// Example:
     ///     let usr = UserLogin {
     //       username: String::from("cyobero"),
@@ -29,16 +71,22 @@ where
     //
     //assert!(usr.verify_user().is_ok());
     fn verify_user(&self, conn: &MysqlConnection) -> Result<BaseUser, AuthError> {
-        get_user_by_username(conn, self.get_username())
+        get_user_by_login(conn, self.get_username())
             .map(|usr| BaseUser {
                 id: usr.get_id().to_owned(),
                 username: usr.username,
                 password: usr.password,
+                ..BaseUser::default()
             })
             .map_err(|_| AuthError::UserNotFound)
     }
 
     /// Check if password provided by user is correct.
+    ///
+    /// Rows created before passwords were hashed still hold plaintext; those are
+    /// compared directly once.  Plaintext rows and hashes made with an outdated
+    /// algorithm or cost are rehashed with the configured hasher on success.
+    ///
     /// Example:
     ///     let usr = UserLogin {
     ///         username: String::from("cyobero"),
@@ -47,13 +95,26 @@ where
     ///     assert!(usr.verify_password().is_ok());
     fn verify_password(&self, conn: &MysqlConnection) -> Result<BaseUser, AuthError> {
         self.verify_user(conn).and_then(|usr| {
-            let hashed = hash(usr.get_password(), DEFAULT_COST).unwrap();
-            let valid = verify(self.get_password(), &hashed).unwrap();
-            if valid == true {
-                Ok(usr)
+            let valid = if is_password_hash(usr.get_password()) {
+                hashers::verify(self.get_password(), usr.get_password())
             } else {
-                Err(AuthError::InvalidPassword)
+                usr.get_password() == self.get_password()
+            };
+
+            if !valid {
+                return Err(AuthError::InvalidPassword);
+            }
+
+            if hashers::configured().needs_rehash(usr.get_password()) {
+                let hashed = hash_password(self.get_password())?;
+                update_user_password(conn, *usr.get_id(), &hashed)?;
+                return Ok(BaseUser {
+                    password: hashed,
+                    ..usr
+                });
             }
+
+            Ok(usr)
         })
     }
 }
@@ -74,10 +135,63 @@ mod tests {
         assert!(usr.authenticate(&conn).is_ok());
     }
 
+    #[test]
+    fn locked_user_login_refused() {
+        use crate::db::{get_user_by_username, lock_user_until};
+        use crate::forms::UserLogin;
+        use chrono::Duration;
+        let conn = establish_connection().unwrap();
+        let id = get_user_by_username(&conn, "cyobero").unwrap().id;
+        let usr = UserLogin {
+            username: String::from("cyobero"),
+            password: String::from("password123"),
+        };
+        let until = Utc::now().naive_utc() + Duration::hours(1);
+        lock_user_until(&conn, id, Some(until)).unwrap();
+        let locked = usr.authenticate(&conn);
+        lock_user_until(&conn, id, None).unwrap();
+        assert!(matches!(locked, Err(AuthError::AccountLocked { .. })));
+        assert!(usr.authenticate(&conn).is_ok());
+    }
+
+    #[test]
+    fn password_hashed_with_configured_hasher() {
+        let hashed = hash_password("password123").unwrap();
+        assert!(is_password_hash(&hashed));
+        assert!(hashers::verify("password123", &hashed));
+        assert!(!hashers::configured().needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn disabled_and_locked_accounts_rejected() {
+        use chrono::Duration;
+        let now = Utc::now().naive_utc();
+        let mut account = User::new();
+        assert!(ensure_unlocked(&account, now).is_ok());
+        assert!(ensure_active(&account).is_ok());
+
+        account.locked_until = Some(now - Duration::minutes(1));
+        assert!(ensure_unlocked(&account, now).is_ok());
+        account.locked_until = Some(now + Duration::minutes(1));
+        assert!(matches!(
+            ensure_unlocked(&account, now),
+            Err(AuthError::AccountLocked { .. })
+        ));
+
+        account.is_active = false;
+        account.disabled_reason = Some(String::from("Spam"));
+        match ensure_active(&account) {
+            Err(AuthError::AccountDisabled { reason }) => assert_eq!(reason.unwrap(), "Spam"),
+            other => panic!("expected AccountDisabled, got {:?}", other),
+        }
+    }
+
     #[test]
     fn user_already_exists_error() {
+        use crate::forms::UserSignup;
         let usr = UserSignup {
             username: String::from("cyobero"),
+            email: String::from("cyobero@example.com"),
             password: String::from("password123"),
             password_confirm: String::from("password123"),
         };
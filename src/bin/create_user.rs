@@ -1,3 +1,4 @@
+use blog_user::auth::hash_password;
 use blog_user::db::{create_user, establish_connection};
 use blog_user::models::NewUser;
 use clap::{App, Arg};
@@ -26,7 +27,14 @@ fn main() {
     let username = matches.value_of("username").unwrap();
     let password = matches.value_of("password").unwrap();
 
-    let _ = create_user(&conn, NewUser { username, password }).expect("Failed to create user.");
+    let hashed = hash_password(password).expect("Failed to hash password.");
+    let item = NewUser {
+        username,
+        password: &hashed,
+        email: None,
+    };
+
+    let _ = create_user(&conn, item).expect("Failed to create user.");
 
     println!("User successfully created!");
 }
@@ -1,7 +1,19 @@
-use blog_user::db::{establish_connection, get_all_users};
+use blog_user::db::{establish_connection, get_users_page};
+use blog_user::users::UserQuery;
 
 fn main() {
     let conn = establish_connection().expect("Failed to establish connection.");
-    let res = get_all_users(&conn).expect("Failed to retrieve users.");
-    res.into_iter().for_each(|usr| println!("{:?}", usr));
+    let mut query = UserQuery {
+        limit: Some(UserQuery::MAX_LIMIT),
+        ..UserQuery::default()
+    };
+    loop {
+        let (items, total) = get_users_page(&conn, &query).expect("Failed to retrieve users.");
+        let seen = query.offset() + items.len() as i64;
+        items.into_iter().for_each(|usr| println!("{:?}", usr));
+        if seen >= total || seen == query.offset() {
+            break;
+        }
+        query.offset = Some(seen);
+    }
 }
@@ -0,0 +1,41 @@
+use blog_user::db::{establish_connection, get_user_by_username, grant_role, revoke_role};
+use clap::{App, Arg};
+
+fn main() {
+    let conn = establish_connection().expect("Failed to establish connection.");
+
+    let matches = App::new("Grant Role")
+        .author("Czar Yobero")
+        .arg(
+            Arg::with_name("username")
+                .short("u")
+                .index(1)
+                .long("username")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("role")
+                .short("r")
+                .long("role")
+                .index(2)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("revoke")
+                .long("revoke")
+                .help("Take the role away instead"),
+        )
+        .get_matches();
+
+    let username = matches.value_of("username").unwrap();
+    let role = matches.value_of("role").unwrap();
+
+    let user = get_user_by_username(&conn, username).expect("Failed to find user.");
+    if matches.is_present("revoke") {
+        revoke_role(&conn, user.id, role).expect("Failed to revoke role.");
+        println!("Role {} revoked from {}.", role, username);
+    } else {
+        grant_role(&conn, user.id, role).expect("Failed to grant role.");
+        println!("Role {} granted to {}.", role, username);
+    }
+}
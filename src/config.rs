@@ -0,0 +1,467 @@
+//! Configuration read from the environment.
+//!
+//! Links in mail point at `APP_URL` (default: `http://127.0.0.1:8000`).
+//!
+//! The cookie session is signed with the hex encoded key in `SESSION_KEY`, e.g.
+//! one made by `openssl rand -hex 32`.  To rotate the key, move the old one to
+//! `SESSION_PREVIOUS_KEYS`, a comma separated list: cookies signed with any of
+//! those are still accepted, and re-signed with the new key.
+//!
+//! `APP_ENV=production` refuses to start without a key of at least 32 random
+//! looking bytes, and marks the cookie `Secure`.  Otherwise a missing key is
+//! replaced with a random one, logging everybody out on every restart.  The
+//! cookie can be tuned with `SESSION_COOKIE_SECURE` (`true` or `false`),
+//! `SESSION_COOKIE_SAME_SITE` (`strict`, `lax` or `none`, default: `lax`) and
+//! `SESSION_COOKIE_MAX_AGE` in seconds (default: `SESSION_MAX_AGE`).  The cookie
+//! is always `HttpOnly`, as scripts have no business reading it.
+//!
+//! The session middleware comes from the config, wrapped outside the others:
+//!     App::new()
+//!         .wrap(SessionAuth)
+//!         .wrap(config.cookie_session())
+//!         .wrap(config.key_rotation())
+
+use super::errors::ConfigError;
+use super::sessions;
+
+use actix_session::CookieSession;
+use actix_web::{
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderValue},
+    Error,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand::{rngs::OsRng, RngCore};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Name of the session cookie.
+pub const COOKIE_NAME: &str = "actix-session";
+/// Shortest key accepted in production, in bytes.
+pub const MIN_KEY_LEN: usize = 32;
+
+/// Where the app is reachable from outside, without a trailing slash.
+pub fn app_url() -> String {
+    env::var("APP_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| String::from("http://127.0.0.1:8000"))
+}
+
+/// The environment the app runs in, from `APP_ENV`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Environment {
+    Development,
+    Production,
+}
+
+impl Environment {
+    /// `Production` if `APP_ENV` is `production`, `Development` otherwise.
+    pub fn from_env() -> Self {
+        match env::var("APP_ENV").as_deref() {
+            Ok("production") => Environment::Production,
+            _ => Environment::Development,
+        }
+    }
+}
+
+/// Key and cookie settings of the cookie session.
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub key: Vec<u8>,
+    /// Keys whose cookies are still accepted, see the module docs.
+    pub previous_keys: Vec<Vec<u8>>,
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Lifetime of the cookie in seconds.
+    pub max_age: i64,
+}
+
+impl SessionConfig {
+    /// Read the config for `environment` from the environment variables.
+    pub fn from_env(environment: Environment) -> Result<Self, ConfigError> {
+        SessionConfig::from_vars(environment, |name| env::var(name).ok())
+    }
+
+    /// Read the config for `environment` from the variables `var` looks up.
+    pub fn from_vars<F>(environment: Environment, var: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let production = environment == Environment::Production;
+        let key = match var("SESSION_KEY").filter(|v| !v.is_empty()) {
+            Some(hex) => parse_key("SESSION_KEY", &hex, production)?,
+            None if production => return Err(ConfigError::Missing("SESSION_KEY")),
+            None => {
+                eprintln!("SESSION_KEY is not set, using a random key until restart.");
+                let mut key = vec![0u8; MIN_KEY_LEN];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        let previous_keys = var("SESSION_PREVIOUS_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|hex| !hex.is_empty())
+            .map(|hex| parse_key("SESSION_PREVIOUS_KEYS", hex, production))
+            .collect::<Result<_, _>>()?;
+
+        let secure = match var("SESSION_COOKIE_SECURE").as_deref() {
+            None => production,
+            Some("true") | Some("1") => true,
+            Some("false") | Some("0") => false,
+            Some(value) => return Err(ConfigError::invalid("SESSION_COOKIE_SECURE", value)),
+        };
+        let same_site = match var("SESSION_COOKIE_SAME_SITE").as_deref() {
+            None | Some("lax") => SameSite::Lax,
+            Some("strict") => SameSite::Strict,
+            Some("none") => SameSite::None,
+            Some(value) => return Err(ConfigError::invalid("SESSION_COOKIE_SAME_SITE", value)),
+        };
+        let max_age = match var("SESSION_COOKIE_MAX_AGE") {
+            None => sessions::max_age().num_seconds(),
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| ConfigError::invalid("SESSION_COOKIE_MAX_AGE", &value))?,
+        };
+
+        Ok(SessionConfig {
+            key,
+            previous_keys,
+            secure,
+            same_site,
+            max_age,
+        })
+    }
+
+    /// The `CookieSession` middleware signing with the current key.
+    pub fn cookie_session(&self) -> CookieSession {
+        CookieSession::signed(&self.key)
+            .name(COOKIE_NAME)
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site)
+            .max_age(self.max_age)
+    }
+
+    /// The `KeyRotation` middleware accepting cookies of the previous keys.
+    pub fn key_rotation(&self) -> KeyRotation {
+        KeyRotation {
+            key: Key::derive_from(&self.key),
+            previous_keys: self
+                .previous_keys
+                .iter()
+                .map(|key| Key::derive_from(key))
+                .collect(),
+            config: self.clone(),
+        }
+    }
+
+    /// The session cookie holding `value`, with the configured attributes.
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(COOKIE_NAME, value)
+            .path("/")
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site)
+            .max_age(time::Duration::seconds(self.max_age))
+            .finish()
+    }
+}
+
+/// Decode the hex key `hex` of variable `name`.  Keys too short or too
+/// repetitive to be random are refused if `strict`, and only warned about
+/// otherwise.
+fn parse_key(name: &str, hex: &str, strict: bool) -> Result<Vec<u8>, ConfigError> {
+    let key = decode_hex(hex).ok_or_else(|| ConfigError::InvalidKey(name.to_string()))?;
+    if key.len() < MIN_KEY_LEN && !strict {
+        // `CookieSession` needs 32 bytes, so pad short development keys.
+        eprintln!("{} is shorter than {} bytes.", name, MIN_KEY_LEN);
+        return Ok(key.iter().cycle().take(MIN_KEY_LEN).copied().collect());
+    }
+    if is_weak(&key) {
+        if strict {
+            return Err(ConfigError::WeakKey(name.to_string()));
+        }
+        eprintln!("{} is weak, do not use it in production.", name);
+    }
+    Ok(key)
+}
+
+/// Returns `true` if `key` is shorter than `MIN_KEY_LEN` bytes or repeats too
+/// many bytes to have been randomly generated, such as `[0; 32]`.
+pub fn is_weak(key: &[u8]) -> bool {
+    let distinct = key.iter().collect::<HashSet<_>>().len();
+    key.len() < MIN_KEY_LEN || distinct < MIN_KEY_LEN / 2
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Middleware re-signing session cookies signed with a previous key with the
+/// current one, so `CookieSession` accepts them.  The browser is sent the
+/// re-signed cookie unless the response sets a new one anyway.
+///
+/// Must be wrapped outside `CookieSession`, see `SessionConfig::key_rotation`.
+pub struct KeyRotation {
+    key: Key,
+    previous_keys: Vec<Key>,
+    config: SessionConfig,
+}
+
+impl KeyRotation {
+    /// The cookie `original`, re-signed with the current key if it was signed
+    /// with a previous one.
+    fn resign(&self, original: &Cookie<'static>) -> Option<Cookie<'static>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(original.clone());
+        if jar.signed(&self.key).get(COOKIE_NAME).is_some() {
+            return None;
+        }
+        let value = self
+            .previous_keys
+            .iter()
+            .find_map(|key| jar.signed(key).get(COOKIE_NAME))?
+            .value()
+            .to_string();
+        let mut jar = CookieJar::new();
+        jar.signed(&self.key).add(self.config.cookie(value));
+        jar.get(COOKIE_NAME).cloned()
+    }
+}
+
+impl<S, B> Transform<S> for KeyRotation
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = KeyRotationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(KeyRotationMiddleware {
+            rotation: Rc::new(KeyRotation {
+                key: self.key.clone(),
+                previous_keys: self.previous_keys.clone(),
+                config: self.config.clone(),
+            }),
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct KeyRotationMiddleware<S> {
+    rotation: Rc<KeyRotation>,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for KeyRotationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        // Parsed by hand, as `req.cookies()` would cache the cookies before
+        // they are replaced.
+        let mut resigned = None;
+        if !self.rotation.previous_keys.is_empty() {
+            let mut pairs = Vec::new();
+            for value in req.headers().get_all(header::COOKIE) {
+                for pair in value.to_str().unwrap_or_default().split(';') {
+                    match Cookie::parse_encoded(pair.trim().to_string()) {
+                        Ok(cookie) if cookie.name() == COOKIE_NAME => {
+                            match self.rotation.resign(&cookie) {
+                                Some(cookie) => {
+                                    let value = cookie.value().to_string();
+                                    pairs.push(
+                                        Cookie::new(COOKIE_NAME, value).encoded().to_string(),
+                                    );
+                                    resigned = Some(cookie);
+                                }
+                                None => pairs.push(pair.trim().to_string()),
+                            }
+                        }
+                        _ => pairs.push(pair.trim().to_string()),
+                    }
+                }
+            }
+            if resigned.is_some() {
+                if let Ok(value) = HeaderValue::from_str(&pairs.join("; ")) {
+                    req.headers_mut().insert(header::COOKIE, value);
+                }
+            }
+        }
+
+        Box::pin(async move {
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            if let Some(cookie) = resigned {
+                let sets_cookie = res
+                    .headers()
+                    .get_all(header::SET_COOKIE)
+                    .filter_map(|v| v.to_str().ok())
+                    .any(|v| v.starts_with(&format!("{}=", COOKIE_NAME)));
+                if !sets_cookie {
+                    if let Ok(value) = HeaderValue::from_str(&cookie.encoded().to_string()) {
+                        res.headers_mut().append(header::SET_COOKIE, value);
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::generate_session_key;
+    use actix_session::Session;
+    use actix_web::{rt, test, web, App, HttpResponse};
+    use std::collections::HashMap;
+
+    fn config(
+        environment: Environment,
+        vars: &[(&str, &str)],
+    ) -> Result<SessionConfig, ConfigError> {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        SessionConfig::from_vars(environment, |name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn production_refuses_weak_keys() {
+        use Environment::*;
+        let zeros = "00".repeat(32);
+        let random = generate_session_key();
+
+        assert_eq!(
+            config(Production, &[]).err(),
+            Some(ConfigError::Missing("SESSION_KEY"))
+        );
+        let weak = Some(ConfigError::WeakKey(String::from("SESSION_KEY")));
+        assert_eq!(config(Production, &[("SESSION_KEY", &zeros)]).err(), weak);
+        assert_eq!(
+            config(Production, &[("SESSION_KEY", &random[..32])]).err(),
+            weak
+        );
+        assert_eq!(
+            config(Production, &[("SESSION_KEY", "not hex")]).err(),
+            Some(ConfigError::InvalidKey(String::from("SESSION_KEY")))
+        );
+        assert_eq!(
+            config(
+                Production,
+                &[("SESSION_KEY", &random), ("SESSION_PREVIOUS_KEYS", &zeros)]
+            )
+            .err(),
+            Some(ConfigError::WeakKey(String::from("SESSION_PREVIOUS_KEYS")))
+        );
+
+        let production = config(Production, &[("SESSION_KEY", &random)]).unwrap();
+        assert!(production.secure);
+        assert_eq!(production.key.len(), 32);
+
+        let development = config(Development, &[("SESSION_KEY", &zeros)]).unwrap();
+        assert!(!development.secure);
+        assert_eq!(config(Development, &[]).unwrap().key.len(), 32);
+        assert!(config(Development, &[("SESSION_COOKIE_SAME_SITE", "sometimes")]).is_err());
+    }
+
+    #[test]
+    fn cookies_of_previous_keys_accepted() {
+        let old_key = generate_session_key();
+        let new_key = generate_session_key();
+        let old = config(Environment::Development, &[("SESSION_KEY", &old_key)]).unwrap();
+        let new = config(
+            Environment::Development,
+            &[
+                ("SESSION_KEY", &new_key),
+                ("SESSION_PREVIOUS_KEYS", &old_key),
+            ],
+        )
+        .unwrap();
+
+        rt::System::new("test").block_on(async move {
+            let app = |config: SessionConfig| {
+                App::new()
+                    .wrap(config.cookie_session())
+                    .wrap(config.key_rotation())
+                    .route(
+                        "/set",
+                        web::get().to(|session: Session| async move {
+                            session.set("name", "bender")?;
+                            Ok::<_, Error>(HttpResponse::Ok().finish())
+                        }),
+                    )
+                    .route(
+                        "/get",
+                        web::get().to(|session: Session| async move {
+                            let name = session.get::<String>("name")?.unwrap_or_default();
+                            Ok::<_, Error>(HttpResponse::Ok().body(name))
+                        }),
+                    )
+            };
+            let mut old_app = test::init_service(app(old)).await;
+            let mut new_app = test::init_service(app(new.clone())).await;
+            let mut unrotated_app = test::init_service(app(SessionConfig {
+                previous_keys: Vec::new(),
+                ..new
+            }))
+            .await;
+
+            let req = test::TestRequest::with_uri("/set").to_request();
+            let res = test::call_service(&mut old_app, req).await;
+            let old_cookie = res.response().cookies().next().unwrap().into_owned();
+
+            let req = test::TestRequest::with_uri("/get")
+                .cookie(old_cookie.clone())
+                .to_request();
+            let res = test::call_service(&mut new_app, req).await;
+            let new_cookie = res.response().cookies().next().unwrap().into_owned();
+            assert_ne!(new_cookie.value(), old_cookie.value());
+            assert_eq!(test::read_body(res).await, "bender");
+
+            let req = test::TestRequest::with_uri("/get")
+                .cookie(new_cookie)
+                .to_request();
+            let res = test::call_service(&mut new_app, req).await;
+            assert_eq!(test::read_body(res).await, "bender");
+
+            let req = test::TestRequest::with_uri("/get")
+                .cookie(old_cookie)
+                .to_request();
+            let res = test::call_service(&mut unrotated_app, req).await;
+            assert_eq!(test::read_body(res).await, "");
+        });
+    }
+}
@@ -0,0 +1,227 @@
+//! CSRF tokens for HTML forms.
+//!
+//! Each browser session gets one random token, kept in the signed cookie
+//! session.  `handlers::render` passes it to every template as `csrf_token`,
+//! and forms carry it in a hidden field:
+//!     <input type="hidden" name="csrf_token" value="{{csrf_token}}">
+//!
+//! The `CsrfProtect` middleware then rejects every POST, PUT, PATCH and DELETE
+//! request without the token, so handlers need not check it themselves.  Scripts
+//! may send the token as `X-CSRF-Token` header instead.  Requests authenticated
+//! with `Authorization: Bearer` and JSON requests are let through, as other
+//! sites cannot make a browser send either without a CORS preflight.
+
+use super::errors::CsrfError;
+use super::forms::peek_form_field;
+use super::sessions::generate_session_key;
+
+use actix_session::{Session, UserSession};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpMessage,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Name of the cookie session field holding the token.
+pub const CSRF_KEY: &str = "csrf-token";
+/// Name of the form field carrying the token.
+pub const CSRF_FIELD: &str = "csrf_token";
+/// Name of the header carrying the token for scripts.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Returns the CSRF token of `session`, creating one if there is none yet.
+pub fn token(session: &Session) -> Result<String, Error> {
+    if let Some(token) = session.get::<String>(CSRF_KEY)? {
+        return Ok(token);
+    }
+    let token = generate_session_key();
+    session.set(CSRF_KEY, &token)?;
+    Ok(token)
+}
+
+/// Check `submitted` against the token of `session`.
+pub fn verify(session: &Session, submitted: &str) -> Result<(), CsrfError> {
+    match session.get::<String>(CSRF_KEY) {
+        Ok(Some(token)) if constant_time_eq(token.as_bytes(), submitted.as_bytes()) => Ok(()),
+        _ => Err(CsrfError),
+    }
+}
+
+/// Compare `a` and `b` in time independent of where they first differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns `true` if `req` changes state and may come from a form on another
+/// site, so must carry the token.
+fn needs_token(req: &ServiceRequest) -> bool {
+    let unsafe_method = matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("Bearer "));
+    unsafe_method && !bearer && req.content_type() != "application/json"
+}
+
+/// Middleware rejecting state changing requests without the session's CSRF
+/// token with `403 Forbidden`.
+///
+/// Must be wrapped inside `CookieSession`:
+///     App::new().wrap(CsrfProtect).wrap(SessionAuth).wrap(CookieSession::signed(&key))
+pub struct CsrfProtect;
+
+impl<S, B> Transform<S> for CsrfProtect
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfProtectMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfProtectMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct CsrfProtectMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for CsrfProtectMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            if needs_token(&req) {
+                let header = req
+                    .headers()
+                    .get(CSRF_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                let submitted = match header {
+                    Some(token) => Some(token),
+                    None => peek_form_field(&mut req, CSRF_FIELD).await?,
+                };
+                verify(&req.get_session(), submitted.as_deref().unwrap_or(""))?;
+            }
+
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::CookieSession;
+    use actix_web::{
+        dev::Service, http::StatusCode, rt, test, test::TestRequest, web, App, HttpResponse,
+    };
+
+    #[test]
+    fn token_issued_once_and_verified() {
+        let req = TestRequest::default().to_http_request();
+        let session = req.get_session();
+        assert!(verify(&session, "").is_err());
+
+        let token = token(&session).unwrap();
+        assert_eq!(super::token(&session).unwrap(), token);
+        assert!(verify(&session, &token).is_ok());
+        assert!(verify(&session, &token[1..]).is_err());
+        assert!(verify(&session, "").is_err());
+    }
+
+    /// Status of the response to `req`, including errors raised by middleware.
+    async fn status<S, R>(app: &mut S, req: R) -> StatusCode
+    where
+        S: Service<Request = R, Response = ServiceResponse, Error = Error>,
+    {
+        match app.call(req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn posts_without_token_rejected() {
+        rt::System::new("test").block_on(async {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(CsrfProtect)
+                    .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                    .route(
+                        "/",
+                        web::get().to(|session: Session| async move {
+                            token(&session).map(|t| HttpResponse::Ok().body(t))
+                        }),
+                    )
+                    .route("/", web::post().to(HttpResponse::Ok)),
+            )
+            .await;
+
+            let res = test::call_service(&mut app, TestRequest::get().to_request()).await;
+            let cookie = res.response().cookies().next().unwrap().into_owned();
+            let token = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            let form = |token: &str| {
+                TestRequest::post()
+                    .cookie(cookie.clone())
+                    .set_form(&[(CSRF_FIELD, token)])
+            };
+
+            let req = TestRequest::post().cookie(cookie.clone()).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+            let req = form("forged").to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+            let req = TestRequest::post()
+                .set_form(&[(CSRF_FIELD, token.as_str())])
+                .to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+
+            let req = form(&token).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+            let req = TestRequest::post()
+                .cookie(cookie.clone())
+                .header(CSRF_HEADER, token.as_str())
+                .to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+            let req = TestRequest::post()
+                .header(header::AUTHORIZATION, "Bearer 9f86d0")
+                .to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+            let req = TestRequest::post()
+                .set_json(&serde_json::json!({ "title": "Hello" }))
+                .to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+        });
+    }
+}
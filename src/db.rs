@@ -1,26 +1,86 @@
 //! Module for database interactions
 
 use super::auth::Auth;
-use super::models::{NewUser, NewUserSession, User};
-use super::schema::{sessions, users};
-use diesel::{mysql::MysqlConnection, Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+use super::models::{
+    Comment, EmailVerificationToken, NewComment, NewEmailVerificationToken, NewPasswordResetToken,
+    NewPost, NewPostTag, NewRecoveryCode, NewTag, NewUser, NewUserRole, NewUserSession,
+    NewUserTotp, NewWebauthnChallenge, NewWebauthnCredential, PasswordResetToken, Post,
+    PostChanges, RateLimitBucket, Tag, User, UserSession, UserTotp, WebauthnChallenge,
+    WebauthnCredential,
+};
+use super::schema::{
+    comments, email_verification_tokens, login_failures, password_reset_tokens, permissions,
+    post_tags, posts, rate_limit_buckets, recovery_codes, role_permissions, roles, sessions, tags,
+    user_roles, user_totp, users, webauthn_challenges, webauthn_credentials,
+};
+use super::users::{normalize_email, SortOrder, UserQuery, UserResponse, UserSort};
+use chrono::NaiveDateTime;
+use diesel::{
+    mysql::{Mysql, MysqlConnection},
+    sql_types::{Timestamp, Varchar},
+    Connection, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    TextExpressionMethods,
+};
 use std::env;
 
 type DieselError = diesel::result::Error;
 
-/// Returns all user records from db.
+no_arg_sql_function!(
+    last_insert_id,
+    diesel::sql_types::Unsigned<diesel::sql_types::Bigint>
+);
+
+/// Filters of `query` applied to the `users` table.
+fn filtered_users(query: &UserQuery) -> users::BoxedQuery<'_, Mysql> {
+    let mut q = users::table.into_boxed();
+    if let Some(pattern) = query.like_pattern() {
+        q = q.filter(users::username.like(pattern));
+    }
+    if let Some(after) = query.created_after {
+        q = q.filter(users::created_at.ge(after));
+    }
+    if let Some(before) = query.created_before {
+        q = q.filter(users::created_at.lt(before));
+    }
+    q
+}
+
+/// Returns one page of the users matching `query`, and how many match in total.
+/// Passwords are never loaded.
 ///
 /// Examples:
-///     let users = get_all_users(pool).unwrap();
-///     assert_ne!(users.len(), 0);  // Passes if users already exist in db
-pub fn get_all_users(conn: &MysqlConnection) -> Result<Vec<User>, DieselError> {
-    let items = users::table.get_results(conn)?;
-    Ok(items)
+///     let query = UserQuery { prefix: Some("bender".into()), ..UserQuery::default() };
+///     let (users, total) = get_users_page(&conn, &query).unwrap();
+///     assert!(users.len() as i64 <= total);
+pub fn get_users_page(
+    conn: &MysqlConnection,
+    query: &UserQuery,
+) -> Result<(Vec<UserResponse>, i64), DieselError> {
+    let total = filtered_users(query).count().get_result(conn)?;
+    let mut q = filtered_users(query);
+    // `id` breaks ties so pages don't overlap.
+    q = match (query.sort, query.order) {
+        (UserSort::CreatedAt, SortOrder::Asc) => {
+            q.order((users::created_at.asc(), users::id.asc()))
+        }
+        (UserSort::CreatedAt, SortOrder::Desc) => {
+            q.order((users::created_at.desc(), users::id.desc()))
+        }
+        (UserSort::Username, SortOrder::Asc) => q.order(users::username.asc()),
+        (UserSort::Username, SortOrder::Desc) => q.order(users::username.desc()),
+    };
+    let items = q
+        .select((users::id, users::username, users::created_at))
+        .limit(query.limit())
+        .offset(query.offset())
+        .load(conn)?;
+    Ok((items, total))
 }
+
 /// Create new user record in db.  Example:
 ///     let username = String::from("testuser2");
 ///     let password = String::from("password123");
-///     let usr = create_user(&conn, NewUser { username, password });
+///     let usr = create_user(&conn, NewUser { username, password, email: None });
 ///     assert!(usr.is_ok()); // only passes the if `usr` not already in db
 pub fn create_user(conn: &MysqlConnection, item: NewUser) -> Result<usize, DieselError> {
     diesel::insert_into(users::table)
@@ -53,6 +113,81 @@ pub fn get_user_by_username(conn: &MysqlConnection, username_: &str) -> Result<U
     Ok(res)
 }
 
+/// Replace the stored password hash of user with given `id`.
+///
+/// Example:
+///     let hashed = hash_password("password123").unwrap();
+///     let res = update_user_password(&conn, 13, &hashed);
+///     assert_eq!(res, Ok(1));
+pub fn update_user_password(
+    conn: &MysqlConnection,
+    id_: i32,
+    password_: &str,
+) -> Result<usize, DieselError> {
+    diesel::update(users::table.filter(users::id.eq(id_)))
+        .set(users::password.eq(password_))
+        .execute(conn)
+}
+
+/// Rename user with given `id` to `username_`.  Fails with a unique
+/// violation if the name is taken.
+///
+/// Example:
+///     let res = update_user_username(&conn, 13, "bender3001");
+///     assert_eq!(res, Ok(1));
+pub fn update_user_username(
+    conn: &MysqlConnection,
+    id_: i32,
+    username_: &str,
+) -> Result<usize, DieselError> {
+    diesel::update(users::table.filter(users::id.eq(id_)))
+        .set(users::username.eq(username_))
+        .execute(conn)
+}
+
+/// Disable user with given `id` for `reason`, or enable them again with
+/// `active` set, which also clears the reason and any lock.
+///
+/// Example:
+///     set_user_active(&conn, 13, false, Some("Spam")).unwrap();
+///     assert!(!get_user_by_id(&conn, 13).unwrap().is_active);
+pub fn set_user_active(
+    conn: &MysqlConnection,
+    id_: i32,
+    active: bool,
+    reason: Option<&str>,
+) -> Result<usize, DieselError> {
+    let target = users::table.filter(users::id.eq(id_));
+    if active {
+        diesel::update(target)
+            .set((
+                users::is_active.eq(true),
+                users::disabled_reason.eq(None::<String>),
+                users::locked_until.eq(None::<NaiveDateTime>),
+            ))
+            .execute(conn)
+    } else {
+        diesel::update(target)
+            .set((
+                users::is_active.eq(false),
+                users::disabled_reason.eq(reason),
+            ))
+            .execute(conn)
+    }
+}
+
+/// Keep user with given `id` from logging in before `until`, or lift the
+/// lock if `None`.
+pub fn lock_user_until(
+    conn: &MysqlConnection,
+    id_: i32,
+    until: Option<NaiveDateTime>,
+) -> Result<usize, DieselError> {
+    diesel::update(users::table.filter(users::id.eq(id_)))
+        .set(users::locked_until.eq(until))
+        .execute(conn)
+}
+
 /// Removes user with given `id` from db.
 ///
 /// Example:
@@ -83,6 +218,72 @@ pub fn remove_user_by_username(
     Ok(res)
 }
 
+/// Returns the names of the roles of user with given `id`, sorted.
+///
+/// Example:
+///     let roles = get_user_roles(&conn, 13).unwrap();
+///     assert!(roles.contains(&String::from("reader")));
+pub fn get_user_roles(conn: &MysqlConnection, user_id_: i32) -> Result<Vec<String>, DieselError> {
+    user_roles::table
+        .inner_join(roles::table)
+        .filter(user_roles::user_id.eq(user_id_))
+        .order(roles::name.asc())
+        .select(roles::name)
+        .load(conn)
+}
+
+/// Returns the names of the permissions granted to user with given `id` by
+/// any of their roles, sorted.
+pub fn get_user_permissions(
+    conn: &MysqlConnection,
+    user_id_: i32,
+) -> Result<Vec<String>, DieselError> {
+    role_permissions::table
+        .inner_join(permissions::table)
+        .inner_join(user_roles::table.on(user_roles::role_id.eq(role_permissions::role_id)))
+        .filter(user_roles::user_id.eq(user_id_))
+        .order(permissions::name.asc())
+        .select(permissions::name)
+        .distinct()
+        .load(conn)
+}
+
+/// Give user with given `id` the role named `role`.  Returns `0` if they
+/// already had it.
+///
+/// Example:
+///     grant_role(&conn, 13, "author").unwrap();
+pub fn grant_role(conn: &MysqlConnection, user_id_: i32, role: &str) -> Result<usize, DieselError> {
+    let role_id_ = roles::table
+        .filter(roles::name.eq(role))
+        .select(roles::id)
+        .get_result(conn)?;
+    diesel::insert_or_ignore_into(user_roles::table)
+        .values(&NewUserRole {
+            user_id: user_id_,
+            role_id: role_id_,
+        })
+        .execute(conn)
+}
+
+/// Take the role named `role` away from user with given `id`.
+pub fn revoke_role(
+    conn: &MysqlConnection,
+    user_id_: i32,
+    role: &str,
+) -> Result<usize, DieselError> {
+    let role_id_: i32 = roles::table
+        .filter(roles::name.eq(role))
+        .select(roles::id)
+        .get_result(conn)?;
+    diesel::delete(
+        user_roles::table
+            .filter(user_roles::user_id.eq(user_id_))
+            .filter(user_roles::role_id.eq(role_id_)),
+    )
+    .execute(conn)
+}
+
 /// Create new `session` record in database
 /// Example:
 ///     let token = String::from("test-token");
@@ -98,12 +299,777 @@ pub fn create_user_session(
     Ok(res)
 }
 
-/// End current user session
-pub fn end_user_session(
+/// Query db for the user owning session `session_key`, ignoring sessions
+/// created before `since`.
+///
+/// Example:
+///     let since = Utc::now().naive_utc() - Duration::days(7);
+///     let usr = get_user_by_session_key(&conn, "test-token", since).unwrap();
+///     assert_eq!(usr.id, 42);
+pub fn get_user_by_session_key(
+    conn: &MysqlConnection,
+    session_key_: &str,
+    since: NaiveDateTime,
+) -> Result<User, DieselError> {
+    sessions::table
+        .inner_join(users::table)
+        .filter(sessions::session_key.eq(session_key_))
+        .filter(sessions::created_at.gt(since))
+        .filter(sessions::pending_2fa.eq(false))
+        .select(users::all_columns)
+        .get_result(conn)
+}
+
+/// Query db for session `session_key` awaiting its second login step,
+/// ignoring sessions created before `since`.
+pub fn get_pending_user_session(
+    conn: &MysqlConnection,
+    session_key_: &str,
+    since: NaiveDateTime,
+) -> Result<UserSession, DieselError> {
+    sessions::table
+        .filter(sessions::session_key.eq(session_key_))
+        .filter(sessions::created_at.gt(since))
+        .filter(sessions::pending_2fa.eq(true))
+        .get_result(conn)
+}
+
+/// Returns the sessions of user with given `id` created after `since`, most
+/// recently seen first.
+///
+/// Example:
+///     let since = Utc::now().naive_utc() - Duration::days(7);
+///     let res = get_user_sessions(&conn, 13, since).unwrap();
+///     assert!(res.iter().all(|s| s.user_id == 13));
+pub fn get_user_sessions(
+    conn: &MysqlConnection,
+    user_id_: i32,
+    since: NaiveDateTime,
+) -> Result<Vec<UserSession>, DieselError> {
+    sessions::table
+        .inner_join(users::table)
+        .filter(users::id.eq(user_id_))
+        .filter(sessions::created_at.gt(since))
+        .filter(sessions::pending_2fa.eq(false))
+        .select(sessions::all_columns)
+        .order(sessions::last_seen_at.desc())
+        .load(conn)
+}
+
+/// Set `last_seen_at` of session `session_key` to `now`, unless it was already
+/// seen after `stale_before`.  Skipping fresh rows keeps this from writing on
+/// every request.
+pub fn touch_user_session(
+    conn: &MysqlConnection,
+    session_key_: &str,
+    now: NaiveDateTime,
+    stale_before: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::update(
+        sessions::table
+            .filter(sessions::session_key.eq(session_key_))
+            .filter(sessions::last_seen_at.lt(stale_before)),
+    )
+    .set(sessions::last_seen_at.eq(now))
+    .execute(conn)
+}
+
+/// Removes session `session_key` only if it belongs to user with given `id`.
+pub fn remove_user_session(
+    conn: &MysqlConnection,
+    user_id_: i32,
+    session_key_: &str,
+) -> Result<usize, DieselError> {
+    diesel::delete(sessions::table)
+        .filter(sessions::session_key.eq(session_key_))
+        .filter(sessions::user_id.eq(user_id_))
+        .execute(conn)
+}
+
+/// Removes every session created before `before`, returning how many were removed.
+pub fn delete_sessions_before(
+    conn: &MysqlConnection,
+    before: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::delete(sessions::table)
+        .filter(sessions::created_at.le(before))
+        .execute(conn)
+}
+
+/// End current user session by removing its `session` record.
+///
+/// Example:
+///     let res = end_user_session(&conn, "test-token").unwrap();
+///     assert_eq!(res, 1);
+pub fn end_user_session(conn: &MysqlConnection, session_key_: &str) -> Result<usize, DieselError> {
+    diesel::delete(sessions::table)
+        .filter(sessions::session_key.eq(session_key_))
+        .execute(conn)
+}
+
+/// End every session of user with given `id`, returning how many were removed.
+pub fn end_all_user_sessions(conn: &MysqlConnection, user_id_: i32) -> Result<usize, DieselError> {
+    diesel::delete(sessions::table)
+        .filter(sessions::user_id.eq(user_id_))
+        .execute(conn)
+}
+
+/// End every session of user with given `id` but `keep`, returning how many
+/// were removed.
+pub fn end_other_user_sessions(
+    conn: &MysqlConnection,
+    user_id_: i32,
+    keep: &str,
+) -> Result<usize, DieselError> {
+    diesel::delete(sessions::table)
+        .filter(sessions::user_id.eq(user_id_))
+        .filter(sessions::session_key.ne(keep))
+        .execute(conn)
+}
+
+/// Count a failed login for `subject` (a username or an IP address) in
+/// `scope` at `now` and return how many have been counted.  Failures before
+/// `window_start` are forgotten.
+///
+/// Example:
+///     let failures = record_login_failure(&conn, "user", "bender", now, now - day).unwrap();
+///     assert!(failures >= 1);
+pub fn record_login_failure(
+    conn: &MysqlConnection,
+    scope: &str,
+    subject_: &str,
+    now: NaiveDateTime,
+    window_start: NaiveDateTime,
+) -> Result<i32, DieselError> {
+    // Counted in one statement so concurrent failures aren't lost.
+    diesel::sql_query(
+        "INSERT INTO login_failures (scope, subject, failures, last_failed_at) \
+         VALUES (?, ?, 1, ?) \
+         ON DUPLICATE KEY UPDATE \
+         failures = IF(last_failed_at < ?, 1, failures + 1), \
+         last_failed_at = VALUES(last_failed_at)",
+    )
+    .bind::<Varchar, _>(scope)
+    .bind::<Varchar, _>(subject_)
+    .bind::<Timestamp, _>(now)
+    .bind::<Timestamp, _>(window_start)
+    .execute(conn)?;
+    login_failures::table
+        .find((scope, subject_))
+        .select(login_failures::failures)
+        .get_result(conn)
+}
+
+/// Keep `subject` in `scope` from logging in before `until`.
+pub fn lock_login(
+    conn: &MysqlConnection,
+    scope: &str,
+    subject_: &str,
+    until: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::update(login_failures::table.find((scope, subject_)))
+        .set(login_failures::locked_until.eq(until))
+        .execute(conn)
+}
+
+/// Returns until when `subject` in `scope` is locked out, if it still is at `now`.
+pub fn get_login_lock(
+    conn: &MysqlConnection,
+    scope: &str,
+    subject_: &str,
+    now: NaiveDateTime,
+) -> Result<Option<NaiveDateTime>, DieselError> {
+    let until: Option<Option<NaiveDateTime>> = login_failures::table
+        .find((scope, subject_))
+        .filter(login_failures::locked_until.gt(now))
+        .select(login_failures::locked_until)
+        .get_result(conn)
+        .optional()?;
+    Ok(until.flatten())
+}
+
+/// Forget the failed logins and any lock of `subject` in `scope`.
+pub fn clear_login_failures(
+    conn: &MysqlConnection,
+    scope: &str,
+    subject_: &str,
+) -> Result<usize, DieselError> {
+    diesel::delete(login_failures::table.find((scope, subject_))).execute(conn)
+}
+
+/// Lock token bucket `key` for the rest of the transaction and return it,
+/// creating it from `fresh` first if it doesn't exist yet.
+pub fn lock_rate_limit_bucket(
+    conn: &MysqlConnection,
+    fresh: &RateLimitBucket,
+) -> Result<RateLimitBucket, DieselError> {
+    diesel::insert_or_ignore_into(rate_limit_buckets::table)
+        .values(fresh)
+        .execute(conn)?;
+    rate_limit_buckets::table
+        .find(&fresh.bucket_key)
+        .for_update()
+        .get_result(conn)
+}
+
+/// Store the new state of a token bucket.
+pub fn save_rate_limit_bucket(
+    conn: &MysqlConnection,
+    bucket: &RateLimitBucket,
+) -> Result<usize, DieselError> {
+    diesel::update(rate_limit_buckets::table.find(&bucket.bucket_key))
+        .set(bucket)
+        .execute(conn)
+}
+
+/// Query db for user with given `email`.  Addresses are stored lowercased,
+/// so `email_` matches regardless of case.
+pub fn get_user_by_email(conn: &MysqlConnection, email_: &str) -> Result<User, DieselError> {
+    users::table
+        .filter(users::email.eq(normalize_email(email_)))
+        .get_result(conn)
+}
+
+/// Query db for the user logging in as `login`: the user with that username,
+/// else the one with that email address.
+///
+/// Example:
+///     let res = get_user_by_login(&conn, "Bender@Example.com").unwrap();
+///     assert_eq!(res.username, "bender3000");
+pub fn get_user_by_login(conn: &MysqlConnection, login: &str) -> Result<User, DieselError> {
+    match get_user_by_username(conn, login) {
+        Err(DieselError::NotFound) if login.contains('@') => get_user_by_email(conn, login),
+        res => res,
+    }
+}
+
+/// Mark the email address of user with given `id` as verified at `at`.
+pub fn set_user_email_verified(
+    conn: &MysqlConnection,
+    id_: i32,
+    at: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::update(users::table.filter(users::id.eq(id_)))
+        .set(users::email_verified_at.eq(at))
+        .execute(conn)
+}
+
+/// Store an email verification token, given as its hash, dropping expired
+/// ones on the way.
+pub fn create_email_verification_token(
+    conn: &MysqlConnection,
+    item: NewEmailVerificationToken,
+    now: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::delete(
+        email_verification_tokens::table.filter(email_verification_tokens::expires_at.le(now)),
+    )
+    .execute(conn)?;
+    diesel::insert_into(email_verification_tokens::table)
+        .values(item)
+        .execute(conn)
+}
+
+/// Returns the email verification token with hash `token_hash_` if it is
+/// still valid at `now`, locked for the rest of the transaction.
+pub fn lock_email_verification_token(
+    conn: &MysqlConnection,
+    token_hash_: &str,
+    now: NaiveDateTime,
+) -> Result<Option<EmailVerificationToken>, DieselError> {
+    email_verification_tokens::table
+        .find(token_hash_)
+        .filter(email_verification_tokens::expires_at.gt(now))
+        .for_update()
+        .get_result(conn)
+        .optional()
+}
+
+/// Removes every email verification token of user with given `id`.
+pub fn remove_email_verification_tokens(
+    conn: &MysqlConnection,
+    id_: i32,
+) -> Result<usize, DieselError> {
+    diesel::delete(
+        email_verification_tokens::table.filter(email_verification_tokens::user_id.eq(id_)),
+    )
+    .execute(conn)
+}
+
+/// Store a password reset token, given as its hash, dropping expired ones
+/// on the way.
+pub fn create_password_reset_token(
+    conn: &MysqlConnection,
+    item: NewPasswordResetToken,
+    now: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::delete(password_reset_tokens::table.filter(password_reset_tokens::expires_at.le(now)))
+        .execute(conn)?;
+    diesel::insert_into(password_reset_tokens::table)
+        .values(item)
+        .execute(conn)
+}
+
+/// Returns the password reset token with hash `token_hash_` if it is still
+/// valid at `now`, locked for the rest of the transaction.
+pub fn lock_password_reset_token(
+    conn: &MysqlConnection,
+    token_hash_: &str,
+    now: NaiveDateTime,
+) -> Result<Option<PasswordResetToken>, DieselError> {
+    password_reset_tokens::table
+        .find(token_hash_)
+        .filter(password_reset_tokens::expires_at.gt(now))
+        .for_update()
+        .get_result(conn)
+        .optional()
+}
+
+/// Removes every password reset token of user with given `id`.
+pub fn remove_password_reset_tokens(
+    conn: &MysqlConnection,
+    id_: i32,
+) -> Result<usize, DieselError> {
+    diesel::delete(password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(id_)))
+        .execute(conn)
+}
+
+/// Returns the TOTP secret of user with given `id`, if they have one.
+pub fn get_user_totp(conn: &MysqlConnection, id_: i32) -> Result<Option<UserTotp>, DieselError> {
+    user_totp::table.find(id_).get_result(conn).optional()
+}
+
+/// Returns the TOTP secret of user with given `id`, locked for the rest of
+/// the transaction.
+pub fn lock_user_totp(conn: &MysqlConnection, id_: i32) -> Result<Option<UserTotp>, DieselError> {
+    user_totp::table
+        .find(id_)
+        .for_update()
+        .get_result(conn)
+        .optional()
+}
+
+/// Store a new, unconfirmed TOTP secret, replacing the user's previous one.
+pub fn create_user_totp(conn: &MysqlConnection, item: NewUserTotp) -> Result<usize, DieselError> {
+    diesel::delete(user_totp::table.find(item.user_id)).execute(conn)?;
+    diesel::insert_into(user_totp::table)
+        .values(item)
+        .execute(conn)
+}
+
+/// Record that user with given `id` used the code of time step `step` at
+/// `at`.  The first use confirms the secret.
+pub fn use_user_totp(
+    conn: &MysqlConnection,
+    id_: i32,
+    step: i64,
+    at: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    let totp = user_totp::table.find(id_);
+    diesel::update(totp.filter(user_totp::confirmed_at.is_null()))
+        .set(user_totp::confirmed_at.eq(at))
+        .execute(conn)?;
+    diesel::update(totp)
+        .set(user_totp::last_used_step.eq(step))
+        .execute(conn)
+}
+
+/// Removes the TOTP secret and recovery codes of user with given `id`.
+pub fn remove_user_totp(conn: &MysqlConnection, id_: i32) -> Result<usize, DieselError> {
+    diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id_))).execute(conn)?;
+    diesel::delete(user_totp::table.find(id_)).execute(conn)
+}
+
+/// Replace the recovery codes of user with given `id` by `items`.
+pub fn replace_recovery_codes(
+    conn: &MysqlConnection,
+    id_: i32,
+    items: &[NewRecoveryCode],
+) -> Result<usize, DieselError> {
+    diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id_))).execute(conn)?;
+    diesel::insert_into(recovery_codes::table)
+        .values(items)
+        .execute(conn)
+}
+
+/// Use up the recovery code of user with given `id` hashed as `code_hash_`,
+/// returning `1` if there was one.
+pub fn use_recovery_code(
+    conn: &MysqlConnection,
+    id_: i32,
+    code_hash_: &str,
+) -> Result<usize, DieselError> {
+    diesel::delete(
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(id_))
+            .filter(recovery_codes::code_hash.eq(code_hash_)),
+    )
+    .execute(conn)
+}
+
+/// Store a WebAuthn challenge, given as its hash, dropping expired ones on
+/// the way.
+pub fn create_webauthn_challenge(
+    conn: &MysqlConnection,
+    item: NewWebauthnChallenge,
+    now: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::delete(webauthn_challenges::table.filter(webauthn_challenges::expires_at.le(now)))
+        .execute(conn)?;
+    diesel::insert_into(webauthn_challenges::table)
+        .values(item)
+        .execute(conn)
+}
+
+/// Use up the WebAuthn challenge with hash `challenge_hash_` issued for
+/// `purpose_`, returning it if it is still valid at `now`.  Must run in a
+/// transaction.
+pub fn take_webauthn_challenge(
+    conn: &MysqlConnection,
+    challenge_hash_: &str,
+    purpose_: &str,
+    now: NaiveDateTime,
+) -> Result<Option<WebauthnChallenge>, DieselError> {
+    let challenge: Option<WebauthnChallenge> = webauthn_challenges::table
+        .find(challenge_hash_)
+        .filter(webauthn_challenges::purpose.eq(purpose_))
+        .filter(webauthn_challenges::expires_at.gt(now))
+        .for_update()
+        .get_result(conn)
+        .optional()?;
+    if challenge.is_some() {
+        diesel::delete(webauthn_challenges::table.find(challenge_hash_)).execute(conn)?;
+    }
+    Ok(challenge)
+}
+
+/// Store a newly registered passkey.
+pub fn create_webauthn_credential(
+    conn: &MysqlConnection,
+    item: NewWebauthnCredential,
+) -> Result<usize, DieselError> {
+    diesel::insert_into(webauthn_credentials::table)
+        .values(item)
+        .execute(conn)
+}
+
+/// Returns the passkey with the authenticator's `credential_id_`, locked for
+/// the rest of the transaction.
+pub fn lock_webauthn_credential(
+    conn: &MysqlConnection,
+    credential_id_: &[u8],
+) -> Result<Option<WebauthnCredential>, DieselError> {
+    webauthn_credentials::table
+        .filter(webauthn_credentials::credential_id.eq(credential_id_))
+        .for_update()
+        .get_result(conn)
+        .optional()
+}
+
+/// Returns the passkeys of user with given `id`, oldest first.
+pub fn get_user_webauthn_credentials(
+    conn: &MysqlConnection,
+    id_: i32,
+) -> Result<Vec<WebauthnCredential>, DieselError> {
+    webauthn_credentials::table
+        .filter(webauthn_credentials::user_id.eq(id_))
+        .order(webauthn_credentials::id.asc())
+        .load(conn)
+}
+
+/// Record that the passkey with given `id` logged its user in at `at`, with
+/// the authenticator's signature counter at `sign_count_`.
+pub fn use_webauthn_credential(
+    conn: &MysqlConnection,
+    id_: i32,
+    sign_count_: i64,
+    at: NaiveDateTime,
+) -> Result<usize, DieselError> {
+    diesel::update(webauthn_credentials::table.find(id_))
+        .set((
+            webauthn_credentials::sign_count.eq(sign_count_),
+            webauthn_credentials::last_used_at.eq(at),
+        ))
+        .execute(conn)
+}
+
+/// Removes the passkey with given `id` if it belongs to user `user_id_`.
+pub fn remove_webauthn_credential(
+    conn: &MysqlConnection,
+    user_id_: i32,
+    id_: i32,
+) -> Result<usize, DieselError> {
+    diesel::delete(
+        webauthn_credentials::table
+            .filter(webauthn_credentials::id.eq(id_))
+            .filter(webauthn_credentials::user_id.eq(user_id_)),
+    )
+    .execute(conn)
+}
+
+/// Create new post record in db.  Example:
+///     let item = NewPost { author_id: 13, title: "Hello", slug: "hello", body: "...", status: "draft" };
+///     let res = create_post(&conn, item);
+///     assert!(res.is_ok()); // only passes if slug `hello` isn't taken
+pub fn create_post(conn: &MysqlConnection, item: NewPost) -> Result<usize, DieselError> {
+    diesel::insert_into(posts::table)
+        .values(&item)
+        .execute(conn)
+}
+
+/// Query db for post with given `slug`.
+///
+/// Example:
+///     let res = get_post_by_slug(&conn, "hello").unwrap();
+///     assert_eq!(res.slug, "hello");
+pub fn get_post_by_slug(conn: &MysqlConnection, slug_: &str) -> Result<Post, DieselError> {
+    posts::table.filter(posts::slug.eq(slug_)).get_result(conn)
+}
+
+/// Query db for post with given `id`.
+pub fn get_post_by_id(conn: &MysqlConnection, id_: i32) -> Result<Post, DieselError> {
+    posts::table.filter(posts::id.eq(id_)).get_result(conn)
+}
+
+/// Returns `true` if a post with given `slug` exists.
+pub fn post_slug_exists(conn: &MysqlConnection, slug_: &str) -> Result<bool, DieselError> {
+    use diesel::dsl::{exists, select};
+    select(exists(posts::table.filter(posts::slug.eq(slug_)))).get_result(conn)
+}
+
+/// Returns up to `limit` posts with given `status`, newest first.
+pub fn get_posts_by_status(
     conn: &MysqlConnection,
-    session_key: &str,
-) -> Result<usize, std::io::Error> {
-    unimplemented!()
+    status_: &str,
+    limit: i64,
+) -> Result<Vec<Post>, DieselError> {
+    posts::table
+        .filter(posts::status.eq(status_))
+        .order(posts::created_at.desc())
+        .limit(limit)
+        .load(conn)
+}
+
+/// Apply `changes` to post with given `id`.
+pub fn update_post(
+    conn: &MysqlConnection,
+    id_: i32,
+    changes: PostChanges,
+) -> Result<usize, DieselError> {
+    diesel::update(posts::table.filter(posts::id.eq(id_)))
+        .set(&changes)
+        .execute(conn)
+}
+
+/// Publish every `scheduled` post whose `publish_at` is at or before `now`.
+/// Returns the number of posts published.
+pub fn publish_due_posts(conn: &MysqlConnection, now: NaiveDateTime) -> Result<usize, DieselError> {
+    diesel::update(
+        posts::table
+            .filter(posts::status.eq("scheduled"))
+            .filter(posts::publish_at.le(now)),
+    )
+    .set(posts::status.eq("published"))
+    .execute(conn)
+}
+
+/// Removes post with given `id` from db.
+pub fn remove_post_by_id(conn: &MysqlConnection, id_: i32) -> Result<usize, DieselError> {
+    diesel::delete(posts::table)
+        .filter(posts::id.eq(id_))
+        .execute(conn)
+}
+
+/// Query db for tag with given `slug`.
+pub fn get_tag_by_slug(conn: &MysqlConnection, slug_: &str) -> Result<Tag, DieselError> {
+    tags::table.filter(tags::slug.eq(slug_)).get_result(conn)
+}
+
+/// Returns the tag with given `slug`, creating it as `name` if there is none.
+///
+/// Example:
+///     let tag = get_or_create_tag(&conn, "Rust", "rust").unwrap();
+///     assert_eq!(tag.slug, "rust");
+pub fn get_or_create_tag(
+    conn: &MysqlConnection,
+    name_: &str,
+    slug_: &str,
+) -> Result<Tag, DieselError> {
+    use diesel::OptionalExtension;
+
+    conn.transaction(|| {
+        if let Some(tag) = get_tag_by_slug(conn, slug_).optional()? {
+            return Ok(tag);
+        }
+        diesel::insert_into(tags::table)
+            .values(&NewTag {
+                name: name_,
+                slug: slug_,
+            })
+            .execute(conn)?;
+        get_tag_by_slug(conn, slug_)
+    })
+}
+
+/// Replace the tags of post with given `id` by the tags with given ids.
+pub fn set_post_tags(
+    conn: &MysqlConnection,
+    post_id_: i32,
+    tag_ids: &[i32],
+) -> Result<usize, DieselError> {
+    let items: Vec<NewPostTag> = tag_ids
+        .iter()
+        .map(|&tag_id| NewPostTag {
+            post_id: post_id_,
+            tag_id,
+        })
+        .collect();
+    conn.transaction(|| {
+        diesel::delete(post_tags::table.filter(post_tags::post_id.eq(post_id_))).execute(conn)?;
+        diesel::insert_into(post_tags::table)
+            .values(&items)
+            .execute(conn)
+    })
+}
+
+/// Returns the tags of post with given `id`, by name.
+pub fn get_post_tags(conn: &MysqlConnection, post_id_: i32) -> Result<Vec<Tag>, DieselError> {
+    post_tags::table
+        .inner_join(tags::table)
+        .filter(post_tags::post_id.eq(post_id_))
+        .order(tags::name.asc())
+        .select(tags::all_columns)
+        .load(conn)
+}
+
+/// Returns up to `limit` posts with given `status` tagged with tag `tag_id`,
+/// newest first.
+pub fn get_posts_by_tag(
+    conn: &MysqlConnection,
+    tag_id_: i32,
+    status_: &str,
+    limit: i64,
+) -> Result<Vec<Post>, DieselError> {
+    post_tags::table
+        .inner_join(posts::table)
+        .filter(post_tags::tag_id.eq(tag_id_))
+        .filter(posts::status.eq(status_))
+        .order(posts::created_at.desc())
+        .limit(limit)
+        .select(posts::all_columns)
+        .load(conn)
+}
+
+/// Returns every tag on at least one post with given `status`, with the
+/// number of such posts, by name.
+///
+/// Example:
+///     let res = get_tag_counts(&conn, "published").unwrap();
+///     assert!(res.iter().all(|(_, count)| *count > 0));
+pub fn get_tag_counts(
+    conn: &MysqlConnection,
+    status_: &str,
+) -> Result<Vec<(Tag, i64)>, DieselError> {
+    use diesel::dsl::sql;
+    use diesel::query_dsl::GroupByDsl;
+    use diesel::sql_types::BigInt;
+
+    post_tags::table
+        .inner_join(tags::table)
+        .inner_join(posts::table)
+        .filter(posts::status.eq(status_))
+        .group_by(tags::id)
+        .order(tags::name.asc())
+        .select((tags::all_columns, sql::<BigInt>("COUNT(*)")))
+        .load(conn)
+}
+
+/// Create new comment record in db and return its id.  Example:
+///     let item = NewComment { post_id: 1, author_id: 13, parent_id: None, body: "Nice!", status: "pending" };
+///     let id = create_comment(&conn, item).unwrap();
+pub fn create_comment(conn: &MysqlConnection, item: NewComment) -> Result<i32, DieselError> {
+    conn.transaction(|| {
+        diesel::insert_into(comments::table)
+            .values(&item)
+            .execute(conn)?;
+        let id: u64 = diesel::select(last_insert_id).get_result(conn)?;
+        Ok(id as i32)
+    })
+}
+
+/// Query db for comment with given `id`.
+pub fn get_comment_by_id(conn: &MysqlConnection, id_: i32) -> Result<Comment, DieselError> {
+    comments::table
+        .filter(comments::id.eq(id_))
+        .get_result(conn)
+}
+
+/// Returns all comments on post with given `id` with their author's username,
+/// oldest first.
+///
+/// Example:
+///     let res = get_comments_by_post(&conn, 1).unwrap();
+///     assert!(res.iter().all(|(c, _)| c.post_id == 1));
+pub fn get_comments_by_post(
+    conn: &MysqlConnection,
+    post_id_: i32,
+) -> Result<Vec<(Comment, String)>, DieselError> {
+    comments::table
+        .inner_join(users::table)
+        .filter(comments::post_id.eq(post_id_))
+        .order((comments::created_at.asc(), comments::id.asc()))
+        .select((comments::all_columns, users::username))
+        .load(conn)
+}
+
+/// Returns the pending comments on posts written by user with given `id`, or
+/// on all posts if `None`, with the commenter's username and the post, oldest
+/// first.
+pub fn get_pending_comments(
+    conn: &MysqlConnection,
+    post_author_id: Option<i32>,
+) -> Result<Vec<(Comment, String, Post)>, DieselError> {
+    let mut query = comments::table
+        .inner_join(posts::table)
+        .inner_join(users::table.on(users::id.eq(comments::author_id)))
+        .filter(comments::status.eq("pending"))
+        .into_boxed();
+    if let Some(author_id_) = post_author_id {
+        query = query.filter(posts::author_id.eq(author_id_));
+    }
+    query
+        .order((comments::created_at.asc(), comments::id.asc()))
+        .select((comments::all_columns, users::username, posts::all_columns))
+        .load(conn)
+}
+
+/// Replace the body of comment with given `id`.
+pub fn update_comment_body(
+    conn: &MysqlConnection,
+    id_: i32,
+    body_: &str,
+) -> Result<usize, DieselError> {
+    diesel::update(comments::table.filter(comments::id.eq(id_)))
+        .set(comments::body.eq(body_))
+        .execute(conn)
+}
+
+/// Set the moderation status of comment with given `id`.
+pub fn set_comment_status(
+    conn: &MysqlConnection,
+    id_: i32,
+    status_: &str,
+) -> Result<usize, DieselError> {
+    diesel::update(comments::table.filter(comments::id.eq(id_)))
+        .set(comments::status.eq(status_))
+        .execute(conn)
+}
+
+/// Removes comment with given `id`, and all replies to it, from db.
+pub fn remove_comment_by_id(conn: &MysqlConnection, id_: i32) -> Result<usize, DieselError> {
+    diesel::delete(comments::table)
+        .filter(comments::id.eq(id_))
+        .execute(conn)
 }
 
 #[cfg(test)]
@@ -112,11 +1078,15 @@ mod tests {
     use crate::models::NewUser;
 
     #[test]
-    fn session_created() {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        let key: u32 = rng.gen();
+    fn session_created_and_ended() {
+        use super::{create_user_session, end_user_session};
+        use crate::models::NewUserSession;
+        use crate::sessions::generate_session_key;
         let conn = establish_connection().unwrap();
+        let key = generate_session_key();
+        let res = create_user_session(&conn, &NewUserSession::new(key.clone(), 13));
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(end_user_session(&conn, &key).unwrap(), 1);
     }
 
     #[test]
@@ -162,9 +1132,347 @@ mod tests {
         let item = NewUser {
             username: "testuser1",
             password: "testpassword123",
+            email: None,
         };
 
         let usr = create_user(&conn, item);
         assert!(usr.is_ok());
     }
+
+    #[test]
+    fn post_created_and_removed() {
+        use super::{create_post, get_post_by_slug, remove_post_by_id};
+        use crate::models::NewPost;
+        let conn = establish_connection().unwrap();
+        if let Ok(post) = get_post_by_slug(&conn, "test-post-1") {
+            let _ = remove_post_by_id(&conn, post.id);
+        }
+        let item = NewPost {
+            author_id: 13,
+            title: "Test Post 1",
+            slug: "test-post-1",
+            body: "Hello, world.",
+            body_html: "<p>Hello, world.</p>\n",
+            status: "draft",
+            publish_at: None,
+        };
+
+        assert!(create_post(&conn, item).is_ok());
+        let post = get_post_by_slug(&conn, "test-post-1").unwrap();
+        assert_eq!(remove_post_by_id(&conn, post.id).unwrap(), 1);
+    }
+
+    #[test]
+    fn scheduled_post_published_when_due() {
+        use super::{create_post, get_post_by_slug, publish_due_posts, remove_post_by_id};
+        use crate::models::NewPost;
+        use chrono::{Duration, Utc};
+        let conn = establish_connection().unwrap();
+        if let Ok(post) = get_post_by_slug(&conn, "test-post-2") {
+            let _ = remove_post_by_id(&conn, post.id);
+        }
+        let now = Utc::now().naive_utc();
+        let item = NewPost {
+            author_id: 13,
+            title: "Test Post 2",
+            slug: "test-post-2",
+            body: "Hello, later.",
+            body_html: "<p>Hello, later.</p>\n",
+            status: "scheduled",
+            publish_at: Some(now + Duration::hours(1)),
+        };
+        assert!(create_post(&conn, item).is_ok());
+
+        publish_due_posts(&conn, now).unwrap();
+        let post = get_post_by_slug(&conn, "test-post-2").unwrap();
+        assert_eq!(post.status, "scheduled");
+
+        publish_due_posts(&conn, now + Duration::hours(2)).unwrap();
+        let post = get_post_by_slug(&conn, "test-post-2").unwrap();
+        assert_eq!(post.status, "published");
+        assert_eq!(remove_post_by_id(&conn, post.id).unwrap(), 1);
+    }
+
+    #[test]
+    fn comment_created_approved_and_removed() {
+        use super::{
+            create_comment, create_post, get_comment_by_id, get_comments_by_post, get_post_by_slug,
+            remove_comment_by_id, remove_post_by_id, set_comment_status,
+        };
+        use crate::models::{NewComment, NewPost};
+        let conn = establish_connection().unwrap();
+        if let Ok(post) = get_post_by_slug(&conn, "test-post-3") {
+            let _ = remove_post_by_id(&conn, post.id);
+        }
+        let item = NewPost {
+            author_id: 13,
+            title: "Test Post 3",
+            slug: "test-post-3",
+            body: "Comment on me.",
+            body_html: "<p>Comment on me.</p>\n",
+            status: "published",
+            publish_at: None,
+        };
+        create_post(&conn, item).unwrap();
+        let post = get_post_by_slug(&conn, "test-post-3").unwrap();
+
+        let item = NewComment {
+            post_id: post.id,
+            author_id: 13,
+            parent_id: None,
+            body: "First!",
+            status: "pending",
+        };
+        let id = create_comment(&conn, item).unwrap();
+        assert_eq!(set_comment_status(&conn, id, "approved").unwrap(), 1);
+        assert!(get_comment_by_id(&conn, id).unwrap().is_approved());
+        let comments = get_comments_by_post(&conn, post.id).unwrap();
+        assert_eq!(comments.len(), 1);
+
+        assert_eq!(remove_comment_by_id(&conn, id).unwrap(), 1);
+        assert_eq!(remove_post_by_id(&conn, post.id).unwrap(), 1);
+    }
+
+    #[test]
+    fn post_tagged_and_counted() {
+        use super::{
+            create_post, get_or_create_tag, get_post_by_slug, get_post_tags, get_posts_by_tag,
+            get_tag_counts, remove_post_by_id, set_post_tags,
+        };
+        use crate::models::NewPost;
+        let conn = establish_connection().unwrap();
+        if let Ok(post) = get_post_by_slug(&conn, "test-post-4") {
+            let _ = remove_post_by_id(&conn, post.id);
+        }
+        let item = NewPost {
+            author_id: 13,
+            title: "Test Post 4",
+            slug: "test-post-4",
+            body: "Tag me.",
+            body_html: "<p>Tag me.</p>\n",
+            status: "published",
+            publish_at: None,
+        };
+        create_post(&conn, item).unwrap();
+        let post = get_post_by_slug(&conn, "test-post-4").unwrap();
+
+        let tag = get_or_create_tag(&conn, "Test Tag", "test-tag").unwrap();
+        assert_eq!(
+            get_or_create_tag(&conn, "test tag", "test-tag").unwrap().id,
+            tag.id
+        );
+        assert_eq!(set_post_tags(&conn, post.id, &[tag.id]).unwrap(), 1);
+        assert_eq!(get_post_tags(&conn, post.id).unwrap()[0].slug, "test-tag");
+        let posts = get_posts_by_tag(&conn, tag.id, "published", 10).unwrap();
+        assert!(posts.iter().any(|p| p.id == post.id));
+        let counts = get_tag_counts(&conn, "published").unwrap();
+        assert!(counts.iter().any(|(t, n)| t.id == tag.id && *n >= 1));
+
+        assert_eq!(remove_post_by_id(&conn, post.id).unwrap(), 1);
+    }
+
+    #[test]
+    fn users_paged_by_username() {
+        use super::get_users_page;
+        use crate::users::{UserQuery, UserSort};
+        let conn = establish_connection().unwrap();
+        let query = UserQuery {
+            sort: UserSort::Username,
+            limit: Some(2),
+            ..UserQuery::default()
+        };
+        let (first, total) = get_users_page(&conn, &query).unwrap();
+        assert!(first.len() as i64 <= total.min(2));
+        let next = UserQuery {
+            offset: Some(2),
+            ..query
+        };
+        let (second, _) = get_users_page(&conn, &next).unwrap();
+        assert!(first
+            .iter()
+            .all(|a| second.iter().all(|b| a.username < b.username)));
+    }
+
+    #[test]
+    fn user_disabled_and_enabled() {
+        use super::{get_user_by_id, set_user_active};
+        let conn = establish_connection().unwrap();
+        set_user_active(&conn, 13, false, Some("Spam")).unwrap();
+        let usr = get_user_by_id(&conn, 13).unwrap();
+        assert!(!usr.is_active);
+        assert_eq!(usr.disabled_reason.as_deref(), Some("Spam"));
+
+        set_user_active(&conn, 13, true, None).unwrap();
+        let usr = get_user_by_id(&conn, 13).unwrap();
+        assert!(usr.is_active);
+        assert!(usr.disabled_reason.is_none());
+    }
+
+    #[test]
+    fn login_failures_counted_locked_and_cleared() {
+        use super::{clear_login_failures, get_login_lock, lock_login, record_login_failure};
+        use chrono::{Duration, Utc};
+        let conn = establish_connection().unwrap();
+        let now = Utc::now().naive_utc();
+        let day_ago = now - Duration::days(1);
+        clear_login_failures(&conn, "ip", "192.0.2.1").unwrap();
+        assert_eq!(
+            record_login_failure(&conn, "ip", "192.0.2.1", now, day_ago).unwrap(),
+            1
+        );
+        assert_eq!(
+            record_login_failure(&conn, "ip", "192.0.2.1", now, day_ago).unwrap(),
+            2
+        );
+        assert!(get_login_lock(&conn, "ip", "192.0.2.1", now)
+            .unwrap()
+            .is_none());
+
+        let until = now + Duration::minutes(1);
+        lock_login(&conn, "ip", "192.0.2.1", until).unwrap();
+        assert!(get_login_lock(&conn, "ip", "192.0.2.1", now)
+            .unwrap()
+            .is_some());
+        assert_eq!(clear_login_failures(&conn, "ip", "192.0.2.1").unwrap(), 1);
+    }
+
+    #[test]
+    fn role_granted_and_revoked() {
+        use super::{get_user_permissions, get_user_roles, grant_role, revoke_role};
+        let conn = establish_connection().unwrap();
+        let _ = revoke_role(&conn, 13, "editor");
+        assert_eq!(grant_role(&conn, 13, "editor").unwrap(), 1);
+        assert_eq!(grant_role(&conn, 13, "editor").unwrap(), 0);
+        assert!(get_user_roles(&conn, 13)
+            .unwrap()
+            .contains(&String::from("editor")));
+        assert!(get_user_permissions(&conn, 13)
+            .unwrap()
+            .contains(&String::from("comments:moderate")));
+        assert_eq!(revoke_role(&conn, 13, "editor").unwrap(), 1);
+    }
+
+    #[test]
+    fn password_reset_token_expires_and_is_removed() {
+        use super::{
+            create_password_reset_token, lock_password_reset_token, remove_password_reset_tokens,
+        };
+        use crate::models::NewPasswordResetToken;
+        use chrono::{Duration, Utc};
+        let conn = establish_connection().unwrap();
+        let now = Utc::now().naive_utc();
+        let hash = "f".repeat(64);
+        let item = NewPasswordResetToken {
+            token_hash: &hash,
+            user_id: 13,
+            expires_at: now + Duration::hours(1),
+        };
+        create_password_reset_token(&conn, item, now).unwrap();
+        assert!(lock_password_reset_token(&conn, &hash, now)
+            .unwrap()
+            .is_some());
+        let later = now + Duration::hours(2);
+        assert!(lock_password_reset_token(&conn, &hash, later)
+            .unwrap()
+            .is_none());
+        assert_eq!(remove_password_reset_tokens(&conn, 13).unwrap(), 1);
+        assert!(lock_password_reset_token(&conn, &hash, now)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn email_verification_token_stores_address() {
+        use super::{
+            create_email_verification_token, lock_email_verification_token,
+            remove_email_verification_tokens,
+        };
+        use crate::models::NewEmailVerificationToken;
+        use chrono::{Duration, Utc};
+        let conn = establish_connection().unwrap();
+        let now = Utc::now().naive_utc();
+        let hash = "e".repeat(64);
+        let item = NewEmailVerificationToken {
+            token_hash: &hash,
+            user_id: 13,
+            email: "bender@example.com",
+            expires_at: now + Duration::days(2),
+        };
+        create_email_verification_token(&conn, item, now).unwrap();
+        let token = lock_email_verification_token(&conn, &hash, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.email, "bender@example.com");
+        let later = now + Duration::days(3);
+        assert!(lock_email_verification_token(&conn, &hash, later)
+            .unwrap()
+            .is_none());
+        assert_eq!(remove_email_verification_tokens(&conn, 13).unwrap(), 1);
+    }
+
+    #[test]
+    fn webauthn_challenge_used_once() {
+        use super::{create_webauthn_challenge, take_webauthn_challenge};
+        use crate::models::NewWebauthnChallenge;
+        use chrono::{Duration, Utc};
+        let conn = establish_connection().unwrap();
+        let now = Utc::now().naive_utc();
+        let hash = "d".repeat(64);
+        let item = NewWebauthnChallenge {
+            challenge_hash: &hash,
+            user_id: Some(13),
+            purpose: "register",
+            expires_at: now + Duration::minutes(5),
+        };
+        create_webauthn_challenge(&conn, item, now).unwrap();
+        assert!(take_webauthn_challenge(&conn, &hash, "login", now)
+            .unwrap()
+            .is_none());
+        let challenge = take_webauthn_challenge(&conn, &hash, "register", now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(challenge.user_id, Some(13));
+        assert!(take_webauthn_challenge(&conn, &hash, "register", now)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn webauthn_credential_created_and_used() {
+        use super::{
+            create_webauthn_credential, get_user_webauthn_credentials, lock_webauthn_credential,
+            remove_webauthn_credential, use_webauthn_credential,
+        };
+        use crate::models::NewWebauthnCredential;
+        use chrono::Utc;
+        let conn = establish_connection().unwrap();
+        let now = Utc::now().naive_utc();
+        let credential_id = [13u8; 16];
+        let item = NewWebauthnCredential {
+            user_id: 13,
+            credential_id: &credential_id,
+            public_key: &[1, 2, 3],
+            sign_count: 0,
+            name: "Phone",
+        };
+        create_webauthn_credential(&conn, item).unwrap();
+        let credential = lock_webauthn_credential(&conn, &credential_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(credential.user_id, 13);
+        assert_eq!(credential.public_key, vec![1, 2, 3]);
+        use_webauthn_credential(&conn, credential.id, 7, now).unwrap();
+        let stored = get_user_webauthn_credentials(&conn, 13).unwrap();
+        assert!(stored
+            .iter()
+            .any(|c| c.id == credential.id && c.sign_count == 7 && c.last_used_at.is_some()));
+        assert_eq!(
+            remove_webauthn_credential(&conn, 14, credential.id).unwrap(),
+            0
+        );
+        assert_eq!(
+            remove_webauthn_credential(&conn, 13, credential.id).unwrap(),
+            1
+        );
+    }
 }
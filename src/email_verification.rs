@@ -0,0 +1,123 @@
+//! Verification of users' email addresses.
+//!
+//! On signup, and again on request, `send_link` mails a link with a random
+//! token to the account's address.  As for password resets only the token's
+//! SHA-256 hash is stored, next to the address it was sent to, so a link stops
+//! working once the account's address changes.  Links expire after
+//! `EMAIL_VERIFICATION_TTL` seconds (default: two days).  Publishing posts and
+//! commenting wait until the address has been verified.
+
+use super::config::app_url;
+use super::db::{
+    create_email_verification_token, get_user_by_id, lock_email_verification_token,
+    remove_email_verification_tokens, set_user_email_verified,
+};
+use super::errors::VerificationError;
+use super::mailer::{Mail, Mailer};
+use super::models::NewEmailVerificationToken;
+use super::password_reset::hash_token;
+use super::sessions::generate_session_key;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{Connection, MysqlConnection};
+use std::env;
+
+/// How long a verification link works, from `EMAIL_VERIFICATION_TTL` in seconds.
+pub fn ttl() -> Duration {
+    let secs = env::var("EMAIL_VERIFICATION_TTL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(2 * 24 * 60 * 60);
+    Duration::seconds(secs)
+}
+
+/// Store a new token verifying `email` for user with given `user_id` and return it.
+pub fn issue(
+    conn: &MysqlConnection,
+    user_id: i32,
+    email: &str,
+    now: NaiveDateTime,
+) -> Result<String, VerificationError> {
+    let token = generate_session_key();
+    let item = NewEmailVerificationToken {
+        token_hash: &hash_token(&token),
+        user_id,
+        email,
+        expires_at: now + ttl(),
+    };
+    create_email_verification_token(conn, item, now)?;
+    Ok(token)
+}
+
+/// The mail sending `link` to `to`.
+pub fn verification_mail(to: &str, link: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: String::from("Verify your email address"),
+        body: format!(
+            "Please confirm that this is your email address by following this link \
+             within {} hours:\n\n\
+             {}\n\n\
+             If you didn't sign up, you can ignore this mail.",
+            ttl().num_hours(),
+            link
+        ),
+    }
+}
+
+/// Mail a verification link to the address of user with given `user_id`.
+/// Nothing is sent if the address has been verified already.
+pub fn send_link(
+    conn: &MysqlConnection,
+    mailer: &dyn Mailer,
+    user_id: i32,
+    now: NaiveDateTime,
+) -> Result<(), VerificationError> {
+    let user = get_user_by_id(conn, user_id)?;
+    let email = user.email.ok_or(VerificationError::NoEmail)?;
+    if user.email_verified_at.is_some() {
+        return Ok(());
+    }
+    let token = issue(conn, user.id, &email, now)?;
+    let link = format!("{}/email/verify/{}", app_url(), token);
+    mailer
+        .send(&verification_mail(&email, &link))
+        .map_err(VerificationError::MailFailed)
+}
+
+/// Mark the address `token` was sent to as verified at `now`, using up every
+/// token of the account.  Fails with `InvalidToken` if the account's address
+/// has changed since.
+pub fn verify(
+    conn: &MysqlConnection,
+    token: &str,
+    now: NaiveDateTime,
+) -> Result<(), VerificationError> {
+    conn.transaction(|| {
+        let token = lock_email_verification_token(conn, &hash_token(token), now)?
+            .ok_or(VerificationError::InvalidToken)?;
+        let user = get_user_by_id(conn, token.user_id)?;
+        if user.email.as_deref() != Some(token.email.as_str()) {
+            return Err(VerificationError::InvalidToken);
+        }
+        set_user_email_verified(conn, user.id, now)?;
+        remove_email_verification_tokens(conn, user.id)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verification_mail_carries_link() {
+        let mail = verification_mail("bender@example.com", "http://localhost/email/verify/t0ken");
+        assert_eq!(mail.to, "bender@example.com");
+        assert!(mail.body.contains("within 48 hours"));
+        assert!(mail
+            .body
+            .contains("\n\nhttp://localhost/email/verify/t0ken\n\n"));
+    }
+}
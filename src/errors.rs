@@ -1,4 +1,10 @@
-use actix_web::error::ResponseError;
+use actix_web::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    HttpResponse,
+};
+use chrono::NaiveDateTime;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use serde::{Deserialize, Serialize};
 
 #[derive(Fail, Debug, Serialize, Deserialize)]
@@ -6,18 +12,584 @@ pub enum FormError {
     #[fail(display = "Passwords do not match.")]
     MismatchPasswords,
 
-    #[fail(display = "This field is too short.")]
+    #[fail(display = "{}", _0)]
     FieldTooShort(String),
 
-    #[fail(display = "Field cannot be empty.")]
+    #[fail(display = "{}", _0)]
     EmptyField(String),
+
+    #[fail(display = "{}", _0)]
+    FieldTooLong(String),
+
+    #[fail(display = "{}", _0)]
+    InvalidChoice(String),
+
+    #[fail(display = "{}", _0)]
+    InvalidFormat(String),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Fail, Debug, Deserialize, Serialize)]
 pub enum AuthError {
+    #[fail(display = "Invalid password.")]
     InvalidPassword,
+
+    #[fail(display = "User not found.")]
     UserNotFound,
+
+    #[fail(display = "A user with that username already exists.")]
     UserAlreadyExists,
+
+    #[fail(display = "A user with that email address already exists.")]
+    EmailAlreadyExists,
+
+    #[fail(display = "Could not hash password.")]
+    HashingFailed,
+
+    #[fail(display = "This account has been disabled.")]
+    AccountDisabled { reason: Option<String> },
+
+    #[fail(display = "This account is locked until {} UTC.", until)]
+    AccountLocked { until: NaiveDateTime },
+
+    #[fail(display = "Too many failed logins. Try again after {} UTC.", until)]
+    TooManyAttempts { until: NaiveDateTime },
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for AuthError {
+    /// Unique constraint violations on insert mean the username or email
+    /// address was taken in the meantime.
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+                if info.message().contains("email") =>
+            {
+                AuthError::EmailAlreadyExists
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                AuthError::UserAlreadyExists
+            }
+            e => AuthError::DatabaseError(e.to_string()),
+        }
+    }
 }
 
 impl ResponseError for FormError {}
+
+#[derive(Fail, Debug)]
+pub enum PostError {
+    #[fail(display = "Post not found.")]
+    NotFound,
+
+    #[fail(display = "Only the author may change this post.")]
+    Forbidden,
+
+    #[fail(display = "Verify your email address first.")]
+    EmailNotVerified,
+
+    #[fail(display = "{}", _0)]
+    Invalid(FormError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for PostError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => PostError::NotFound,
+            e => PostError::DatabaseError(e.to_string()),
+        }
+    }
+}
+
+impl From<FormError> for PostError {
+    fn from(e: FormError) -> Self {
+        PostError::Invalid(e)
+    }
+}
+
+impl ResponseError for PostError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PostError::NotFound => StatusCode::NOT_FOUND,
+            PostError::Forbidden | PostError::EmailNotVerified => StatusCode::FORBIDDEN,
+            PostError::Invalid(_) => StatusCode::BAD_REQUEST,
+            PostError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by extractors when a handler requires a logged-in user.
+///
+/// Browsers (`redirect` set) are sent to `/login`; API clients get `401`.
+#[derive(Fail, Debug)]
+#[fail(display = "You must be logged in.")]
+pub struct LoginRequired {
+    pub redirect: bool,
+}
+
+impl ResponseError for LoginRequired {
+    fn status_code(&self) -> StatusCode {
+        if self.redirect {
+            StatusCode::SEE_OTHER
+        } else {
+            StatusCode::UNAUTHORIZED
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.redirect {
+            HttpResponse::SeeOther()
+                .header(header::LOCATION, "/login")
+                .finish()
+        } else {
+            HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .finish()
+        }
+    }
+}
+
+/// Returned by `roles::RoleGuard` when the logged-in user has a role that
+/// requires two-factor authentication but hasn't set it up.
+///
+/// Browsers (`redirect` set) are sent to `/account/2fa`; API clients get `403`.
+#[derive(Fail, Debug)]
+#[fail(display = "Set up two-factor authentication first.")]
+pub struct TwoFactorRequired {
+    pub redirect: bool,
+}
+
+impl ResponseError for TwoFactorRequired {
+    fn status_code(&self) -> StatusCode {
+        if self.redirect {
+            StatusCode::SEE_OTHER
+        } else {
+            StatusCode::FORBIDDEN
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.redirect {
+            HttpResponse::SeeOther()
+                .header(header::LOCATION, "/account/2fa")
+                .finish()
+        } else {
+            HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+        }
+    }
+}
+
+/// Returned by `roles::RoleGuard` when the logged-in user lacks the role or
+/// permission a route requires.
+#[derive(Fail, Debug)]
+#[fail(display = "You are not allowed to do this.")]
+pub struct AccessDenied;
+
+impl ResponseError for AccessDenied {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned when a form is submitted without the CSRF token of the session,
+/// see `csrf`.
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid or missing CSRF token.")]
+pub struct CsrfError;
+
+impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum AdminError {
+    #[fail(display = "User not found.")]
+    UserNotFound,
+
+    #[fail(display = "Admins cannot disable, lock, delete or demote their own account.")]
+    OwnAccount,
+
+    #[fail(display = "{}", _0)]
+    Invalid(FormError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for AdminError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => AdminError::UserNotFound,
+            e => AdminError::DatabaseError(e.to_string()),
+        }
+    }
+}
+
+impl From<FormError> for AdminError {
+    fn from(e: FormError) -> Self {
+        AdminError::Invalid(e)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::UserNotFound => StatusCode::NOT_FOUND,
+            AdminError::OwnAccount => StatusCode::FORBIDDEN,
+            AdminError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AdminError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `password_reset` when a password cannot be reset.
+#[derive(Fail, Debug)]
+pub enum ResetError {
+    #[fail(display = "This password reset link is invalid or has expired.")]
+    InvalidToken,
+
+    #[fail(display = "{}", _0)]
+    Invalid(FormError),
+
+    #[fail(display = "Could not send mail: {}", _0)]
+    MailFailed(String),
+
+    #[fail(display = "{}", _0)]
+    Auth(AuthError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for ResetError {
+    fn from(e: DieselError) -> Self {
+        ResetError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<FormError> for ResetError {
+    fn from(e: FormError) -> Self {
+        ResetError::Invalid(e)
+    }
+}
+
+impl From<AuthError> for ResetError {
+    fn from(e: AuthError) -> Self {
+        ResetError::Auth(e)
+    }
+}
+
+impl ResponseError for ResetError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResetError::InvalidToken | ResetError::Invalid(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `account` when changing the password or username fails.
+#[derive(Fail, Debug)]
+pub enum AccountError {
+    #[fail(display = "{}", _0)]
+    Invalid(FormError),
+
+    #[fail(display = "{}", _0)]
+    Auth(AuthError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for AccountError {
+    fn from(e: DieselError) -> Self {
+        AccountError::Auth(AuthError::from(e))
+    }
+}
+
+impl From<FormError> for AccountError {
+    fn from(e: FormError) -> Self {
+        AccountError::Invalid(e)
+    }
+}
+
+impl From<AuthError> for AccountError {
+    fn from(e: AuthError) -> Self {
+        AccountError::Auth(e)
+    }
+}
+
+impl ResponseError for AccountError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AccountError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AccountError::Auth(AuthError::InvalidPassword) => StatusCode::FORBIDDEN,
+            AccountError::Auth(AuthError::UserAlreadyExists) => StatusCode::CONFLICT,
+            AccountError::Auth(AuthError::TooManyAttempts { .. }) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `webauthn` and `passkeys` when registering a passkey or
+/// logging in with one fails.
+#[derive(Fail, Debug)]
+pub enum PasskeyError {
+    #[fail(display = "{}", _0)]
+    Invalid(FormError),
+
+    #[fail(display = "Malformed passkey response: {}", _0)]
+    InvalidResponse(String),
+
+    #[fail(display = "The passkey request has expired. Please try again.")]
+    InvalidChallenge,
+
+    #[fail(display = "The passkey response is for another site.")]
+    InvalidOrigin,
+
+    #[fail(display = "The authenticator did not verify the user.")]
+    UserNotVerified,
+
+    #[fail(display = "Unsupported passkey algorithm.")]
+    UnsupportedAlgorithm,
+
+    #[fail(display = "Invalid passkey signature.")]
+    InvalidSignature,
+
+    #[fail(display = "The authenticator's counter went backwards, it may have been cloned.")]
+    CounterRegressed,
+
+    #[fail(display = "Unknown passkey.")]
+    UnknownCredential,
+
+    #[fail(display = "This passkey is already registered.")]
+    CredentialExists,
+
+    #[fail(display = "{}", _0)]
+    Auth(AuthError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for PasskeyError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                PasskeyError::CredentialExists
+            }
+            e => PasskeyError::DatabaseError(e.to_string()),
+        }
+    }
+}
+
+impl From<FormError> for PasskeyError {
+    fn from(e: FormError) -> Self {
+        PasskeyError::Invalid(e)
+    }
+}
+
+impl From<AuthError> for PasskeyError {
+    fn from(e: AuthError) -> Self {
+        PasskeyError::Auth(e)
+    }
+}
+
+impl ResponseError for PasskeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PasskeyError::Invalid(_)
+            | PasskeyError::InvalidResponse(_)
+            | PasskeyError::InvalidChallenge
+            | PasskeyError::InvalidOrigin
+            | PasskeyError::UserNotVerified
+            | PasskeyError::UnsupportedAlgorithm => StatusCode::BAD_REQUEST,
+            PasskeyError::InvalidSignature
+            | PasskeyError::CounterRegressed
+            | PasskeyError::UnknownCredential => StatusCode::UNAUTHORIZED,
+            PasskeyError::CredentialExists => StatusCode::CONFLICT,
+            PasskeyError::Auth(AuthError::InvalidPassword)
+            | PasskeyError::Auth(AuthError::AccountDisabled { .. })
+            | PasskeyError::Auth(AuthError::AccountLocked { .. }) => StatusCode::FORBIDDEN,
+            PasskeyError::Auth(AuthError::TooManyAttempts { .. }) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `two_factor` when setting up, using or turning off
+/// two-factor authentication fails.
+#[derive(Fail, Debug)]
+pub enum TwoFactorError {
+    #[fail(display = "Invalid code.")]
+    InvalidCode,
+
+    #[fail(display = "Two-factor authentication is not set up.")]
+    NotEnabled,
+
+    #[fail(display = "Two-factor authentication is already set up.")]
+    AlreadyEnabled,
+
+    #[fail(display = "Two-factor authentication is required for your account.")]
+    Required,
+
+    #[fail(display = "Your login has expired. Please log in again.")]
+    LoginExpired,
+
+    #[fail(display = "{}", _0)]
+    Auth(AuthError),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for TwoFactorError {
+    fn from(e: DieselError) -> Self {
+        TwoFactorError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<AuthError> for TwoFactorError {
+    fn from(e: AuthError) -> Self {
+        TwoFactorError::Auth(e)
+    }
+}
+
+impl ResponseError for TwoFactorError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TwoFactorError::InvalidCode
+            | TwoFactorError::NotEnabled
+            | TwoFactorError::AlreadyEnabled => StatusCode::BAD_REQUEST,
+            TwoFactorError::Required
+            | TwoFactorError::Auth(AuthError::AccountDisabled { .. })
+            | TwoFactorError::Auth(AuthError::AccountLocked { .. }) => StatusCode::FORBIDDEN,
+            TwoFactorError::LoginExpired => StatusCode::UNAUTHORIZED,
+            TwoFactorError::Auth(AuthError::TooManyAttempts { .. }) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `email_verification` when an address can't be verified.
+#[derive(Fail, Debug)]
+pub enum VerificationError {
+    #[fail(display = "This verification link is invalid or has expired.")]
+    InvalidToken,
+
+    #[fail(display = "There is no email address to verify.")]
+    NoEmail,
+
+    #[fail(display = "Could not send mail: {}", _0)]
+    MailFailed(String),
+
+    #[fail(display = "Database error: {}", _0)]
+    DatabaseError(String),
+}
+
+impl From<DieselError> for VerificationError {
+    fn from(e: DieselError) -> Self {
+        VerificationError::DatabaseError(e.to_string())
+    }
+}
+
+impl ResponseError for VerificationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            VerificationError::InvalidToken | VerificationError::NoEmail => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `ratelimit::RateLimit` when a client used up its requests.
+#[derive(Fail, Debug)]
+#[fail(display = "Too many requests. Try again in {} seconds.", retry_after)]
+pub struct RateLimited {
+    /// Seconds until the next request will be let through.
+    pub retry_after: u64,
+}
+
+impl ResponseError for RateLimited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .header(header::RETRY_AFTER, self.retry_after.to_string())
+            .json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Returned by `config` when the app is misconfigured and must not start.
+#[derive(Fail, Debug, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "{} must be set in production.", _0)]
+    Missing(&'static str),
+
+    #[fail(display = "{} must be hex encoded.", _0)]
+    InvalidKey(String),
+
+    #[fail(
+        display = "{} is too weak for production, use at least 32 random bytes, e.g. from `openssl rand -hex 32`.",
+        _0
+    )]
+    WeakKey(String),
+
+    #[fail(display = "Invalid value '{}' for {}.", value, name)]
+    InvalidValue { name: String, value: String },
+}
+
+impl ConfigError {
+    pub fn invalid(name: &str, value: &str) -> Self {
+        ConfigError::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
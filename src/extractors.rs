@@ -0,0 +1,114 @@
+//! Request extractors for the logged-in user.
+//!
+//! Both read the `CurrentSession` that `sessions::SessionAuth` resolved from the
+//! session cookie or an `Authorization: Bearer <session key>` header:
+//!     #[get("/sessions")]
+//!     async fn list_sessions(current: CurrentUser) -> HttpResponse { ... }
+
+use super::errors::LoginRequired;
+use super::sessions::CurrentSession;
+
+use actix_web::{dev::Payload, http::header, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{err, ok, Ready};
+use std::ops::Deref;
+
+/// The logged-in user.  Extraction fails with `LoginRequired` if there is none.
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub CurrentSession);
+
+impl Deref for CurrentUser {
+    type Target = CurrentSession;
+
+    fn deref(&self) -> &CurrentSession {
+        &self.0
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<CurrentSession>() {
+            Some(current) => ok(CurrentUser(current.clone())),
+            None => err(LoginRequired {
+                redirect: wants_html(req),
+            }
+            .into()),
+        }
+    }
+}
+
+/// The logged-in user, if any.  Extraction never fails.
+#[derive(Debug, Clone)]
+pub struct OptionalUser(pub Option<CurrentSession>);
+
+impl Deref for OptionalUser {
+    type Target = Option<CurrentSession>;
+
+    fn deref(&self) -> &Option<CurrentSession> {
+        &self.0
+    }
+}
+
+impl FromRequest for OptionalUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(OptionalUser(
+            req.extensions().get::<CurrentSession>().cloned(),
+        ))
+    }
+}
+
+/// Returns `true` if the client accepts HTML, i.e. is a browser rather than an API client.
+pub(crate) fn wants_html<R: HttpMessage>(req: &R) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("text/html"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::UserResponse;
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use futures::executor::block_on;
+
+    #[test]
+    fn current_user_extracted() {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(CurrentSession {
+            session_key: String::from("test-token"),
+            user: UserResponse::new(),
+            roles: vec![],
+            permissions: vec![],
+            email_verified: true,
+            two_factor: false,
+        });
+        let usr = block_on(CurrentUser::extract(&req)).unwrap();
+        assert_eq!(usr.session_key, "test-token");
+    }
+
+    #[test]
+    fn missing_user_redirects_browsers() {
+        let req = TestRequest::default()
+            .header(header::ACCEPT, "text/html")
+            .to_http_request();
+        let e = block_on(CurrentUser::extract(&req)).unwrap_err();
+        assert_eq!(e.as_response_error().status_code(), StatusCode::SEE_OTHER);
+
+        let req = TestRequest::default().to_http_request();
+        let e = block_on(CurrentUser::extract(&req)).unwrap_err();
+        assert_eq!(
+            e.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(block_on(OptionalUser::extract(&req)).unwrap().is_none());
+    }
+}
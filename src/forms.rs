@@ -1,13 +1,51 @@
-use super::auth::Auth;
-use super::db::get_user_by_username;
+use super::auth::{hash_password, Auth};
+use super::db::{create_user, get_user_by_email, get_user_by_username, grant_role};
 use super::errors::AuthError;
 use super::errors::FormError;
-use super::users::BaseUser;
+use super::models::NewUser;
+use super::roles::{DEFAULT_ROLE, ROLES};
+use super::slugs::slugify;
+use super::users::{normalize_email, BaseUser};
+use super::webauthn::RegistrationResponse;
 
-use actix_web::web::Form;
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    web::{self, Form},
+    Error, HttpMessage,
+};
+use chrono::{Duration, NaiveDateTime};
 use diesel::{mysql::MysqlConnection, Connection};
+use futures::future::ok;
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
 
+/// Returns `true` if `email` looks like an email address: a local part and a
+/// dotted domain around a single `@`, without whitespace, at most 255
+/// characters long.  Whether it exists is left to `email_verification`.
+///
+/// Example:
+///     assert!(is_email("bender@example.com"));
+///     assert!(!is_email("bender@localhost"));
+pub fn is_email(email: &str) -> bool {
+    let email = email.trim();
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    email.chars().count() <= 255
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..")
+}
+
+/// Largest form body middleware reads to peek at a field, as for `web::Form`.
+const MAX_FORM_SIZE: usize = 16 * 1024;
+
 pub trait Valid<T = BaseUser>
 where
     T: Serialize,
@@ -78,6 +116,7 @@ impl Valid<UserLogin> for UserLogin {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserSignup {
     pub username: String,
+    pub email: String,
     pub password: String,
     pub password_confirm: String,
 }
@@ -93,11 +132,58 @@ impl UserSignup {
                 id: -1,
                 username: self.get_username().to_owned(),
                 password: self.get_password().to_owned(),
+                ..BaseUser::default()
             })
         } else {
             Err(FormError::MismatchPasswords)
         }
     }
+
+    /// Returns `Ok` if `email` looks like an email address and `username`
+    /// doesn't, so that logging in with either is unambiguous.
+    pub fn clean_identity(&self) -> Result<(), FormError> {
+        if self.username.contains('@') {
+            return Err(FormError::InvalidFormat(String::from(
+                "Username must not contain '@'.",
+            )));
+        }
+        if !is_email(&self.email) {
+            return Err(FormError::InvalidFormat(String::from(
+                "Field 'email' must be a valid email address.",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Hash the password and insert the new user into db with the default role,
+    /// returning the created user.  The email address is stored lowercased and
+    /// still needs to be verified, see `email_verification`.
+    ///
+    /// The existence check and insert run in one transaction; a concurrent signup
+    /// that claims the username or address first still fails with
+    /// `AuthError::UserAlreadyExists` or `AuthError::EmailAlreadyExists` through
+    /// the unique constraints on `users`.
+    pub fn register(&self, conn: &MysqlConnection) -> Result<BaseUser, AuthError> {
+        let hashed = hash_password(self.get_password())?;
+        let email = normalize_email(&self.email);
+        conn.transaction(|| {
+            self.verify_user(conn)?;
+            let item = NewUser {
+                username: self.get_username(),
+                password: &hashed,
+                email: Some(&email),
+            };
+            create_user(conn, item)?;
+            let usr = get_user_by_username(conn, self.get_username())?;
+            grant_role(conn, usr.id, DEFAULT_ROLE)?;
+            Ok(BaseUser {
+                id: usr.id,
+                username: usr.username,
+                password: usr.password,
+                ..BaseUser::default()
+            })
+        })
+    }
 }
 
 impl Auth for UserSignup {
@@ -110,14 +196,18 @@ impl Auth for UserSignup {
     }
 
     fn verify_user(&self, conn: &MysqlConnection) -> Result<BaseUser, AuthError> {
+        if get_user_by_email(conn, &self.email).is_ok() {
+            return Err(AuthError::EmailAlreadyExists);
+        }
         let usr = get_user_by_username(conn, self.get_username());
 
         match usr {
             Ok(_) => Err(AuthError::UserAlreadyExists),
             Err(_) => Ok(BaseUser {
-                id: usr.unwrap().get_id().to_owned(),
+                id: -1,
                 username: self.get_username().to_owned(),
                 password: self.get_password().to_owned(),
+                ..BaseUser::default()
             }),
         }
     }
@@ -136,3 +226,380 @@ impl Valid<UserSignup> for Form<UserSignup> {
         self.0.to_owned()
     }
 }
+
+/// Post statuses an author may pick.  `scheduled` posts are published by
+/// `publisher` once their `publish_at` time has passed.
+pub const POST_STATUSES: [&str; 4] = ["draft", "scheduled", "published", "archived"];
+
+/// Form for creating and editing posts, as HTML form or JSON body.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PostForm {
+    pub title: String,
+    pub body: String,
+    #[serde(default = "PostForm::default_status")]
+    pub status: String,
+    /// UTC time to publish a `scheduled` post, as `YYYY-MM-DDTHH:MM[:SS]`.
+    #[serde(default)]
+    pub publish_at: Option<String>,
+    /// Comma separated tag names, e.g. `"Rust, Web"`.  Left out, the post's tags
+    /// stay as they are.
+    #[serde(default)]
+    pub tags: Option<String>,
+}
+
+impl PostForm {
+    fn default_status() -> String {
+        String::from("draft")
+    }
+
+    /// Parsed `publish_at`, or `None` if it was left blank.
+    pub fn publish_at(&self) -> Result<Option<NaiveDateTime>, FormError> {
+        let value = match self.publish_at.as_deref().map(str::trim) {
+            None | Some("") => return Ok(None),
+            Some(value) => value,
+        };
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M"))
+            .map(Some)
+            .map_err(|_| FormError::InvalidChoice(format!("Invalid publish time '{}'.", value)))
+    }
+
+    /// Distinct tag names in `tags`, in the order given, or `None` if `tags`
+    /// was left out.  Names that slugify alike count as the same tag.
+    pub fn tag_names(&self) -> Option<Vec<String>> {
+        let tags = self.tags.as_ref()?;
+        let mut seen = Vec::new();
+        let mut names = Vec::new();
+        for name in tags.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let slug = slugify(name);
+            if !seen.contains(&slug) {
+                seen.push(slug);
+                names.push(name.to_owned());
+            }
+        }
+        Some(names)
+    }
+
+    /// Returns `Ok` if `title` and `body` aren't blank, `title` fits in 255
+    /// characters, `status` is one of `POST_STATUSES`, `scheduled` posts
+    /// have a valid `publish_at` and there are at most 10 tags of at most 64
+    /// characters each.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.title.trim().is_empty() {
+            return Err(FormError::EmptyField(String::from(
+                "Field 'title' cannot be empty.",
+            )));
+        }
+        if self.title.chars().count() > 255 {
+            return Err(FormError::FieldTooLong(String::from(
+                "Field 'title' must be at most 255 characters long.",
+            )));
+        }
+        if self.body.trim().is_empty() {
+            return Err(FormError::EmptyField(String::from(
+                "Field 'body' cannot be empty.",
+            )));
+        }
+        if !POST_STATUSES.contains(&self.status.as_str()) {
+            return Err(FormError::InvalidChoice(format!(
+                "Unknown post status '{}'.",
+                self.status
+            )));
+        }
+        if self.publish_at()?.is_none() && self.status == "scheduled" {
+            return Err(FormError::EmptyField(String::from(
+                "Field 'publish_at' is required for scheduled posts.",
+            )));
+        }
+        let names = self.tag_names().unwrap_or_default();
+        if names.len() > 10 {
+            return Err(FormError::InvalidChoice(String::from(
+                "A post can have at most 10 tags.",
+            )));
+        }
+        for name in names {
+            if name.chars().count() > 64 {
+                return Err(FormError::FieldTooLong(String::from(
+                    "Tags must be at most 64 characters long.",
+                )));
+            }
+            if slugify(&name).is_empty() {
+                return Err(FormError::InvalidChoice(format!(
+                    "Tag '{}' needs at least one letter or digit.",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Form for writing and editing comments, as HTML form or JSON body.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommentForm {
+    pub body: String,
+    /// Id of the comment being replied to; ignored when editing.
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+}
+
+impl CommentForm {
+    /// Returns `Ok` if `body` isn't blank and fits in 10000 characters.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.body.trim().is_empty() {
+            return Err(FormError::EmptyField(String::from(
+                "Field 'body' cannot be empty.",
+            )));
+        }
+        if self.body.chars().count() > 10_000 {
+            return Err(FormError::FieldTooLong(String::from(
+                "Field 'body' must be at most 10000 characters long.",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Form disabling a user from the admin section.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DisableUserForm {
+    /// Shown to the user when they try to log in.
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl DisableUserForm {
+    /// The reason, unless left blank.
+    pub fn reason(&self) -> Option<&str> {
+        Some(self.reason.trim()).filter(|r| !r.is_empty())
+    }
+
+    /// Returns `Ok` if `reason` fits in 255 characters.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.reason.chars().count() > 255 {
+            return Err(FormError::FieldTooLong(String::from(
+                "Field 'reason' must be at most 255 characters long.",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Form locking a user for some hours from the admin section.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LockUserForm {
+    pub hours: i64,
+}
+
+impl LockUserForm {
+    /// Longest lock, one year.
+    pub const MAX_HOURS: i64 = 365 * 24;
+
+    /// Returns `Ok` if the lock lasts between one hour and `MAX_HOURS`.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.hours < 1 || self.hours > Self::MAX_HOURS {
+            return Err(FormError::InvalidFormat(format!(
+                "Field 'hours' must be between 1 and {}.",
+                Self::MAX_HOURS
+            )));
+        }
+        Ok(())
+    }
+
+    /// When a lock set at `now` ends.
+    pub fn until(&self, now: NaiveDateTime) -> NaiveDateTime {
+        now + Duration::hours(self.hours)
+    }
+}
+
+/// Form granting or revoking a role from the admin section.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoleChangeForm {
+    pub role: String,
+    /// Either `grant` or `revoke`.
+    pub action: String,
+}
+
+impl RoleChangeForm {
+    /// Returns `true` if the role is to be granted rather than revoked.
+    pub fn grant(&self) -> bool {
+        self.action == "grant"
+    }
+
+    /// Returns `Ok` if `role` is one of `roles::ROLES` and `action` is
+    /// `grant` or `revoke`.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if !ROLES.contains(&self.role.as_str()) {
+            return Err(FormError::InvalidChoice(format!(
+                "Field 'role' must be one of: {}.",
+                ROLES.join(", ")
+            )));
+        }
+        if self.action != "grant" && self.action != "revoke" {
+            return Err(FormError::InvalidChoice(String::from(
+                "Field 'action' must be 'grant' or 'revoke'.",
+            )));
+        }
+        Ok(())
+    }
+}
+/// Form asking for a password reset link.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+impl ForgotPasswordForm {
+    /// Returns `Ok` if `email` is not empty and fits in 255 characters.
+    pub fn validate(&self) -> Result<(), FormError> {
+        let email = self.email.trim();
+        if email.is_empty() {
+            return Err(FormError::EmptyField(String::from(
+                "Field 'email' must not be empty.",
+            )));
+        }
+        if email.chars().count() > 255 {
+            return Err(FormError::FieldTooLong(String::from(
+                "Field 'email' must be at most 255 characters long.",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Form choosing a new password from a password reset link.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ResetPasswordForm {
+    pub password: String,
+    pub password_confirm: String,
+}
+
+impl ResetPasswordForm {
+    /// Returns `Ok` if `password` is at least 8 characters long, like at
+    /// signup, and matches `password_confirm`.
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.password.len() < 8 {
+            return Err(FormError::FieldTooShort(String::from(
+                "Field 'password' must be at least 8 characters long.",
+            )));
+        }
+        if self.password != self.password_confirm {
+            return Err(FormError::MismatchPasswords);
+        }
+        Ok(())
+    }
+}
+
+/// Form changing the password of the logged-in user on the account page.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub password: String,
+    pub password_confirm: String,
+}
+
+impl ChangePasswordForm {
+    /// Returns `Ok` if the new `password` passes `Valid::clean_password`, as
+    /// at signup, and matches `password_confirm`.
+    pub fn validate(&self) -> Result<(), FormError> {
+        let usr = UserLogin {
+            username: String::new(),
+            password: self.password.clone(),
+        };
+        usr.clean_password()?;
+        if self.password != self.password_confirm {
+            return Err(FormError::MismatchPasswords);
+        }
+        Ok(())
+    }
+}
+
+/// Form changing the username of the logged-in user on the account page.
+/// The current password is asked for as well.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChangeUsernameForm {
+    pub username: String,
+    pub password: String,
+}
+
+impl ChangeUsernameForm {
+    /// Returns `Ok` if the new `username` passes `Valid::clean_username` and,
+    /// as at signup, can't be mistaken for an email address.
+    pub fn validate(&self) -> Result<(), FormError> {
+        let usr = UserLogin {
+            username: self.username.trim().to_string(),
+            password: self.password.clone(),
+        };
+        usr.clean_username()?;
+        if usr.username.contains('@') {
+            return Err(FormError::InvalidFormat(String::from(
+                "Username must not contain '@'.",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Form carrying a code from the authenticator app or a recovery code, for
+/// the second login step and the two-factor settings.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TwoFactorCodeForm {
+    pub code: String,
+}
+
+/// JSON body registering a passkey on `/account/passkeys`: the credential the
+/// browser created, a name to tell it apart and the current password.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PasskeyRegistration {
+    pub name: String,
+    pub password: String,
+    pub credential: RegistrationResponse,
+}
+
+impl PasskeyRegistration {
+    /// Returns `Ok` if the trimmed `name` is 1 to 64 characters long.
+    pub fn validate(&self) -> Result<(), FormError> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(FormError::EmptyField(String::from(
+                "Passkey name must not be empty.",
+            )));
+        }
+        if name.chars().count() > 64 {
+            return Err(FormError::FieldTooLong(String::from(
+                "Passkey name must be at most 64 characters long.",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Value of field `name` of the urlencoded form in the body of `req`, if any.
+/// The body is put back for the handler, so middleware may look at a field
+/// before the handler extracts the whole form.
+pub(crate) async fn peek_form_field(
+    req: &mut ServiceRequest,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    if req.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+    let mut payload = req.take_payload();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_FORM_SIZE {
+            return Err(PayloadError::Overflow.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let value = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .ok()
+        .and_then(|pairs| pairs.into_iter().find(|(k, _)| k == name))
+        .map(|(_, v)| v);
+    req.set_payload(Payload::Stream(Box::pin(stream::once(
+        ok::<_, PayloadError>(body),
+    ))));
+    Ok(value)
+}
@@ -1,20 +1,47 @@
+pub mod account;
+pub mod admin;
+pub mod comments;
+pub mod email;
+pub mod passkeys;
+pub mod password;
+pub mod posts;
+pub mod tags;
+pub mod two_factor;
+
 use super::auth::Auth;
+use super::csrf;
+use super::email_verification;
+use super::errors::AuthError;
+use super::extractors::{CurrentUser, OptionalUser};
 use super::forms::{UserLogin, UserSignup, Valid};
-use super::users::{BaseUser, UserResponse};
+use super::lockout;
+use super::mailer::Mailer;
+use super::ratelimit::{LOGIN_RATE_LIMIT, SIGNUP_RATE_LIMIT};
+use super::roles::CAN_READ_USERS;
+use super::sessions::{
+    create_pending_session, create_session, expiry_cutoff, remember, remember_pending, session_id,
+    ClientInfo,
+};
+use super::two_factor::is_enabled;
+use super::users::{BaseUser, UserPage, UserQuery, UserResponse};
 use super::{db::*, DbPool};
 
-use actix_session::Session;
+use actix_session::{Session, UserSession};
 use actix_web::{
-    self, get,
-    http::StatusCode,
+    self, delete,
+    error::BlockingError,
+    get,
+    http::{header, StatusCode},
     post,
     web::{self, Form, HttpRequest},
     HttpResponse,
 };
 
+use chrono::Utc;
 use diesel::{sql_query, sql_types::*, RunQueryDsl};
 use handlebars::Handlebars;
 use serde::{Deserialize, Serialize};
+use std::env;
 
 /// Handler for resource 'GET /users/{id}
 ///
@@ -49,103 +76,396 @@ pub async fn retrieve_user_by_id(
         .unwrap()
 }
 
-/// Handler for resource 'POST /users'
-#[post("/signup")]
+/// Handler for resource 'GET /users'
+///
+/// Lists users a page at a time.  See `UserQuery` for the query parameters;
+/// the response carries `total` and the `next_offset` to continue from.
+/// Requires the `users:read` permission.
+///
+/// Example request:
+///     `$curl 'localhost/users?prefix=test&sort=username&limit=2'
+///      {"items":[{"id":13,"username":"testuser3",...}],"total":1,"limit":2,"offset":0,"next_offset":null}`
+#[get("/users", wrap = "CAN_READ_USERS")]
+pub async fn list_users(
+    pool: web::Data<DbPool>,
+    query: web::Query<UserQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    let pool = pool.clone();
+    let page = web::block(move || {
+        let conn = pool.get().map_err(|e| e.to_string())?;
+        let (items, total) = get_users_page(&conn, &query).map_err(|e| e.to_string())?;
+        Ok::<_, String>(UserPage::new(items, total, &query))
+    })
+    .await
+    .map_err(|e| {
+        eprintln!("{}", e);
+        HttpResponse::InternalServerError().finish()
+    })?;
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Handler for resource 'POST /signup'
+///
+/// Validates the form, checks that both passwords match and creates the user,
+/// then mails them a link to verify their email address.  Form errors are
+/// answered with `400`, a taken username or address with `409`.  When
+/// `SIGNUP_AUTO_LOGIN` isn't set to `false` the new user is logged in as well.
+#[post("/signup", wrap = "SIGNUP_RATE_LIMIT")]
 pub async fn signup(
-    hb: web::Data<Handlebars<'_>>,
     pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
     form: web::Form<UserSignup>,
+    request: HttpRequest,
+    session: Session,
 ) -> Result<HttpResponse, actix_web::Error> {
     let conn = pool
         .get()
         .expect("Could not establish connection from pool.");
+    let client = ClientInfo::from_request(&request);
 
-    let valid = form.validate();
+    let valid = form
+        .validate()
+        .and_then(|usr| usr.clean_identity().map(|_| usr))
+        .and_then(|usr| usr.clone().match_passwords().map(|_| usr));
 
     match valid {
-        Ok(usr) => web::block(move || usr.authenticate(&conn))
-            .await
-            .map(|_| {
+        Ok(usr) => match web::block(move || {
+            let u = usr.register(&conn)?;
+            let now = Utc::now().naive_utc();
+            if let Err(e) = email_verification::send_link(&conn, &**mailer, u.id, now) {
+                eprintln!("{}", e);
+            }
+            if signup_auto_login() {
+                create_session(&conn, u.id, &client).map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+        {
+            Ok(key) => {
+                if let Some(key) = key {
+                    remember(&session, &key)?;
+                }
                 Ok(HttpResponse::Ok()
                     .content_type("text/html; charset=utf-8")
                     .body(include_str!("../templates/signup_success.html")))
-            })
-            .map_err(|e| {
-                let data = json!({ "error": format!("{}", e) });
-                let body = hb.render("signup", &data).unwrap();
-                HttpResponse::InternalServerError()
-                    .content_type("text/html; charset=utf-8")
-                    .body(&body)
-            })?,
+            }
+            Err(BlockingError::Error(e)) => {
+                let status = match e {
+                    AuthError::UserAlreadyExists | AuthError::EmailAlreadyExists => {
+                        StatusCode::CONFLICT
+                    }
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                Ok(signup_error(&request, status, &e.to_string()))
+            }
+            Err(e) => Ok(signup_error(
+                &request,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            )),
+        },
 
-        Err(e) => {
-            let data = json!({ " error": e });
-            let body = hb.render("signup", &data).unwrap();
-            Ok(HttpResponse::InternalServerError()
-                .content_type("text/html; charset=utf-8")
-                .body(&body))
-        }
+        Err(e) => Ok(signup_error(
+            &request,
+            StatusCode::BAD_REQUEST,
+            &e.to_string(),
+        )),
     }
 }
 
-/// Handler for resource 'GET /users/new'
+/// Re-render the signup form with `error` shown below it.
+fn signup_error(req: &HttpRequest, status: StatusCode, error: &str) -> HttpResponse {
+    render(req, status, "signup", &json!({ "error": error }))
+}
+
+/// Returns `false` only if `SIGNUP_AUTO_LOGIN` is set to `false` or `0`.
+fn signup_auto_login() -> bool {
+    env::var("SIGNUP_AUTO_LOGIN")
+        .map(|v| v != "false" && v != "0")
+        .unwrap_or(true)
+}
+
+/// Handler for resource 'GET /signup'
 ///
 /// Returns form for new user signup.
 #[get("/signup")]
-pub async fn signup_form() -> Result<HttpResponse, actix_web::Error> {
-    Ok(HttpResponse::build(StatusCode::OK)
-        .content_type("text/html; charset=utf-8")
-        .body(include_str!("../templates/signup.html")))
+pub async fn signup_form(req: HttpRequest) -> HttpResponse {
+    render(&req, StatusCode::OK, "signup", &json!({}))
 }
+
 /// Handler for `GET /login`
 #[get("/login")]
-pub async fn login_form() -> Result<HttpResponse, actix_web::Error> {
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(include_str!("../templates/login.html")))
+pub async fn login_form(req: HttpRequest) -> HttpResponse {
+    render(&req, StatusCode::OK, "login", &json!({}))
 }
 
 /// Handler for `POST /login`
-#[post("/login")]
+///
+/// Accounts with two-factor authentication only get a pending session and are
+/// sent on to `/login/2fa`.
+#[post("/login", wrap = "LOGIN_RATE_LIMIT")]
 pub async fn login(
-    hb: web::Data<Handlebars<'_>>,
     form: web::Form<UserLogin>,
     pool: web::Data<DbPool>,
+    request: HttpRequest,
     session: Session,
 ) -> Result<HttpResponse, actix_web::Error> {
     let conn = pool
         .get()
         .expect("Could not establish connection from pool.");
+    let client = ClientInfo::from_request(&request);
 
     let valid = form.validate();
 
     match valid {
-        Ok(usr) => web::block(move || usr.authenticate(&conn))
-            .await
-            .map(|u| {
-                session.set("user", &u)?;
+        Ok(usr) => web::block(move || {
+            let u = lockout::authenticate(&conn, &usr, client.ip_address.as_deref())?;
+            if is_enabled(&conn, u.id)? {
+                create_pending_session(&conn, u.id, &client).map(|key| (key, true))
+            } else {
+                create_session(&conn, u.id, &client).map(|key| (key, false))
+            }
+        })
+        .await
+        .map(|(key, pending)| {
+            if pending {
+                remember_pending(&session, &key)?;
+                return Ok(redirect_to("/login/2fa"));
+            }
+            remember(&session, &key)?;
 
-                Ok(HttpResponse::Ok()
-                    .content_type("text/html; charset=utf-8")
-                    .body(include_str!("../templates/login_success.html")))
-            })
-            .map_err(|e| {
+            Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(include_str!("../templates/login_success.html")))
+        })
+        .map_err(|e| match e {
+            BlockingError::Error(e) => login_error(&request, &e),
+            e => {
                 let data = json!({ "error": format!("{}", e) });
-                let body = hb.render("login", &data).unwrap();
-                HttpResponse::InternalServerError()
-                    .content_type("text/html; charset=utf-8")
-                    .body(&body)
-            })?,
+                render(&request, StatusCode::INTERNAL_SERVER_ERROR, "login", &data)
+            }
+        })?,
         Err(e) => {
             let data = json!({ "error": e });
-            let body = hb.render("login", &data).unwrap();
-            Ok(HttpResponse::InternalServerError()
-                .content_type("text/html; charset=utf-8")
-                .body(&body))
+            Ok(render(
+                &request,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "login",
+                &data,
+            ))
         }
     }
 }
 
+/// Re-render the login form for the failed login `e`.  Disabled and locked
+/// accounts get their own messages.
+fn login_error(req: &HttpRequest, e: &AuthError) -> HttpResponse {
+    match e {
+        AuthError::AccountDisabled { reason } => render(
+            req,
+            StatusCode::FORBIDDEN,
+            "login",
+            &json!({ "disabled": true, "reason": reason }),
+        ),
+        AuthError::TooManyAttempts { .. } => render(
+            req,
+            StatusCode::TOO_MANY_REQUESTS,
+            "login",
+            &json!({ "error": e.to_string() }),
+        ),
+        AuthError::AccountLocked { until } => render(
+            req,
+            StatusCode::FORBIDDEN,
+            "login",
+            &json!({ "locked_until": until.format("%Y-%m-%d %H:%M").to_string() }),
+        ),
+        e => render(
+            req,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "login",
+            &json!({ "error": e.to_string() }),
+        ),
+    }
+}
+
+/// Handler for `POST /logout`
+///
+/// Ends the current session and clears the session cookie.
+#[post("/logout")]
+pub async fn logout(
+    pool: web::Data<DbPool>,
+    current: OptionalUser,
+    session: Session,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let OptionalUser(Some(current)) = current {
+        let conn = pool
+            .get()
+            .expect("Could not establish connection from pool.");
+        web::block(move || end_user_session(&conn, &current.session_key)).await?;
+    }
+    session.purge();
+    Ok(redirect_to("/login"))
+}
+
+/// Handler for `POST /logout/all`
+///
+/// Ends every session of the current user, e.g. to log out a lost device.
+#[post("/logout/all")]
+pub async fn logout_all(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    session: Session,
+) -> Result<HttpResponse, actix_web::Error> {
+    let conn = pool
+        .get()
+        .expect("Could not establish connection from pool.");
+    let user_id = current.user.id;
+    web::block(move || end_all_user_sessions(&conn, user_id)).await?;
+    session.purge();
+    Ok(redirect_to("/login"))
+}
+
+/// An active session as listed by `GET /sessions`.
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    /// Opaque id for `DELETE /sessions/{id}`, see `sessions::session_id`.
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+    pub current: bool,
+}
+
+/// Handler for `GET /sessions`
+///
+/// Lists the unexpired sessions of the current user.  Session keys are
+/// credentials, so each session is identified by an opaque id instead.
+///
+/// Example request:
+///     `$curl localhost/sessions
+///      [{"id":"3c8e1a...","user_agent":"curl/7.68.0","ip_address":"127.0.0.1",
+///        "created_at":"2021-07-24T12:00:00","last_seen_at":"2021-07-24T12:30:00","current":true}]`
+#[get("/sessions")]
+pub async fn list_sessions(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let conn = pool
+        .get()
+        .expect("Could not establish connection from pool.");
+
+    let user_id = current.user.id;
+    let items = web::block(move || get_user_sessions(&conn, user_id, expiry_cutoff())).await?;
+    let body: Vec<SessionResponse> = items
+        .into_iter()
+        .map(|s| SessionResponse {
+            id: session_id(&s.session_key),
+            current: s.session_key == current.session_key,
+            user_agent: s.user_agent,
+            ip_address: s.ip_address,
+            created_at: s.created_at,
+            last_seen_at: s.last_seen_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Handler for `DELETE /sessions/{id}`
+///
+/// Revokes one of the current user's sessions by the id `GET /sessions`
+/// lists.  Sessions of other users are reported as not found.
+#[delete("/sessions/{id}")]
+pub async fn revoke_session(
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+    current: CurrentUser,
+    session: Session,
+) -> Result<HttpResponse, actix_web::Error> {
+    let conn = pool
+        .get()
+        .expect("Could not establish connection from pool.");
+
+    let id = path.into_inner();
+    let user_id = current.user.id;
+    let current_key = current.session_key.clone();
+    // Resolve the id among the user's own sessions, then revoke by key.
+    let revoked = web::block(move || -> Result<_, diesel::result::Error> {
+        let key = get_user_sessions(&conn, user_id, expiry_cutoff())?
+            .into_iter()
+            .map(|s| s.session_key)
+            .find(|key| session_id(key) == id);
+        match key {
+            Some(key) => {
+                let removed = remove_user_session(&conn, user_id, &key)?;
+                Ok(Some((removed, key == current_key)))
+            }
+            None => Ok(None),
+        }
+    })
+    .await?;
+
+    match revoked {
+        Some((removed, revoked_current)) if removed > 0 => {
+            if revoked_current {
+                session.purge();
+            }
+            Ok(HttpResponse::NoContent().finish())
+        }
+        _ => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Render template `name` with `data` as an HTML response with given `status`.
+///
+/// The templates are taken from the app data.  An object `data` gets the
+/// session's CSRF token added as `csrf_token` for the forms on the page, see
+/// `csrf::CsrfProtect`.
+pub fn render(
+    req: &HttpRequest,
+    status: StatusCode,
+    name: &str,
+    data: &serde_json::Value,
+) -> HttpResponse {
+    let hb = match req.app_data::<web::Data<Handlebars<'static>>>() {
+        Some(hb) => hb,
+        None => {
+            eprintln!("No templates registered.");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut data = data.clone();
+    if let Some(fields) = data.as_object_mut() {
+        match csrf::token(&req.get_session()) {
+            Ok(token) => fields.insert(String::from("csrf_token"), token.into()),
+            Err(e) => {
+                eprintln!("{}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+    }
+    match hb.render(name, &data) {
+        Ok(body) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body),
+        Err(e) => {
+            eprintln!("{}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// `303 See Other` pointing at `location`.
+pub(crate) fn redirect_to(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::auth::Auth;
@@ -0,0 +1,129 @@
+//! Handlers for the account settings page, see `account`.
+
+use super::render;
+use crate::account::{change_password, change_username};
+use crate::db::get_user_by_id;
+use crate::errors::AccountError;
+use crate::extractors::CurrentUser;
+use crate::forms::{ChangePasswordForm, ChangeUsernameForm};
+use crate::models::User;
+use crate::ratelimit::ACCOUNT_RATE_LIMIT;
+use crate::sessions::ClientInfo;
+use crate::DbPool;
+
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, web, HttpRequest, HttpResponse,
+    ResponseError,
+};
+use diesel::MysqlConnection;
+use serde_json::Value;
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, AccountError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, AccountError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| AccountError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => AccountError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Render the account page of `user`, with `outcome` naming the change just
+/// made or why it failed, if any.
+fn account_page(
+    req: &HttpRequest,
+    user: &User,
+    outcome: Option<Result<&str, AccountError>>,
+) -> HttpResponse {
+    let mut data = json!({
+        "username": user.username,
+        "email": user.email,
+        "email_verified": user.email_verified_at.is_some(),
+    });
+    let status = match outcome {
+        None => StatusCode::OK,
+        Some(Ok(change)) => {
+            data[change] = Value::Bool(true);
+            StatusCode::OK
+        }
+        Some(Err(e)) => {
+            data["error"] = Value::String(e.to_string());
+            e.status_code()
+        }
+    };
+    render(req, status, "account", &data)
+}
+
+/// Handler for `GET /account`
+#[get("/account")]
+pub async fn account_form(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let user = run(&pool, move |conn| Ok(get_user_by_id(conn, user_id)?)).await?;
+    Ok(account_page(&req, &user, None))
+}
+
+/// Handler for `POST /account/password`
+///
+/// Changes the password, given the current one, and logs the account out
+/// everywhere else.
+#[post("/account/password", wrap = "ACCOUNT_RATE_LIMIT")]
+pub async fn change_password_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<ChangePasswordForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let user_id = current.user.id;
+    let ip = ClientInfo::from_request(&req).ip_address;
+    let changed = run(&pool, move |conn| {
+        change_password(conn, &current, &form, ip.as_deref())
+    })
+    .await;
+    let user = run(&pool, move |conn| Ok(get_user_by_id(conn, user_id)?)).await?;
+    Ok(account_page(
+        &req,
+        &user,
+        Some(changed.map(|_| "password_changed")),
+    ))
+}
+
+/// Handler for `POST /account/username`
+///
+/// Renames the account, given its password, unless the name is taken.
+#[post("/account/username", wrap = "ACCOUNT_RATE_LIMIT")]
+pub async fn change_username_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<ChangeUsernameForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let user_id = current.user.id;
+    let ip = ClientInfo::from_request(&req).ip_address;
+    let changed = run(&pool, move |conn| {
+        change_username(conn, &current, &form, ip.as_deref())
+    })
+    .await;
+    match changed {
+        Ok(user) => Ok(account_page(&req, &user, Some(Ok("username_changed")))),
+        Err(e) => {
+            let user = run(&pool, move |conn| Ok(get_user_by_id(conn, user_id)?)).await?;
+            Ok(account_page(&req, &user, Some(Err(e))))
+        }
+    }
+}
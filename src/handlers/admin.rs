@@ -0,0 +1,323 @@
+//! Handlers for the admin section under `/admin`.
+//!
+//! Every route takes the `users:manage` permission.  Admins may search users,
+//! look at their roles and active sessions, change their roles, disable, lock
+//! for some hours and re-enable them and delete them.  Each action is a POST
+//! form, protected by `csrf::CsrfProtect` like every other, and admins cannot
+//! disable, lock, delete or demote themselves.
+
+use super::{redirect_to, render};
+use crate::db::*;
+use crate::errors::AdminError;
+use crate::extractors::CurrentUser;
+use crate::forms::{DisableUserForm, LockUserForm, RoleChangeForm};
+use crate::lockout::USERNAME_SCOPE;
+use crate::roles::{ADMIN, CAN_MANAGE_USERS, ROLES};
+use crate::sessions::expiry_cutoff;
+use crate::users::{UserPage, UserQuery, UserResponse};
+use crate::DbPool;
+
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, web, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use diesel::{Connection, MysqlConnection};
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, AdminError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, AdminError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| AdminError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => AdminError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Fails with `OwnAccount` if the admin `current` is acting on themselves.
+fn not_self(current: &CurrentUser, user_id: i32) -> Result<(), AdminError> {
+    if current.user.id == user_id {
+        return Err(AdminError::OwnAccount);
+    }
+    Ok(())
+}
+
+/// Handler for `GET /admin`
+#[get("/admin", wrap = "CAN_MANAGE_USERS")]
+pub async fn dashboard() -> HttpResponse {
+    redirect_to("/admin/users")
+}
+
+/// Handler for `GET /admin/users`
+///
+/// Lists users a page at a time, optionally only those whose username starts
+/// with `prefix`.
+#[get("/admin/users", wrap = "CAN_MANAGE_USERS")]
+pub async fn list_users_page(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    query: web::Query<UserQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut query = query.into_inner();
+    query.prefix = query.prefix.filter(|p| !p.is_empty());
+    let page_query = query.clone();
+    let (items, total) = run(&pool, move |conn| Ok(get_users_page(conn, &page_query)?)).await?;
+    let page = UserPage::new(items, total, &query);
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "admin_users",
+        &json!({
+            "page": page,
+            "prefix": query.prefix,
+            "has_prev": page.offset > 0,
+            "prev_offset": (page.offset - page.limit).max(0),
+        }),
+    ))
+}
+
+/// Handler for `GET /admin/users/{id}`
+///
+/// Shows a user with their roles, their active sessions and the action forms.
+#[get("/admin/users/{id}", wrap = "CAN_MANAGE_USERS")]
+pub async fn view_user_page(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let now = Utc::now().naive_utc();
+    let (user, roles, sessions, login_lock) = run(&pool, move |conn| {
+        let user = get_user_by_id(conn, id)?;
+        let roles = get_user_roles(conn, id)?;
+        let sessions = get_user_sessions(conn, id, expiry_cutoff())?;
+        let login_lock = get_login_lock(conn, USERNAME_SCOPE, &user.username, now)?;
+        Ok((user, roles, sessions, login_lock))
+    })
+    .await?;
+    // Locked by support or by too many failed logins, whichever lasts longer.
+    let locked_until = user
+        .locked_until
+        .filter(|_| user.is_locked(now))
+        .max(login_lock);
+    let status = json!({
+        "is_active": user.is_active,
+        "disabled_reason": user.disabled_reason,
+        "locked_until": locked_until,
+    });
+    let user = UserResponse {
+        id: user.id,
+        username: user.username,
+        created_at: user.created_at,
+    };
+    // Session keys are credentials, so only their metadata is shown.
+    let sessions: Vec<_> = sessions
+        .iter()
+        .map(|s| {
+            json!({
+                "user_agent": s.user_agent,
+                "ip_address": s.ip_address,
+                "created_at": s.created_at,
+                "last_seen_at": s.last_seen_at,
+            })
+        })
+        .collect();
+    let other_roles: Vec<_> = ROLES
+        .iter()
+        .filter(|r| !roles.iter().any(|h| h == *r))
+        .collect();
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "admin_user",
+        &json!({
+            "user": user,
+            "status": status,
+            "roles": roles,
+            "other_roles": other_roles,
+            "sessions": sessions,
+            "is_self": current.user.id == id,
+        }),
+    ))
+}
+
+/// Handler for `POST /admin/users/{id}/roles`
+///
+/// Grants or revokes one role, then goes back to the user's page.
+#[post("/admin/users/{id}/roles", wrap = "CAN_MANAGE_USERS")]
+pub async fn change_role_form(
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+    form: web::Form<RoleChangeForm>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let form = form.into_inner();
+    form.validate().map_err(AdminError::from)?;
+    if !form.grant() && form.role == ADMIN {
+        not_self(&current, id)?;
+    }
+    run(&pool, move |conn| {
+        get_user_by_id(conn, id)?;
+        if form.grant() {
+            grant_role(conn, id, &form.role)?;
+        } else {
+            revoke_role(conn, id, &form.role)?;
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(redirect_to(&format!("/admin/users/{}", id)))
+}
+
+/// Handler for `POST /admin/users/{id}/disable`
+///
+/// Keeps the user from logging in, for the reason given, and ends all their
+/// sessions.  Their posts, comments and roles are kept.
+#[post("/admin/users/{id}/disable", wrap = "CAN_MANAGE_USERS")]
+pub async fn disable_user_form(
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+    form: web::Form<DisableUserForm>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let form = form.into_inner();
+    form.validate().map_err(AdminError::from)?;
+    not_self(&current, id)?;
+    run(&pool, move |conn| {
+        conn.transaction(|| {
+            get_user_by_id(conn, id)?;
+            set_user_active(conn, id, false, form.reason())?;
+            end_all_user_sessions(conn, id)?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(redirect_to(&format!("/admin/users/{}", id)))
+}
+
+/// Handler for `POST /admin/users/{id}/lock`
+///
+/// Keeps the user from logging in for the hours given, e.g. while support
+/// looks into their account, and ends all their sessions.
+#[post("/admin/users/{id}/lock", wrap = "CAN_MANAGE_USERS")]
+pub async fn lock_user_form(
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+    form: web::Form<LockUserForm>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let form = form.into_inner();
+    form.validate().map_err(AdminError::from)?;
+    not_self(&current, id)?;
+    let until = form.until(Utc::now().naive_utc());
+    run(&pool, move |conn| {
+        conn.transaction(|| {
+            get_user_by_id(conn, id)?;
+            lock_user_until(conn, id, Some(until))?;
+            end_all_user_sessions(conn, id)?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(redirect_to(&format!("/admin/users/{}", id)))
+}
+
+/// Handler for `POST /admin/users/{id}/enable`
+///
+/// Lets a disabled or locked user log in again, forgetting their failed logins.
+#[post("/admin/users/{id}/enable", wrap = "CAN_MANAGE_USERS")]
+pub async fn enable_user_form(
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    run(&pool, move |conn| {
+        let user = get_user_by_id(conn, id)?;
+        set_user_active(conn, id, true, None)?;
+        clear_login_failures(conn, USERNAME_SCOPE, &user.username)?;
+        Ok(())
+    })
+    .await?;
+    Ok(redirect_to(&format!("/admin/users/{}", id)))
+}
+
+/// Handler for `POST /admin/users/{id}/delete`
+///
+/// Deletes the user along with their sessions, posts and comments.
+#[post("/admin/users/{id}/delete", wrap = "CAN_MANAGE_USERS")]
+pub async fn delete_user_form(
+    pool: web::Data<DbPool>,
+    path: web::Path<i32>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    not_self(&current, id)?;
+    run(&pool, move |conn| {
+        conn.transaction(|| {
+            get_user_by_id(conn, id)?;
+            end_all_user_sessions(conn, id)?;
+            remove_user_by_id(conn, id)?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(redirect_to("/admin/users"))
+}
+
+#[cfg(test)]
+mod tests {
+    use handlebars::Handlebars;
+
+    #[test]
+    fn own_account_actions_hidden() {
+        let mut hb = Handlebars::new();
+        hb.register_template_string(
+            "admin_user",
+            include_str!("../../templates/admin_user.html"),
+        )
+        .unwrap();
+        let data = |is_self: bool, is_active: bool| {
+            json!({
+                "user": { "id": 1, "username": "root", "created_at": "2021-08-08T10:00:00" },
+                "status": { "is_active": is_active, "disabled_reason": "Spam" },
+                "roles": ["admin", "reader"],
+                "other_roles": ["author", "editor"],
+                "sessions": [],
+                "is_self": is_self,
+                "csrf_token": "t0ken",
+            })
+        };
+
+        let own = hb.render("admin_user", &data(true, true)).unwrap();
+        assert_eq!(own.matches(r#"value="revoke""#).count(), 1);
+        assert!(!own.contains("/disable"));
+        assert!(!own.contains("/lock"));
+        assert!(!own.contains("/delete"));
+
+        let other = hb.render("admin_user", &data(false, true)).unwrap();
+        assert_eq!(other.matches(r#"value="revoke""#).count(), 2);
+        assert!(other.contains("/admin/users/1/delete"));
+        assert!(other.contains("/admin/users/1/lock"));
+        assert_eq!(other.matches(r#"value="t0ken""#).count(), 6);
+        assert!(!other.contains("/enable"));
+
+        let disabled = hb.render("admin_user", &data(false, false)).unwrap();
+        assert!(disabled.contains("Disabled: Spam"));
+        assert!(disabled.contains("/admin/users/1/enable"));
+        assert!(!disabled.contains("/disable"));
+        assert!(!disabled.contains("/lock"));
+    }
+}
@@ -0,0 +1,449 @@
+//! Handlers for comments on posts.
+//!
+//! Comments may reply to another comment on the same post, forming threads.
+//! Comments by accounts younger than `NEW_ACCOUNT_AGE` seconds (default: three
+//! days) start out `pending` and are only shown to their author and the post's
+//! moderators until one of them approves them.  A post's moderators are its
+//! author and everyone with the `comments:moderate` permission.
+
+use super::posts::{get_visible_post, run};
+use super::{redirect_to, render};
+use crate::db::*;
+use crate::errors::{FormError, PostError};
+use crate::extractors::{CurrentUser, OptionalUser};
+use crate::forms::CommentForm;
+use crate::markdown;
+use crate::models::{Comment, NewComment, Post};
+use crate::roles::{CAN_WRITE_COMMENTS, MODERATE_COMMENTS};
+use crate::sessions::CurrentSession;
+use crate::users::UserResponse;
+use crate::DbPool;
+
+use actix_web::{delete, get, http::StatusCode, post, put, web, HttpRequest, HttpResponse};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::MysqlConnection;
+use serde_json::Value;
+use std::env;
+
+/// How old an account must be before its comments skip moderation, from
+/// `NEW_ACCOUNT_AGE` in seconds.
+pub fn new_account_age() -> Duration {
+    let secs = env::var("NEW_ACCOUNT_AGE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3 * 24 * 60 * 60);
+    Duration::seconds(secs)
+}
+
+/// Returns `true` if a comment by `user` on a post by `post_author_id` must be
+/// approved before others can see it.
+pub fn needs_moderation(user: &UserResponse, post_author_id: i32, now: NaiveDateTime) -> bool {
+    user.id != post_author_id && now - user.created_at < new_account_age()
+}
+
+/// Who is reading or changing comments.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Viewer {
+    /// Id of the logged-in user, if any.
+    pub id: Option<i32>,
+    /// Whether they may moderate the comments on every post.
+    pub moderator: bool,
+}
+
+impl Viewer {
+    pub(super) fn of(current: Option<&CurrentSession>) -> Self {
+        Viewer {
+            id: current.map(|c| c.user.id),
+            moderator: current.is_some_and(|c| c.can(MODERATE_COMMENTS)),
+        }
+    }
+
+    /// Returns `true` if the viewer may approve and delete the comments on a
+    /// post by `post_author_id`.
+    fn moderates(&self, post_author_id: i32) -> bool {
+        self.moderator || self.id == Some(post_author_id)
+    }
+}
+
+/// Returns `true` if `viewer` may see `comment` on a post by `post_author_id`.
+fn is_visible(comment: &Comment, viewer: Viewer, post_author_id: i32) -> bool {
+    comment.is_approved()
+        || viewer.id == Some(comment.author_id)
+        || viewer.moderates(post_author_id)
+}
+
+/// Arrange the comments `viewer` may see into threads of replies under
+/// `parent`, oldest first.
+///
+/// Each node carries the comment, its author's username, its rendered body,
+/// what `viewer` may do with it and its `replies`.  Replies to hidden comments
+/// are hidden with them.
+pub(super) fn thread(
+    items: &[(Comment, String)],
+    parent: Option<i32>,
+    viewer: Viewer,
+    post_author_id: i32,
+) -> Vec<Value> {
+    items
+        .iter()
+        .filter(|(c, _)| c.parent_id == parent && is_visible(c, viewer, post_author_id))
+        .map(|(c, author)| {
+            json!({
+                "comment": c,
+                "author": author,
+                "body_html": markdown::render(&c.body),
+                "can_approve": !c.is_approved() && viewer.moderates(post_author_id),
+                "can_delete": viewer.id == Some(c.author_id) || viewer.moderates(post_author_id),
+                "replies": thread(items, Some(c.id), viewer, post_author_id),
+            })
+        })
+        .collect()
+}
+
+/// Comment on post `slug` as `current`, returning the new comment.  Only
+/// users who verified their email address may comment.
+fn insert_comment(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    slug: &str,
+    form: &CommentForm,
+) -> Result<Comment, PostError> {
+    form.validate()?;
+    if !current.email_verified {
+        return Err(PostError::EmailNotVerified);
+    }
+    let (user, viewer) = (&current.user, Viewer::of(Some(current)));
+    let post = get_visible_post(conn, slug, viewer.id)?;
+    if let Some(parent_id) = form.parent_id {
+        let unknown = || FormError::InvalidChoice(format!("Unknown comment {}.", parent_id));
+        let parent = get_comment_by_id(conn, parent_id).map_err(|_| unknown())?;
+        if parent.post_id != post.id || !is_visible(&parent, viewer, post.author_id) {
+            return Err(unknown().into());
+        }
+    }
+    let status =
+        if !viewer.moderator && needs_moderation(user, post.author_id, Utc::now().naive_utc()) {
+            "pending"
+        } else {
+            "approved"
+        };
+    let item = NewComment {
+        post_id: post.id,
+        author_id: user.id,
+        parent_id: form.parent_id,
+        body: &form.body,
+        status,
+    };
+    let id = create_comment(conn, item)?;
+    Ok(get_comment_by_id(conn, id)?)
+}
+
+/// Load comment `id` and its post as seen by `viewer`.
+fn get_visible_comment(
+    conn: &MysqlConnection,
+    id: i32,
+    viewer: Viewer,
+) -> Result<(Comment, Post), PostError> {
+    let comment = get_comment_by_id(conn, id)?;
+    let post = get_post_by_id(conn, comment.post_id)?;
+    if !is_visible(&comment, viewer, post.author_id)
+        || !(post.is_public() || viewer.id == Some(post.author_id))
+    {
+        return Err(PostError::NotFound);
+    }
+    Ok((comment, post))
+}
+
+/// Replace the body of comment `id` written by `current`.  Edits by accounts
+/// that still need moderation send the comment back to the queue.
+fn edit_comment(
+    conn: &MysqlConnection,
+    id: i32,
+    current: &CurrentSession,
+    form: &CommentForm,
+) -> Result<Comment, PostError> {
+    form.validate()?;
+    let (user, viewer) = (&current.user, Viewer::of(Some(current)));
+    let (comment, post) = get_visible_comment(conn, id, viewer)?;
+    if comment.author_id != user.id {
+        return Err(PostError::Forbidden);
+    }
+    update_comment_body(conn, id, &form.body)?;
+    if !viewer.moderator && needs_moderation(user, post.author_id, Utc::now().naive_utc()) {
+        set_comment_status(conn, id, "pending")?;
+    }
+    Ok(get_comment_by_id(conn, id)?)
+}
+
+/// Approve comment `id` on a post `viewer` moderates.
+fn approve_comment(conn: &MysqlConnection, id: i32, viewer: Viewer) -> Result<Comment, PostError> {
+    let (_, post) = get_visible_comment(conn, id, viewer)?;
+    if !viewer.moderates(post.author_id) {
+        return Err(PostError::Forbidden);
+    }
+    set_comment_status(conn, id, "approved")?;
+    Ok(get_comment_by_id(conn, id)?)
+}
+
+/// Delete comment `id` and its replies, if `viewer` wrote it or moderates its
+/// post.  Returns the post it was on.
+fn delete_comment(conn: &MysqlConnection, id: i32, viewer: Viewer) -> Result<Post, PostError> {
+    let (comment, post) = get_visible_comment(conn, id, viewer)?;
+    if viewer.id != Some(comment.author_id) && !viewer.moderates(post.author_id) {
+        return Err(PostError::Forbidden);
+    }
+    remove_comment_by_id(conn, id)?;
+    Ok(post)
+}
+
+/// Pending comments on the posts `viewer` moderates, each with its author and post.
+fn pending_comments(conn: &MysqlConnection, viewer: Viewer) -> Result<Vec<Value>, PostError> {
+    let post_author_id = if viewer.moderator { None } else { viewer.id };
+    let items = get_pending_comments(conn, post_author_id)?;
+    Ok(items
+        .into_iter()
+        .map(|(comment, author, post)| {
+            json!({
+                "body_html": markdown::render(&comment.body),
+                "comment": comment,
+                "author": author,
+                "post": { "slug": post.slug, "title": post.title },
+            })
+        })
+        .collect())
+}
+
+/// Handler for `POST /posts/{slug}/comments`
+///
+/// Adds a comment and redirects back to it.
+#[post("/posts/{slug}/comments", wrap = "CAN_WRITE_COMMENTS")]
+pub async fn create_comment_form(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+    form: web::Form<CommentForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    let post_slug = slug.clone();
+    let comment = run(&pool, move |conn| {
+        insert_comment(conn, &current, &slug, &form)
+    })
+    .await?;
+    Ok(redirect_to(&format!(
+        "/posts/{}#comment-{}",
+        post_slug, comment.id
+    )))
+}
+
+/// Handler for `POST /comments/{id}/approve`
+#[post("/comments/{id}/approve")]
+pub async fn approve_comment_form(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let viewer = Viewer::of(Some(&current));
+    run(&pool, move |conn| approve_comment(conn, id, viewer)).await?;
+    Ok(redirect_to("/comments/pending"))
+}
+
+/// Handler for `POST /comments/{id}/delete`
+#[post("/comments/{id}/delete")]
+pub async fn delete_comment_form(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let viewer = Viewer::of(Some(&current));
+    let post = run(&pool, move |conn| delete_comment(conn, id, viewer)).await?;
+    Ok(redirect_to(&format!("/posts/{}", post.slug)))
+}
+
+/// Handler for `GET /comments/pending`
+///
+/// The moderation queue: comments awaiting approval on the current user's
+/// posts, or on all posts for users who may moderate every comment.
+#[get("/comments/pending")]
+pub async fn pending_comments_page(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let viewer = Viewer::of(Some(&current));
+    let comments = run(&pool, move |conn| pending_comments(conn, viewer)).await?;
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "comments_pending",
+        &json!({ "comments": comments }),
+    ))
+}
+
+/// Handler for `GET /api/posts/{slug}/comments`
+///
+/// Example request:
+///     `$curl localhost/api/posts/hello-world/comments
+///      [{"comment":{"id":1,"post_id":1,"parent_id":null,...},"author":"bender3000","replies":[...]}]`
+#[get("/api/posts/{slug}/comments")]
+pub async fn list_comments_json(
+    pool: web::Data<DbPool>,
+    current: OptionalUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let viewer = Viewer::of(current.as_ref());
+    let (post, items) = run(&pool, move |conn| {
+        let post = get_visible_post(conn, &slug, viewer.id)?;
+        let items = get_comments_by_post(conn, post.id)?;
+        Ok((post, items))
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(thread(&items, None, viewer, post.author_id)))
+}
+
+/// Handler for `POST /api/posts/{slug}/comments`
+#[post("/api/posts/{slug}/comments", wrap = "CAN_WRITE_COMMENTS")]
+pub async fn create_comment_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+    form: web::Json<CommentForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    let comment = run(&pool, move |conn| {
+        insert_comment(conn, &current, &slug, &form)
+    })
+    .await?;
+    Ok(HttpResponse::Created()
+        .header("Location", format!("/api/comments/{}", comment.id))
+        .json(comment))
+}
+
+/// Handler for `PUT /api/comments/{id}`
+#[put("/api/comments/{id}", wrap = "CAN_WRITE_COMMENTS")]
+pub async fn update_comment_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+    form: web::Json<CommentForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let current = current.0;
+    let comment = run(&pool, move |conn| edit_comment(conn, id, &current, &form)).await?;
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+/// Handler for `DELETE /api/comments/{id}`
+#[delete("/api/comments/{id}")]
+pub async fn delete_comment_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let viewer = Viewer::of(Some(&current));
+    run(&pool, move |conn| delete_comment(conn, id, viewer)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Handler for `POST /api/comments/{id}/approve`
+#[post("/api/comments/{id}/approve")]
+pub async fn approve_comment_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let id = path.into_inner();
+    let viewer = Viewer::of(Some(&current));
+    let comment = run(&pool, move |conn| approve_comment(conn, id, viewer)).await?;
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+/// Handler for `GET /api/comments/pending`
+#[get("/api/comments/pending")]
+pub async fn pending_comments_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let viewer = Viewer::of(Some(&current));
+    let comments = run(&pool, move |conn| pending_comments(conn, viewer)).await?;
+    Ok(HttpResponse::Ok().json(comments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: i32, age: Duration) -> UserResponse {
+        UserResponse {
+            id,
+            username: format!("user{}", id),
+            created_at: Utc::now().naive_utc() - age,
+        }
+    }
+
+    fn comment(id: i32, parent_id: Option<i32>, author_id: i32, status: &str) -> (Comment, String) {
+        let now = Utc::now().naive_utc();
+        let comment = Comment {
+            id,
+            post_id: 1,
+            author_id,
+            parent_id,
+            body: format!("Comment {}", id),
+            status: status.to_owned(),
+            created_at: now,
+            updated_at: now,
+        };
+        (comment, format!("user{}", author_id))
+    }
+
+    #[test]
+    fn new_accounts_are_moderated() {
+        let now = Utc::now().naive_utc();
+        assert!(needs_moderation(&user(2, Duration::hours(1)), 1, now));
+        assert!(!needs_moderation(&user(2, Duration::days(30)), 1, now));
+        assert!(!needs_moderation(&user(1, Duration::hours(1)), 1, now));
+    }
+
+    #[test]
+    fn comments_threaded_and_pending_hidden() {
+        let items = vec![
+            comment(1, None, 2, "approved"),
+            comment(2, Some(1), 3, "approved"),
+            comment(3, None, 3, "pending"),
+            comment(4, Some(3), 2, "approved"),
+        ];
+
+        let viewer = |id| Viewer {
+            id: Some(id),
+            moderator: false,
+        };
+        let public = thread(&items, None, Viewer::default(), 1);
+        assert_eq!(public.len(), 1);
+        assert_eq!(public[0]["comment"]["id"], 1);
+        assert_eq!(public[0]["replies"][0]["comment"]["id"], 2);
+        assert_eq!(public[0]["can_delete"], false);
+
+        let commenter = thread(&items, None, viewer(3), 1);
+        assert_eq!(commenter.len(), 2);
+        assert_eq!(commenter[1]["replies"][0]["comment"]["id"], 4);
+
+        let post_author = thread(&items, None, viewer(1), 1);
+        assert_eq!(post_author.len(), 2);
+        assert_eq!(post_author[1]["can_approve"], true);
+        assert_eq!(post_author[0]["can_approve"], false);
+
+        let moderator = Viewer {
+            id: Some(4),
+            moderator: true,
+        };
+        let moderated = thread(&items, None, moderator, 1);
+        assert_eq!(moderated.len(), 2);
+        assert_eq!(moderated[1]["can_approve"], true);
+        assert_eq!(moderated[0]["can_delete"], true);
+    }
+}
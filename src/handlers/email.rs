@@ -0,0 +1,93 @@
+//! Handlers for verifying email addresses, see `email_verification`.
+
+use super::render;
+use crate::email_verification::{send_link, verify};
+use crate::errors::VerificationError;
+use crate::extractors::CurrentUser;
+use crate::mailer::Mailer;
+use crate::ratelimit::EMAIL_VERIFICATION_RATE_LIMIT;
+use crate::DbPool;
+
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, web, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use diesel::MysqlConnection;
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, VerificationError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, VerificationError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| VerificationError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => VerificationError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Handler for `GET /email/verify`
+///
+/// Tells the current user whether their address is verified and offers to
+/// send a new link if not.
+#[get("/email/verify")]
+pub async fn verification_status(req: HttpRequest, current: CurrentUser) -> HttpResponse {
+    let data = json!({ "verified": current.email_verified });
+    render(&req, StatusCode::OK, "email_verify", &data)
+}
+
+/// Handler for `POST /email/verify`
+///
+/// Mails the current user a new verification link.
+#[post("/email/verify", wrap = "EMAIL_VERIFICATION_RATE_LIMIT")]
+pub async fn resend_verification(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    current: CurrentUser,
+) -> HttpResponse {
+    let user_id = current.user.id;
+    let now = Utc::now().naive_utc();
+    let sent = run(&pool, move |conn| send_link(conn, &**mailer, user_id, now)).await;
+    let data = match &sent {
+        Ok(()) => json!({ "sent": true }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    let status = match &sent {
+        Ok(()) => StatusCode::OK,
+        Err(e) => actix_web::ResponseError::status_code(e),
+    };
+    render(&req, status, "email_verify", &data)
+}
+
+/// Handler for `GET /email/verify/{token}`
+///
+/// The link mailed by `send_link`; verifies the address it was sent to.
+#[get("/email/verify/{token}")]
+pub async fn verify_email(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let token = path.into_inner();
+    let now = Utc::now().naive_utc();
+    let verified = run(&pool, move |conn| verify(conn, &token, now)).await;
+    let data = match &verified {
+        Ok(()) => json!({ "verified": true }),
+        Err(VerificationError::InvalidToken) => json!({ "invalid": true }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    let status = match &verified {
+        Ok(()) => StatusCode::OK,
+        Err(e) => actix_web::ResponseError::status_code(e),
+    };
+    render(&req, status, "email_verify", &data)
+}
@@ -0,0 +1,130 @@
+//! Handlers for registering passkeys and logging in with them, see `passkeys`.
+//!
+//! The ceremonies run in the browser, so apart from the settings page these
+//! take and return JSON.
+
+use super::{redirect_to, render};
+use crate::errors::PasskeyError;
+use crate::extractors::CurrentUser;
+use crate::forms::PasskeyRegistration;
+use crate::passkeys::{
+    finish_login, finish_registration, list, remove, start_login, start_registration,
+};
+use crate::ratelimit::PASSKEY_RATE_LIMIT;
+use crate::sessions::{remember, ClientInfo};
+use crate::webauthn::AssertionResponse;
+use crate::DbPool;
+
+use actix_session::Session;
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, web, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use diesel::MysqlConnection;
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, PasskeyError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, PasskeyError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| PasskeyError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => PasskeyError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Handler for `GET /account/passkeys`
+#[get("/account/passkeys")]
+pub async fn passkeys_page(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let passkeys = run(&pool, move |conn| list(conn, user_id)).await?;
+    let data = json!({ "passkeys": passkeys });
+    Ok(render(&req, StatusCode::OK, "passkeys", &data))
+}
+
+/// Handler for `POST /account/passkeys/options`
+///
+/// Returns the options for `navigator.credentials.create()`.
+#[post("/account/passkeys/options", wrap = "PASSKEY_RATE_LIMIT")]
+pub async fn registration_options(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let now = Utc::now().naive_utc();
+    let options = run(&pool, move |conn| start_registration(conn, &current, now)).await?;
+    Ok(HttpResponse::Ok().json(options))
+}
+
+/// Handler for `POST /account/passkeys`
+///
+/// Stores the passkey the browser created, given the current password.
+#[post("/account/passkeys", wrap = "PASSKEY_RATE_LIMIT")]
+pub async fn register_passkey(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    body: web::Json<PasskeyRegistration>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let ip = ClientInfo::from_request(&req).ip_address;
+    let now = Utc::now().naive_utc();
+    let passkey = run(&pool, move |conn| {
+        finish_registration(conn, &current, &body, ip.as_deref(), now)
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(passkey))
+}
+
+/// Handler for `POST /account/passkeys/{id}/delete`
+#[post("/account/passkeys/{id}/delete")]
+pub async fn delete_passkey(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let id = path.into_inner();
+    run(&pool, move |conn| remove(conn, user_id, id)).await?;
+    Ok(redirect_to("/account/passkeys"))
+}
+
+/// Handler for `POST /login/passkey/options`
+///
+/// Returns the options for `navigator.credentials.get()`.
+#[post("/login/passkey/options", wrap = "PASSKEY_RATE_LIMIT")]
+pub async fn login_options(pool: web::Data<DbPool>) -> Result<HttpResponse, actix_web::Error> {
+    let now = Utc::now().naive_utc();
+    let options = run(&pool, move |conn| start_login(conn, now)).await?;
+    Ok(HttpResponse::Ok().json(options))
+}
+
+/// Handler for `POST /login/passkey`
+///
+/// Logs in the owner of the passkey that signed the challenge.
+#[post("/login/passkey", wrap = "PASSKEY_RATE_LIMIT")]
+pub async fn login_with_passkey(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    session: Session,
+    body: web::Json<AssertionResponse>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client = ClientInfo::from_request(&req);
+    let now = Utc::now().naive_utc();
+    let key = run(&pool, move |conn| finish_login(conn, &body, &client, now)).await?;
+    remember(&session, &key)?;
+    Ok(HttpResponse::Ok().json(json!({ "redirect": "/" })))
+}
@@ -0,0 +1,129 @@
+//! Handlers for resetting a forgotten password, see `password_reset`.
+
+use super::render;
+use crate::errors::ResetError;
+use crate::forms::{ForgotPasswordForm, ResetPasswordForm};
+use crate::mailer::Mailer;
+use crate::password_reset::{is_valid, reset_password, send_link};
+use crate::ratelimit::PASSWORD_RESET_RATE_LIMIT;
+use crate::DbPool;
+
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, rt, web, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use diesel::MysqlConnection;
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, ResetError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, ResetError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| ResetError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => ResetError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Handler for `GET /password/forgot`
+#[get("/password/forgot")]
+pub async fn forgot_password_form(req: HttpRequest) -> HttpResponse {
+    render(&req, StatusCode::OK, "password_forgot", &json!({}))
+}
+
+/// Handler for `POST /password/forgot`
+///
+/// Mails a reset link to the account with the email address, if there is one.
+/// The answer is the same either way, and comes before the mail is sent, so
+/// it doesn't tell whether an address is known.
+#[post("/password/forgot", wrap = "PASSWORD_RESET_RATE_LIMIT")]
+pub async fn forgot_password(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    form: web::Form<ForgotPasswordForm>,
+) -> HttpResponse {
+    if let Err(e) = form.validate() {
+        let data = json!({ "error": e.to_string() });
+        return render(&req, StatusCode::BAD_REQUEST, "password_forgot", &data);
+    }
+    let email = form.email.trim().to_string();
+    rt::spawn(async move {
+        let now = Utc::now().naive_utc();
+        let sent = run(&pool, move |conn| send_link(conn, &**mailer, &email, now)).await;
+        if let Err(e) = sent {
+            eprintln!("{}", e);
+        }
+    });
+    render(
+        &req,
+        StatusCode::OK,
+        "password_forgot",
+        &json!({ "sent": true }),
+    )
+}
+
+/// Handler for `GET /password/reset/{token}`
+#[get("/password/reset/{token}")]
+pub async fn reset_password_form(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let token = path.into_inner();
+    let now = Utc::now().naive_utc();
+    if run(&pool, move |conn| is_valid(conn, &token, now)).await? {
+        Ok(render(&req, StatusCode::OK, "password_reset", &json!({})))
+    } else {
+        let data = json!({ "invalid": true });
+        Ok(render(
+            &req,
+            StatusCode::BAD_REQUEST,
+            "password_reset",
+            &data,
+        ))
+    }
+}
+
+/// Handler for `POST /password/reset/{token}`
+///
+/// Sets the new password and logs the account out everywhere.
+#[post("/password/reset/{token}")]
+pub async fn reset_password_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+    form: web::Form<ResetPasswordForm>,
+) -> HttpResponse {
+    let token = path.into_inner();
+    let form = form.into_inner();
+    let now = Utc::now().naive_utc();
+    let reset = match form.validate() {
+        Ok(()) => {
+            run(&pool, move |conn| {
+                reset_password(conn, &token, &form.password, now)
+            })
+            .await
+        }
+        Err(e) => Err(e.into()),
+    };
+    let data = match &reset {
+        Ok(()) => json!({ "done": true }),
+        Err(ResetError::InvalidToken) => json!({ "invalid": true }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    let status = match &reset {
+        Ok(()) => StatusCode::OK,
+        Err(e) => actix_web::ResponseError::status_code(e),
+    };
+    render(&req, status, "password_reset", &data)
+}
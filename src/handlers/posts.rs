@@ -0,0 +1,428 @@
+//! Handlers for blog posts.
+//!
+//! HTML pages live under `/posts`, the JSON API under `/api/posts`.  Anyone may
+//! read a published post; drafts, scheduled and archived posts are only shown to
+//! their author.  Writing posts takes the `posts:write` permission, and only a
+//! post's author or an editor may change or delete it.
+
+use super::comments::{thread, Viewer};
+use super::{redirect_to, render};
+use crate::db::*;
+use crate::errors::PostError;
+use crate::extractors::{CurrentUser, OptionalUser};
+use crate::forms::PostForm;
+use crate::markdown;
+use crate::models::{NewPost, Post, PostChanges};
+use crate::roles::{CAN_WRITE_POSTS, EDIT_ANY_POST};
+use crate::sessions::CurrentSession;
+use crate::slugs::{slugify, unique_slug};
+use crate::DbPool;
+
+use actix_web::{
+    delete, error::BlockingError, get, http::StatusCode, post, put, web, HttpRequest, HttpResponse,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, MysqlConnection};
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+pub(super) async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, PostError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, PostError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| PostError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => PostError::DatabaseError(e.to_string()),
+    })
+}
+
+/// When a post saved from `form` goes live: the requested time for scheduled
+/// posts, the original (or current) time for published and archived ones.
+fn publish_time(
+    form: &PostForm,
+    previous: Option<&Post>,
+) -> Result<Option<NaiveDateTime>, PostError> {
+    let published_at = previous.and_then(|p| p.publish_at);
+    Ok(match form.status.as_str() {
+        "scheduled" => form.publish_at()?,
+        "published" => published_at
+            .filter(|_| previous.is_some_and(Post::is_public))
+            .or_else(|| Some(Utc::now().naive_utc())),
+        "archived" => published_at,
+        _ => None,
+    })
+}
+
+/// Replace the tags of post `post_id` by those named in `form`, creating new
+/// tags as needed.  Does nothing if `form` leaves the tags out.
+fn save_tags(conn: &MysqlConnection, post_id: i32, form: &PostForm) -> Result<(), PostError> {
+    if let Some(names) = form.tag_names() {
+        let ids = names
+            .iter()
+            .map(|name| get_or_create_tag(conn, name, &slugify(name)).map(|tag| tag.id))
+            .collect::<Result<Vec<_>, _>>()?;
+        set_post_tags(conn, post_id, &ids)?;
+    }
+    Ok(())
+}
+
+/// Fails with `PostError::EmailNotVerified` if saving `form` would make a
+/// post public before `current` verified their email address.
+fn ensure_may_publish(form: &PostForm, current: &CurrentSession) -> Result<(), PostError> {
+    let publishes = form.status == "published" || form.status == "scheduled";
+    if publishes && !current.email_verified {
+        return Err(PostError::EmailNotVerified);
+    }
+    Ok(())
+}
+
+/// Insert a post by `current` under a free slug derived from its title.
+fn insert_post(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    form: &PostForm,
+) -> Result<Post, PostError> {
+    form.validate()?;
+    ensure_may_publish(form, current)?;
+    conn.transaction(|| {
+        let slug = unique_slug(&slugify(&form.title), "post", |s| {
+            // `new` would be shadowed by `GET /posts/new`.
+            Ok::<_, PostError>(s == "new" || post_slug_exists(conn, s)?)
+        })?;
+        let body_html = markdown::render(&form.body);
+        let item = NewPost {
+            author_id: current.user.id,
+            title: form.title.trim(),
+            slug: &slug,
+            body: &form.body,
+            body_html: &body_html,
+            status: &form.status,
+            publish_at: publish_time(form, None)?,
+        };
+        create_post(conn, item)?;
+        let post = get_post_by_slug(conn, &slug)?;
+        save_tags(conn, post.id, form)?;
+        Ok(post)
+    })
+}
+
+/// Load post `slug` as seen by `viewer`: posts that aren't public are only
+/// visible to their author and are `PostError::NotFound` for everyone else.
+pub(super) fn get_visible_post(
+    conn: &MysqlConnection,
+    slug: &str,
+    viewer: Option<i32>,
+) -> Result<Post, PostError> {
+    let post = get_post_by_slug(conn, slug)?;
+    if !post.is_public() && viewer != Some(post.author_id) {
+        return Err(PostError::NotFound);
+    }
+    Ok(post)
+}
+
+/// Returns `true` if `current` may change `post`: they wrote it or may edit any post.
+fn may_edit(post: &Post, current: &CurrentSession) -> bool {
+    post.author_id == current.user.id || current.can(EDIT_ANY_POST)
+}
+
+/// Load post `slug`, failing with `PostError::Forbidden` unless `current` may change it.
+fn get_own_post(
+    conn: &MysqlConnection,
+    slug: &str,
+    current: &CurrentSession,
+) -> Result<Post, PostError> {
+    let post = get_post_by_slug(conn, slug)?;
+    if !may_edit(&post, current) {
+        return Err(PostError::Forbidden);
+    }
+    Ok(post)
+}
+
+/// Apply `form` to post `slug` on behalf of `current` and return the updated post.
+fn edit_post(
+    conn: &MysqlConnection,
+    slug: &str,
+    current: &CurrentSession,
+    form: &PostForm,
+) -> Result<Post, PostError> {
+    form.validate()?;
+    ensure_may_publish(form, current)?;
+    let post = get_own_post(conn, slug, current)?;
+    let body_html = markdown::render(&form.body);
+    let changes = PostChanges {
+        title: form.title.trim(),
+        body: &form.body,
+        body_html: &body_html,
+        status: &form.status,
+        publish_at: publish_time(form, Some(&post))?,
+    };
+    conn.transaction(|| {
+        update_post(conn, post.id, changes)?;
+        save_tags(conn, post.id, form)?;
+        Ok(get_post_by_id(conn, post.id)?)
+    })
+}
+
+/// Delete post `slug` on behalf of `current`.
+fn delete_post(
+    conn: &MysqlConnection,
+    slug: &str,
+    current: &CurrentSession,
+) -> Result<(), PostError> {
+    let post = get_own_post(conn, slug, current)?;
+    remove_post_by_id(conn, post.id)?;
+    Ok(())
+}
+
+/// Render the post form, showing `error` if given.
+fn post_form(
+    req: &HttpRequest,
+    status: StatusCode,
+    action: &str,
+    form: &PostForm,
+    error: Option<String>,
+) -> HttpResponse {
+    let data = json!({ "action": action, "post": form, "error": error });
+    render(req, status, "post_form", &data)
+}
+
+/// Turn a failed HTML form submission into a response: validation errors and
+/// unverified email addresses re-render the form, anything else becomes the
+/// error's own response.
+fn form_error(req: &HttpRequest, action: &str, form: &PostForm, e: PostError) -> HttpResponse {
+    match e {
+        PostError::Invalid(_) | PostError::EmailNotVerified => post_form(
+            req,
+            actix_web::ResponseError::status_code(&e),
+            action,
+            form,
+            Some(e.to_string()),
+        ),
+        e => actix_web::ResponseError::error_response(&e),
+    }
+}
+
+/// Handler for `GET /posts`
+///
+/// Lists the most recent published posts with their cached HTML bodies.
+#[get("/posts")]
+pub async fn list_posts(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let items = run(&pool, |conn| {
+        Ok(get_posts_by_status(conn, "published", 50)?)
+    })
+    .await?;
+    let posts: Vec<_> = items
+        .iter()
+        .map(|post| json!({ "post": post, "body_html": post.html() }))
+        .collect();
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "posts",
+        &json!({ "posts": posts }),
+    ))
+}
+
+/// Handler for `GET /posts/new`
+#[get("/posts/new", wrap = "CAN_WRITE_POSTS")]
+pub async fn new_post_form(
+    req: HttpRequest,
+    _current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = PostForm {
+        title: String::new(),
+        body: String::new(),
+        status: String::from("draft"),
+        publish_at: None,
+        tags: Some(String::new()),
+    };
+    Ok(post_form(&req, StatusCode::OK, "/posts", &form, None))
+}
+
+/// Handler for `POST /posts`
+///
+/// Creates a post and redirects to it.
+#[post("/posts", wrap = "CAN_WRITE_POSTS")]
+pub async fn create_post_form(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<PostForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.into_inner();
+    let item = form.clone();
+    let current = current.0;
+    match run(&pool, move |conn| insert_post(conn, &current, &item)).await {
+        Ok(post) => Ok(redirect_to(&format!("/posts/{}", post.slug))),
+        Err(e) => Ok(form_error(&req, "/posts", &form, e)),
+    }
+}
+
+/// Handler for `GET /posts/{slug}`
+///
+/// Authors may preview their posts before they are published.
+#[get("/posts/{slug}")]
+pub async fn view_post(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: OptionalUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let viewer = current.as_ref().map(|c| c.user.id);
+    let (post, author, tags, comments) = run(&pool, move |conn| {
+        let post = get_visible_post(conn, &slug, viewer)?;
+        let author = get_user_by_id(conn, post.author_id)?;
+        let tags = get_post_tags(conn, post.id)?;
+        let comments = get_comments_by_post(conn, post.id)?;
+        Ok((post, author.username, tags, comments))
+    })
+    .await?;
+
+    let is_author = viewer == Some(post.author_id);
+    let can_edit = current.as_ref().is_some_and(|c| may_edit(&post, c));
+    let data = json!({
+        "post": post,
+        "body_html": post.html(),
+        "author": author,
+        "tags": tags,
+        "is_author": is_author,
+        "can_edit": can_edit,
+        "preview": !post.is_public(),
+        "comments": thread(&comments, None, Viewer::of(current.as_ref()), post.author_id),
+        "logged_in": viewer.is_some(),
+    });
+    Ok(render(&req, StatusCode::OK, "post", &data))
+}
+
+/// Handler for `GET /posts/{slug}/edit`
+#[get("/posts/{slug}/edit", wrap = "CAN_WRITE_POSTS")]
+pub async fn edit_post_form(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    let (post, tags) = run(&pool, move |conn| {
+        let post = get_own_post(conn, &slug, &current)?;
+        let tags = get_post_tags(conn, post.id)?;
+        Ok((post, tags))
+    })
+    .await?;
+    let tags: Vec<_> = tags.into_iter().map(|tag| tag.name).collect();
+    let form = PostForm {
+        title: post.title,
+        body: post.body,
+        status: post.status,
+        publish_at: post
+            .publish_at
+            .map(|t| t.format("%Y-%m-%dT%H:%M").to_string()),
+        tags: Some(tags.join(", ")),
+    };
+    let action = format!("/posts/{}/edit", post.slug);
+    Ok(post_form(&req, StatusCode::OK, &action, &form, None))
+}
+
+/// Handler for `POST /posts/{slug}/edit`
+#[post("/posts/{slug}/edit", wrap = "CAN_WRITE_POSTS")]
+pub async fn update_post_form(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+    form: web::Form<PostForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let action = format!("/posts/{}/edit", slug);
+    let form = form.into_inner();
+    let item = form.clone();
+    let current = current.0;
+    match run(&pool, move |conn| edit_post(conn, &slug, &current, &item)).await {
+        Ok(post) => Ok(redirect_to(&format!("/posts/{}", post.slug))),
+        Err(e) => Ok(form_error(&req, &action, &form, e)),
+    }
+}
+
+/// Handler for `POST /posts/{slug}/delete`
+#[post("/posts/{slug}/delete", wrap = "CAN_WRITE_POSTS")]
+pub async fn delete_post_form(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    run(&pool, move |conn| delete_post(conn, &slug, &current)).await?;
+    Ok(redirect_to("/posts"))
+}
+
+/// Handler for `GET /api/posts/{slug}`
+///
+/// Example request:
+///     `$curl localhost/api/posts/hello-world
+///      {"id":1,"author_id":13,"title":"Hello, World","slug":"hello-world",...}`
+#[get("/api/posts/{slug}")]
+pub async fn get_post_json(
+    pool: web::Data<DbPool>,
+    current: OptionalUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let viewer = current.as_ref().map(|c| c.user.id);
+    let post = run(&pool, move |conn| get_visible_post(conn, &slug, viewer)).await?;
+    Ok(HttpResponse::Ok().json(post))
+}
+
+/// Handler for `POST /api/posts`
+#[post("/api/posts", wrap = "CAN_WRITE_POSTS")]
+pub async fn create_post_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Json<PostForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let post = run(&pool, move |conn| insert_post(conn, &current, &form)).await?;
+    Ok(HttpResponse::Created()
+        .header("Location", format!("/api/posts/{}", post.slug))
+        .json(post))
+}
+
+/// Handler for `PUT /api/posts/{slug}`
+#[put("/api/posts/{slug}", wrap = "CAN_WRITE_POSTS")]
+pub async fn update_post_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+    form: web::Json<PostForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    let post = run(&pool, move |conn| edit_post(conn, &slug, &current, &form)).await?;
+    Ok(HttpResponse::Ok().json(post))
+}
+
+/// Handler for `DELETE /api/posts/{slug}`
+#[delete("/api/posts/{slug}", wrap = "CAN_WRITE_POSTS")]
+pub async fn delete_post_json(
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let current = current.0;
+    run(&pool, move |conn| delete_post(conn, &slug, &current)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,114 @@
+//! Handlers for browsing posts by tag.
+//!
+//! Tags are created on the fly when authors tag their posts, so there are no
+//! routes to manage them directly.  Only published posts are listed or counted.
+
+use super::posts::run;
+use super::render;
+use crate::db::*;
+use crate::models::Tag;
+use crate::DbPool;
+
+use actix_web::{get, http::StatusCode, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+/// A tag with the number of published posts carrying it.
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    #[serde(flatten)]
+    pub tag: Tag,
+    pub count: i64,
+    /// Size in the tag cloud, from 1 for the least used tags to 5 for the most used.
+    pub weight: u8,
+}
+
+/// Scale `count` to a tag cloud weight between 1 and 5, relative to `max`.
+pub fn cloud_weight(count: i64, max: i64) -> u8 {
+    if max <= 1 {
+        return 1;
+    }
+    1 + ((count.max(1) - 1) * 4 / (max - 1)) as u8
+}
+
+/// Published post counts of every tag in use, with their cloud weights.
+async fn tag_counts(pool: &web::Data<DbPool>) -> Result<Vec<TagCount>, actix_web::Error> {
+    let counts = run(pool, |conn| Ok(get_tag_counts(conn, "published")?)).await?;
+    let max = counts.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    Ok(counts
+        .into_iter()
+        .map(|(tag, count)| TagCount {
+            tag,
+            count,
+            weight: cloud_weight(count, max),
+        })
+        .collect())
+}
+
+/// Handler for `GET /tags`
+///
+/// Renders the tag cloud.
+#[get("/tags")]
+pub async fn tag_cloud(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let tags = tag_counts(&pool).await?;
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "tags",
+        &json!({ "tags": tags }),
+    ))
+}
+
+/// Handler for `GET /tags/{slug}`
+///
+/// Lists the most recent published posts with the tag.
+#[get("/tags/{slug}")]
+pub async fn list_tagged_posts(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let slug = path.into_inner();
+    let (tag, items) = run(&pool, move |conn| {
+        let tag = get_tag_by_slug(conn, &slug)?;
+        let items = get_posts_by_tag(conn, tag.id, "published", 50)?;
+        Ok((tag, items))
+    })
+    .await?;
+    let posts: Vec<_> = items
+        .iter()
+        .map(|post| json!({ "post": post, "body_html": post.html() }))
+        .collect();
+    Ok(render(
+        &req,
+        StatusCode::OK,
+        "tag",
+        &json!({ "tag": tag, "posts": posts }),
+    ))
+}
+
+/// Handler for `GET /api/tags`
+///
+/// Example request:
+///     `$curl localhost/api/tags
+///      [{"id":1,"name":"Rust","slug":"rust","created_at":"...","count":3,"weight":5},...]`
+#[get("/api/tags")]
+pub async fn tag_cloud_json(pool: web::Data<DbPool>) -> Result<HttpResponse, actix_web::Error> {
+    let tags = tag_counts(&pool).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cloud_weight;
+
+    #[test]
+    fn cloud_weights_scaled() {
+        assert_eq!(cloud_weight(1, 1), 1);
+        assert_eq!(cloud_weight(1, 9), 1);
+        assert_eq!(cloud_weight(5, 9), 3);
+        assert_eq!(cloud_weight(9, 9), 5);
+    }
+}
@@ -0,0 +1,260 @@
+//! Handlers for the second login step and the two-factor settings, see
+//! `two_factor`.
+
+use super::{redirect_to, render};
+use crate::errors::TwoFactorError;
+use crate::extractors::CurrentUser;
+use crate::forms::TwoFactorCodeForm;
+use crate::ratelimit::TWO_FACTOR_RATE_LIMIT;
+use crate::sessions::{remember, ClientInfo, CurrentSession, PENDING_SESSION_KEY};
+use crate::two_factor::{
+    complete_login, confirm, disable, enroll, is_enabled, is_required, pending_enrollment,
+    regenerate_recovery_codes, Enrollment,
+};
+use crate::DbPool;
+
+use actix_session::Session;
+use actix_web::{
+    error::BlockingError, get, http::StatusCode, post, web, HttpRequest, HttpResponse,
+    ResponseError,
+};
+use chrono::Utc;
+use diesel::MysqlConnection;
+use serde_json::Value;
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+async fn run<F, T>(pool: &web::Data<DbPool>, f: F) -> Result<T, TwoFactorError>
+where
+    F: FnOnce(&MysqlConnection) -> Result<T, TwoFactorError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| TwoFactorError::DatabaseError(e.to_string()))?;
+        f(&conn)
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => TwoFactorError::DatabaseError(e.to_string()),
+    })
+}
+
+/// Handler for `GET /login/2fa`
+#[get("/login/2fa")]
+pub async fn login_code_form(req: HttpRequest, session: Session) -> HttpResponse {
+    match session.get::<String>(PENDING_SESSION_KEY) {
+        Ok(Some(_)) => render(&req, StatusCode::OK, "login_2fa", &json!({})),
+        _ => redirect_to("/login"),
+    }
+}
+
+/// Handler for `POST /login/2fa`
+///
+/// Completes the login started by `POST /login` given a code from the
+/// authenticator app or a recovery code.
+#[post("/login/2fa", wrap = "TWO_FACTOR_RATE_LIMIT")]
+pub async fn login_code_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    session: Session,
+    form: web::Form<TwoFactorCodeForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let pending_key = match session.get::<String>(PENDING_SESSION_KEY)? {
+        Some(key) => key,
+        None => return Ok(redirect_to("/login")),
+    };
+    let client = ClientInfo::from_request(&req);
+    let now = Utc::now().naive_utc();
+    let completed = run(&pool, move |conn| {
+        complete_login(conn, &pending_key, &form.code, &client, now)
+    })
+    .await;
+    match completed {
+        Ok(key) => {
+            session.remove(PENDING_SESSION_KEY);
+            remember(&session, &key)?;
+            Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(include_str!("../../templates/login_success.html")))
+        }
+        Err(e) => {
+            if let TwoFactorError::LoginExpired = e {
+                session.remove(PENDING_SESSION_KEY);
+            }
+            let data = json!({ "error": e.to_string() });
+            Ok(render(&req, e.status_code(), "login_2fa", &data))
+        }
+    }
+}
+
+/// What the two-factor settings page shows besides the current state.
+enum Outcome {
+    /// Recovery codes just issued, shown this once.
+    RecoveryCodes(Vec<String>),
+    /// Two-factor authentication was just turned off.
+    Disabled,
+    Failed(TwoFactorError),
+}
+
+/// Render the two-factor settings page of `current`, offering to confirm
+/// `pending` if set up but not confirmed yet.
+fn settings_page(
+    req: &HttpRequest,
+    current: &CurrentSession,
+    enabled: bool,
+    pending: Option<Enrollment>,
+    outcome: Option<Outcome>,
+) -> HttpResponse {
+    let mut data = json!({
+        "enabled": enabled,
+        "required": is_required(&current.roles),
+    });
+    if let Some(pending) = pending {
+        data["secret"] = Value::String(pending.secret);
+        data["uri"] = Value::String(pending.uri);
+    }
+    let status = match outcome {
+        None => StatusCode::OK,
+        Some(Outcome::RecoveryCodes(codes)) => {
+            data["recovery_codes"] = json!(codes);
+            StatusCode::OK
+        }
+        Some(Outcome::Disabled) => {
+            data["disabled"] = Value::Bool(true);
+            StatusCode::OK
+        }
+        Some(Outcome::Failed(e)) => {
+            data["error"] = Value::String(e.to_string());
+            e.status_code()
+        }
+    };
+    render(req, status, "two_factor", &data)
+}
+
+/// Render the settings page after a change to `current`'s setup ended with
+/// `outcome`, looking up the state it left behind.
+async fn settings_after(
+    req: &HttpRequest,
+    pool: &web::Data<DbPool>,
+    current: &CurrentSession,
+    outcome: Outcome,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let account = current.user.username.clone();
+    let (enabled, pending) = run(pool, move |conn| {
+        let enabled = is_enabled(conn, user_id)?;
+        Ok((enabled, pending_enrollment(conn, user_id, &account)?))
+    })
+    .await?;
+    Ok(settings_page(req, current, enabled, pending, Some(outcome)))
+}
+
+/// Handler for `GET /account/2fa`
+#[get("/account/2fa")]
+pub async fn settings(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let account = current.user.username.clone();
+    let pending = run(&pool, move |conn| {
+        pending_enrollment(conn, user_id, &account)
+    })
+    .await?;
+    Ok(settings_page(
+        &req,
+        &current,
+        current.two_factor,
+        pending,
+        None,
+    ))
+}
+
+/// Handler for `POST /account/2fa/enroll`
+///
+/// Starts over setting up two-factor authentication with a new secret.
+#[post("/account/2fa/enroll", wrap = "TWO_FACTOR_RATE_LIMIT")]
+pub async fn enroll_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = current.user.id;
+    let account = current.user.username.clone();
+    match run(&pool, move |conn| enroll(conn, user_id, &account)).await {
+        Ok(enrollment) => Ok(settings_page(&req, &current, false, Some(enrollment), None)),
+        Err(e) => settings_after(&req, &pool, &current, Outcome::Failed(e)).await,
+    }
+}
+
+/// Handler for `POST /account/2fa/confirm`
+///
+/// Turns two-factor authentication on given a code from the app, and shows
+/// the recovery codes.
+#[post("/account/2fa/confirm", wrap = "TWO_FACTOR_RATE_LIMIT")]
+pub async fn confirm_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<TwoFactorCodeForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let session = current.clone();
+    let now = Utc::now().naive_utc();
+    let confirmed = run(&pool, move |conn| confirm(conn, &session, &form.code, now)).await;
+    let outcome = match confirmed {
+        Ok(codes) => Outcome::RecoveryCodes(codes),
+        Err(e) => Outcome::Failed(e),
+    };
+    settings_after(&req, &pool, &current, outcome).await
+}
+
+/// Handler for `POST /account/2fa/recovery-codes`
+///
+/// Replaces the recovery codes, given a valid code.
+#[post("/account/2fa/recovery-codes", wrap = "TWO_FACTOR_RATE_LIMIT")]
+pub async fn recovery_codes_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<TwoFactorCodeForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let session = current.clone();
+    let now = Utc::now().naive_utc();
+    let issued = run(&pool, move |conn| {
+        regenerate_recovery_codes(conn, &session, &form.code, now)
+    })
+    .await;
+    let outcome = match issued {
+        Ok(codes) => Outcome::RecoveryCodes(codes),
+        Err(e) => Outcome::Failed(e),
+    };
+    settings_after(&req, &pool, &current, outcome).await
+}
+
+/// Handler for `POST /account/2fa/disable`
+///
+/// Turns two-factor authentication off, given a valid code, unless the
+/// account's roles require it.
+#[post("/account/2fa/disable", wrap = "TWO_FACTOR_RATE_LIMIT")]
+pub async fn disable_submit(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    current: CurrentUser,
+    form: web::Form<TwoFactorCodeForm>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let current = current.0;
+    let session = current.clone();
+    let now = Utc::now().naive_utc();
+    let disabled = run(&pool, move |conn| disable(conn, &session, &form.code, now)).await;
+    let outcome = match disabled {
+        Ok(()) => Outcome::Disabled,
+        Err(e) => Outcome::Failed(e),
+    };
+    settings_after(&req, &pool, &current, outcome).await
+}
@@ -0,0 +1,225 @@
+//! Password hashing backends.
+//!
+//! Every stored hash describes how it was made: Argon2id hashes are PHC strings
+//! (`$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`) and bcrypt hashes keep their
+//! modular crypt form (`$2y$12$<salt+hash>`), which already carries the algorithm
+//! and cost.  This lets `verify` check a password against any supported hash and
+//! `needs_rehash` spot hashes made with an outdated algorithm or cost.
+//!
+//! The backend used for new hashes is picked from the environment:
+//!     PASSWORD_HASHER=argon2id   # or `bcrypt` (default)
+//!     BCRYPT_COST=12
+//!     ARGON2_M_COST=19456        # memory in KiB
+//!     ARGON2_T_COST=2            # iterations
+//!     ARGON2_P_COST=1            # parallelism
+
+use super::errors::AuthError;
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::rngs::OsRng;
+use std::convert::TryFrom;
+use std::env;
+
+lazy_static! {
+    static ref CONFIGURED: Box<dyn PasswordHasher> = from_env();
+}
+
+pub trait PasswordHasher: Send + Sync {
+    /// Hash `password` into a self-describing string for `users.password`.
+    fn hash(&self, password: &str) -> Result<String, AuthError>;
+
+    /// Returns `true` if `stored` was not made with this backend's current parameters.
+    fn needs_rehash(&self, stored: &str) -> bool;
+}
+
+/// bcrypt with a configurable cost factor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bcrypt {
+    pub cost: u32,
+}
+
+impl Default for Bcrypt {
+    fn default() -> Self {
+        Bcrypt {
+            cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+impl PasswordHasher for Bcrypt {
+    fn hash(&self, password: &str) -> Result<String, AuthError> {
+        bcrypt::hash(password, self.cost).map_err(|_| AuthError::HashingFailed)
+    }
+
+    fn needs_rehash(&self, stored: &str) -> bool {
+        bcrypt_cost(stored) != Some(self.cost)
+    }
+}
+
+/// Argon2id with configurable memory, iteration and parallelism costs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Argon2id {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for Argon2id {
+    fn default() -> Self {
+        Argon2id {
+            m_cost: 19456,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+impl Argon2id {
+    fn params(&self) -> Result<Params, AuthError> {
+        Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|_| AuthError::HashingFailed)
+    }
+}
+
+impl PasswordHasher for Argon2id {
+    fn hash(&self, password: &str) -> Result<String, AuthError> {
+        use argon2::PasswordHasher as _;
+
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?)
+            .hash_password(password.as_bytes(), &salt)
+            .map(|h| h.to_string())
+            .map_err(|_| AuthError::HashingFailed)
+    }
+
+    fn needs_rehash(&self, stored: &str) -> bool {
+        let parsed = match PasswordHash::new(stored) {
+            Ok(parsed) => parsed,
+            Err(_) => return true,
+        };
+        if parsed.algorithm != Algorithm::Argon2id.ident()
+            || parsed.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+        match Params::try_from(&parsed) {
+            Ok(p) => {
+                (p.m_cost(), p.t_cost(), p.p_cost()) != (self.m_cost, self.t_cost, self.p_cost)
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+/// Returns the hasher configured for new passwords.
+pub fn configured() -> &'static dyn PasswordHasher {
+    CONFIGURED.as_ref()
+}
+
+/// Build a hasher from `PASSWORD_HASHER` and its cost variables, falling back to
+/// the defaults for anything unset or unparsable.
+pub fn from_env() -> Box<dyn PasswordHasher> {
+    fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+        env::var(key).ok().and_then(|v| v.parse().ok())
+    }
+
+    match env::var("PASSWORD_HASHER").as_deref() {
+        Ok("argon2id") | Ok("argon2") => {
+            let d = Argon2id::default();
+            Box::new(Argon2id {
+                m_cost: var("ARGON2_M_COST").unwrap_or(d.m_cost),
+                t_cost: var("ARGON2_T_COST").unwrap_or(d.t_cost),
+                p_cost: var("ARGON2_P_COST").unwrap_or(d.p_cost),
+            })
+        }
+        _ => Box::new(Bcrypt {
+            cost: var("BCRYPT_COST").unwrap_or(bcrypt::DEFAULT_COST),
+        }),
+    }
+}
+
+/// Returns `true` if `stored` is a hash made by one of the supported backends
+/// rather than a legacy plaintext password.
+pub fn is_password_hash(stored: &str) -> bool {
+    bcrypt_cost(stored).is_some() || PasswordHash::new(stored).is_ok()
+}
+
+/// Check `password` against `stored`, whichever backend produced it.
+///
+/// Example:
+///     let hashed = Argon2id::default().hash("password123").unwrap();
+///     assert!(verify("password123", &hashed));
+pub fn verify(password: &str, stored: &str) -> bool {
+    if bcrypt_cost(stored).is_some() {
+        return bcrypt::verify(password, stored).unwrap_or(false);
+    }
+    match PasswordHash::new(stored) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Cost factor of a bcrypt hash, or `None` if `stored` isn't one.
+fn bcrypt_cost(stored: &str) -> Option<u32> {
+    let parts: Vec<&str> = stored.split('$').collect();
+    match parts.as_slice() {
+        ["", "2a", cost, rest] | ["", "2b", cost, rest] | ["", "2y", cost, rest]
+            if rest.len() == 53 =>
+        {
+            cost.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcrypt_hash_verified() {
+        let hasher = Bcrypt { cost: 4 };
+        let hashed = hasher.hash("password123").unwrap();
+        assert!(is_password_hash(&hashed));
+        assert!(verify("password123", &hashed));
+        assert!(!verify("password124", &hashed));
+        assert!(!hasher.needs_rehash(&hashed));
+        assert!(Bcrypt { cost: 5 }.needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn argon2id_hash_verified() {
+        let hasher = Argon2id {
+            m_cost: 1024,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let hashed = hasher.hash("password123").unwrap();
+        assert!(hashed.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(verify("password123", &hashed));
+        assert!(!verify("password124", &hashed));
+        assert!(!hasher.needs_rehash(&hashed));
+        assert!(Argon2id {
+            t_cost: 2,
+            ..hasher
+        }
+        .needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn algorithm_change_needs_rehash() {
+        let hashed = Bcrypt { cost: 4 }.hash("password123").unwrap();
+        assert!(Argon2id::default().needs_rehash(&hashed));
+        assert!(verify("password123", &hashed));
+    }
+
+    #[test]
+    fn plaintext_is_not_a_hash() {
+        assert!(!is_password_hash("password123"));
+        assert!(!verify("password123", "password123"));
+        assert!(Bcrypt::default().needs_rehash("password123"));
+    }
+}
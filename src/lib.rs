@@ -7,14 +7,36 @@ extern crate diesel;
 #[macro_use]
 extern crate failure;
 
+#[macro_use]
+extern crate lazy_static;
+
+pub mod account;
 pub mod auth;
+pub mod config;
+pub mod csrf;
 pub mod db;
+pub mod email_verification;
 pub mod errors;
+pub mod extractors;
 pub mod forms;
 pub mod handlers;
+pub mod hashers;
+pub mod lockout;
+pub mod mailer;
+pub mod markdown;
 pub mod models;
+pub mod passkeys;
+pub mod password_reset;
+pub mod publisher;
+pub mod ratelimit;
+pub mod roles;
 pub mod schema;
+pub mod sessions;
+pub mod slugs;
+pub mod totp;
+pub mod two_factor;
 pub mod users;
+pub mod webauthn;
 
 use diesel::mysql::MysqlConnection;
 use diesel::prelude::*;
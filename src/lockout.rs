@@ -0,0 +1,199 @@
+//! Lockout after repeated failed logins.
+//!
+//! Wrong passwords are counted per username and per client IP address in the
+//! `login_failures` table.  Once either count reaches `LOGIN_MAX_FAILURES`
+//! (default: 5) logins for that username or from that address are refused for
+//! `LOGIN_LOCKOUT_SECS` seconds (default: one minute), doubling with every
+//! further failure up to `LOGIN_LOCKOUT_MAX_SECS` (default: one day).  Failures
+//! older than a day are forgotten, and a successful login clears both counts.
+//!
+//! `POST /login` goes through `authenticate` instead of `Auth::authenticate`:
+//!     lockout::authenticate(&conn, &form, client.ip_address.as_deref())
+
+use super::auth::Auth;
+use super::db::{
+    clear_login_failures, get_login_lock, get_user_by_login, lock_login, record_login_failure,
+};
+use super::errors::AuthError;
+use super::users::BaseUser;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{Connection, MysqlConnection};
+use std::env;
+
+/// `login_failures.scope` of counts kept per username.
+pub const USERNAME_SCOPE: &str = "user";
+/// `login_failures.scope` of counts kept per client IP address.
+pub const IP_SCOPE: &str = "ip";
+
+/// When and for how long repeated failures lock logins out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockoutPolicy {
+    /// Failures allowed before the first lock.
+    pub max_failures: i32,
+    /// Length of the first lock.
+    pub base: Duration,
+    /// Longest lock.
+    pub max: Duration,
+    /// How long a failure is remembered.
+    pub window: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures: 5,
+            base: Duration::minutes(1),
+            max: Duration::days(1),
+            window: Duration::days(1),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// The default policy with limits overridden by `LOGIN_MAX_FAILURES`,
+    /// `LOGIN_LOCKOUT_SECS` and `LOGIN_LOCKOUT_MAX_SECS`.
+    pub fn from_env() -> Self {
+        let default = LockoutPolicy::default();
+        let secs = |key: &str| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|secs| *secs > 0)
+                .map(Duration::seconds)
+        };
+        LockoutPolicy {
+            max_failures: env::var("LOGIN_MAX_FAILURES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n| *n > 0)
+                .unwrap_or(default.max_failures),
+            base: secs("LOGIN_LOCKOUT_SECS").unwrap_or(default.base),
+            max: secs("LOGIN_LOCKOUT_MAX_SECS").unwrap_or(default.max),
+            ..default
+        }
+    }
+
+    /// How long to lock out after `failures` failed logins, if at all.
+    ///
+    /// Example, with the default policy:
+    ///     assert_eq!(policy.lock_duration(4), None);
+    ///     assert_eq!(policy.lock_duration(5), Some(Duration::minutes(1)));
+    ///     assert_eq!(policy.lock_duration(7), Some(Duration::minutes(4)));
+    pub fn lock_duration(&self, failures: i32) -> Option<Duration> {
+        if failures < self.max_failures {
+            return None;
+        }
+        // Capping the exponent keeps the multiplication from overflowing.
+        let doublings = (failures - self.max_failures).min(30) as u32;
+        let secs = self.base.num_seconds().saturating_mul(1 << doublings);
+        Some(Duration::seconds(secs).min(self.max))
+    }
+}
+
+/// The scopes and subjects `username` logging in from `ip` is counted under.
+fn subjects<'a>(username: &'a str, ip: Option<&'a str>) -> Vec<(&'static str, &'a str)> {
+    let mut subjects = vec![(USERNAME_SCOPE, username)];
+    if let Some(ip) = ip {
+        subjects.push((IP_SCOPE, ip));
+    }
+    subjects
+}
+
+/// Fails with `TooManyAttempts` if `username` or `ip` is locked out at `now`.
+pub fn check(
+    conn: &MysqlConnection,
+    username: &str,
+    ip: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<(), AuthError> {
+    let mut until = None;
+    for (scope, subject) in subjects(username, ip) {
+        until = until.max(get_login_lock(conn, scope, subject, now)?);
+    }
+    match until {
+        Some(until) => Err(AuthError::TooManyAttempts { until }),
+        None => Ok(()),
+    }
+}
+
+/// Count a wrong password for `username` from `ip`, locking either out once
+/// `policy` says so.
+pub fn record_failure(
+    conn: &MysqlConnection,
+    policy: &LockoutPolicy,
+    username: &str,
+    ip: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<(), AuthError> {
+    conn.transaction(|| {
+        for (scope, subject) in subjects(username, ip) {
+            let failures = record_login_failure(conn, scope, subject, now, now - policy.window)?;
+            if let Some(duration) = policy.lock_duration(failures) {
+                lock_login(conn, scope, subject, now + duration)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Forget the failed logins of `username` and `ip`.
+pub fn reset(conn: &MysqlConnection, username: &str, ip: Option<&str>) -> Result<(), AuthError> {
+    for (scope, subject) in subjects(username, ip) {
+        clear_login_failures(conn, scope, subject)?;
+    }
+    Ok(())
+}
+
+/// `Auth::authenticate` guarded by the lockout: refuses locked out usernames
+/// and addresses, counts wrong passwords and clears the counts on success.
+/// Logins by email address count against the username of the account.
+pub fn authenticate<A: Auth>(
+    conn: &MysqlConnection,
+    credentials: &A,
+    ip: Option<&str>,
+) -> Result<BaseUser, AuthError> {
+    let username = get_user_by_login(conn, credentials.get_username())
+        .map(|usr| usr.username)
+        .unwrap_or_else(|_| credentials.get_username().to_owned());
+    let username = username.as_str();
+    let now = Utc::now().naive_utc();
+    check(conn, username, ip, now)?;
+    match credentials.authenticate(conn) {
+        Ok(usr) => {
+            reset(conn, username, ip)?;
+            Ok(usr)
+        }
+        Err(AuthError::InvalidPassword) => {
+            record_failure(conn, &LockoutPolicy::from_env(), username, ip, now)?;
+            Err(AuthError::InvalidPassword)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockouts_double_up_to_max() {
+        let policy = LockoutPolicy::default();
+        assert_eq!(policy.lock_duration(1), None);
+        assert_eq!(policy.lock_duration(4), None);
+        assert_eq!(policy.lock_duration(5), Some(Duration::minutes(1)));
+        assert_eq!(policy.lock_duration(6), Some(Duration::minutes(2)));
+        assert_eq!(policy.lock_duration(7), Some(Duration::minutes(4)));
+        assert_eq!(policy.lock_duration(20), Some(Duration::days(1)));
+        assert_eq!(policy.lock_duration(i32::MAX), Some(Duration::days(1)));
+    }
+
+    #[test]
+    fn failures_counted_per_username_and_ip() {
+        assert_eq!(subjects("bender", None), vec![(USERNAME_SCOPE, "bender")]);
+        assert_eq!(
+            subjects("bender", Some("192.0.2.1")),
+            vec![(USERNAME_SCOPE, "bender"), (IP_SCOPE, "192.0.2.1")]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! Outgoing mail.
+//!
+//! Handlers send mail through the `Mailer` registered as app data, picked by
+//! `MAILER`:
+//! - `smtp` relays through `SMTP_HOST` on `SMTP_PORT` (default: 587), upgrading
+//!   the connection with STARTTLS unless `SMTP_STARTTLS=false`, and logging in
+//!   as `SMTP_USERNAME` with `SMTP_PASSWORD` if set.
+//! - `file`, the default, writes every mail to a file in `MAIL_DIR` (default:
+//!   `mail`), so links can be followed in development without a mail server.
+//! - `memory` keeps them in memory, for tests.
+//!
+//! Mail is sent from `MAIL_FROM` (default: `noreply@localhost`):
+//!     App::new().app_data(mailer::mailer_from_env()?)
+
+use super::errors::ConfigError;
+use super::sessions::generate_session_key;
+
+use actix_web::web;
+use chrono::Utc;
+use native_tls::TlsConnector;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A plain text mail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    /// The mail as an RFC 5322 message from `from`, with CRLF line endings.
+    /// Fails if a header would contain a line break.
+    pub fn message(&self, from: &str) -> Result<String, String> {
+        let headers = [("From", from), ("To", &self.to), ("Subject", &self.subject)];
+        if headers.iter().any(|(_, v)| v.contains(&['\r', '\n'][..])) {
+            return Err(String::from("Mail headers must not contain line breaks."));
+        }
+        let mut message = String::new();
+        for (name, value) in headers.iter() {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        message.push_str(&format!("Date: {}\r\n", Utc::now().to_rfc2822()));
+        message.push_str("MIME-Version: 1.0\r\n");
+        message.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        for line in self.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        Ok(message)
+    }
+}
+
+/// Sends mail.  Implementations block, so call them on the blocking pool.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), String>;
+}
+
+/// Keeps sent mail in memory, for tests.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl MemoryMailer {
+    /// The mail sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, mail: &Mail) -> Result<(), String> {
+        self.sent.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
+
+/// Writes every mail to a new `.eml` file in `dir`.
+pub struct FileMailer {
+    pub dir: PathBuf,
+    pub from: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), String> {
+        let message = mail.message(&self.from)?;
+        fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            &generate_session_key()[..8]
+        );
+        fs::write(self.dir.join(name), message).map_err(|e| e.to_string())
+    }
+}
+
+/// Relays mail to an SMTP server.
+pub struct SmtpMailer {
+    pub host: String,
+    pub port: u16,
+    /// Upgrade the connection with STARTTLS before sending anything else.
+    pub starttls: bool,
+    /// Username and password to log in with, only ever sent over TLS.
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+}
+
+/// The client side of an SMTP conversation over `S`.
+struct Smtp<S: Read + Write>(BufReader<S>);
+
+impl<S: Read + Write> Smtp<S> {
+    /// Read a reply, which may span several lines, and fail unless its code
+    /// is `expected`.
+    fn reply(&mut self, expected: u16) -> Result<(), String> {
+        loop {
+            let mut line = String::new();
+            if self.0.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Err(String::from("SMTP server closed the connection."));
+            }
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                return match line.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+                    Some(code) if code == expected => Ok(()),
+                    _ => Err(format!("Unexpected SMTP reply: {}", line.trim_end())),
+                };
+            }
+        }
+    }
+
+    /// Send `line` and wait for reply `expected`.
+    fn command(&mut self, line: &str, expected: u16) -> Result<(), String> {
+        self.write(&format!("{}\r\n", line))?;
+        self.reply(expected)
+    }
+
+    fn write(&mut self, data: &str) -> Result<(), String> {
+        let stream = self.0.get_mut();
+        stream
+            .write_all(data.as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl SmtpMailer {
+    /// Log in if configured and hand over `message` for `to`.
+    fn deliver<S: Read + Write>(
+        &self,
+        smtp: &mut Smtp<S>,
+        secure: bool,
+        to: &str,
+        message: &str,
+    ) -> Result<(), String> {
+        if let Some((username, password)) = &self.credentials {
+            if !secure {
+                return Err(String::from(
+                    "Refusing to send SMTP credentials without STARTTLS.",
+                ));
+            }
+            let plain = base64::encode(format!("\0{}\0{}", username, password));
+            smtp.command(&format!("AUTH PLAIN {}", plain), 235)?;
+        }
+        smtp.command(&format!("MAIL FROM:<{}>", self.from), 250)?;
+        smtp.command(&format!("RCPT TO:<{}>", to), 250)?;
+        smtp.command("DATA", 354)?;
+        // Lines starting with a dot are escaped with another one.
+        let mut data = String::new();
+        for line in message.split_terminator("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        smtp.write(&data)?;
+        smtp.reply(250)?;
+        smtp.command("QUIT", 221)
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), String> {
+        let message = mail.message(&self.from)?;
+        let stream =
+            TcpStream::connect((self.host.as_str(), self.port)).map_err(|e| e.to_string())?;
+        let timeout = Some(Duration::from_secs(30));
+        stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(|e| e.to_string())?;
+
+        let mut smtp = Smtp(BufReader::new(stream));
+        smtp.reply(220)?;
+        smtp.command("EHLO localhost", 250)?;
+        if !self.starttls {
+            return self.deliver(&mut smtp, false, &mail.to, &message);
+        }
+
+        smtp.command("STARTTLS", 220)?;
+        // Anything the server sent before the handshake is dropped with the
+        // buffer, so it cannot pose as a reply over TLS.
+        let stream = smtp.0.into_inner();
+        let tls = TlsConnector::new()
+            .map_err(|e| e.to_string())?
+            .connect(&self.host, stream)
+            .map_err(|e| e.to_string())?;
+        let mut smtp = Smtp(BufReader::new(tls));
+        smtp.command("EHLO localhost", 250)?;
+        self.deliver(&mut smtp, true, &mail.to, &message)
+    }
+}
+
+/// The mailer picked by `MAILER` (`smtp`, `file` or `memory`), as app data.
+pub fn mailer_from_env() -> Result<web::Data<dyn Mailer>, ConfigError> {
+    let from = env::var("MAIL_FROM").unwrap_or_else(|_| String::from("noreply@localhost"));
+    let mailer: Arc<dyn Mailer> = match env::var("MAILER").as_deref() {
+        Ok("smtp") => {
+            let host = env::var("SMTP_HOST").map_err(|_| ConfigError::Missing("SMTP_HOST"))?;
+            let port = match env::var("SMTP_PORT") {
+                Ok(port) => port
+                    .parse()
+                    .map_err(|_| ConfigError::invalid("SMTP_PORT", &port))?,
+                Err(_) => 587,
+            };
+            let credentials = env::var("SMTP_USERNAME")
+                .ok()
+                .map(|username| (username, env::var("SMTP_PASSWORD").unwrap_or_default()));
+            Arc::new(SmtpMailer {
+                host,
+                port,
+                starttls: env::var("SMTP_STARTTLS").map_or(true, |v| v != "false" && v != "0"),
+                credentials,
+                from,
+            })
+        }
+        Ok("memory") => Arc::new(MemoryMailer::default()),
+        _ => Arc::new(FileMailer {
+            dir: PathBuf::from(env::var("MAIL_DIR").unwrap_or_else(|_| String::from("mail"))),
+            from,
+        }),
+    };
+    Ok(web::Data::from(mailer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn mail(body: &str) -> Mail {
+        Mail {
+            to: String::from("bender@example.com"),
+            subject: String::from("Hello"),
+            body: String::from(body),
+        }
+    }
+
+    #[test]
+    fn header_injection_refused() {
+        let message = mail("Hi\nthere").message("noreply@localhost").unwrap();
+        assert!(message.starts_with("From: noreply@localhost\r\nTo: bender@example.com\r\n"));
+        assert!(message.ends_with("\r\n\r\nHi\r\nthere\r\n"));
+
+        let injected = Mail {
+            subject: String::from("Hello\r\nBcc: everyone@example.com"),
+            ..mail("Hi")
+        };
+        assert!(injected.message("noreply@localhost").is_err());
+    }
+
+    #[test]
+    fn mail_relayed_over_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = Vec::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = match line.as_str() {
+                    "EHLO localhost" => b"250-localhost\r\n250 8BITMIME\r\n",
+                    "DATA" => b"354 Go ahead\r\n",
+                    "." => b"250 Queued\r\n",
+                    "QUIT" => b"221 Bye\r\n",
+                    l if l.starts_with("MAIL") || l.starts_with("RCPT") => b"250 OK\r\n",
+                    _ => b"",
+                };
+                received.push(line.clone());
+                writer.write_all(reply).unwrap();
+                if line == "QUIT" {
+                    return received;
+                }
+            }
+        });
+
+        let mailer = SmtpMailer {
+            host: String::from("127.0.0.1"),
+            port,
+            starttls: false,
+            credentials: None,
+            from: String::from("noreply@localhost"),
+        };
+        mailer.send(&mail("Hi\n.hidden")).unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received[1], "MAIL FROM:<noreply@localhost>");
+        assert_eq!(received[2], "RCPT TO:<bender@example.com>");
+        assert!(received.contains(&String::from("..hidden")));
+
+        let with_credentials = SmtpMailer {
+            credentials: Some((String::from("bender"), String::from("secret"))),
+            ..mailer
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            reader.read_line(&mut String::new()).unwrap();
+            stream.write_all(b"250 localhost\r\n").unwrap();
+            reader.read_to_end(&mut Vec::new()).unwrap();
+        });
+        let err = SmtpMailer {
+            port,
+            ..with_credentials
+        }
+        .send(&mail("Hi"))
+        .unwrap_err();
+        assert!(err.contains("without STARTTLS"));
+        server.join().unwrap();
+    }
+}
@@ -1,37 +1,35 @@
 #[macro_use]
 extern crate serde_json;
 
-use actix_session::{CookieSession, Session};
-use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{get, http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+use blog_user::config::{Environment, SessionConfig};
+use blog_user::csrf::CsrfProtect;
+use blog_user::extractors::OptionalUser;
 use blog_user::handlers;
-use blog_user::users::BaseUser;
+use blog_user::mailer;
+use blog_user::publisher;
+use blog_user::ratelimit;
+use blog_user::sessions::SessionAuth;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 use dotenv::dotenv;
 use handlebars::Handlebars;
 
 use std::env;
+use std::io;
 
 pub type DbPool = r2d2::Pool<ConnectionManager<MysqlConnection>>;
 
 /// Handler for index page
 #[get("/")]
-pub async fn index(
-    hb: web::Data<Handlebars<'_>>,
-    request: HttpRequest,
-    session: Session,
-) -> Result<HttpResponse, actix_web::Error> {
-    let cookie = request.headers().get("cookie").unwrap();
-    let user = session.get::<BaseUser>("user").unwrap();
+pub async fn index(request: HttpRequest, current: OptionalUser) -> HttpResponse {
+    let cookie = request.headers().get("cookie");
+    let user = current.0.map(|current| current.user);
     let data = json!({
-        "session_id": session.get::<u32>("session-id").unwrap(),
         "cookie": format!("{:?}", &cookie),
         "user": user
     });
-    let body = hb.render("index", &data).unwrap();
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(&body))
+    handlers::render(&request, StatusCode::OK, "index", &data)
 }
 
 #[actix_web::main]
@@ -45,6 +43,20 @@ async fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create pool.");
 
+    // Refuse to start with a weak session key in production
+    let session_config = SessionConfig::from_env(Environment::from_env())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    // Publish scheduled posts in the background
+    publisher::start(pool.clone());
+
+    // Buckets of the rate limited routes, shared by all workers
+    let rate_limits = ratelimit::store_from_env(pool.clone());
+
+    // Outgoing mail, e.g. password reset and email verification links
+    let mailer = mailer::mailer_from_env()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
     // For template rendering
     let mut handlebars = Handlebars::new();
     handlebars
@@ -56,6 +68,61 @@ async fn main() -> std::io::Result<()> {
     handlebars
         .register_template_string("signup", include_str!("../templates/signup.html"))
         .unwrap();
+    handlebars
+        .register_template_string("posts", include_str!("../templates/posts.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("post", include_str!("../templates/post.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("post_form", include_str!("../templates/post_form.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("tags", include_str!("../templates/tags.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("tag", include_str!("../templates/tag.html"))
+        .unwrap();
+    handlebars
+        .register_template_string(
+            "comments_pending",
+            include_str!("../templates/comments_pending.html"),
+        )
+        .unwrap();
+
+    handlebars
+        .register_template_string("admin_users", include_str!("../templates/admin_users.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("admin_user", include_str!("../templates/admin_user.html"))
+        .unwrap();
+    handlebars
+        .register_template_string(
+            "password_forgot",
+            include_str!("../templates/password_forgot.html"),
+        )
+        .unwrap();
+    handlebars
+        .register_template_string(
+            "password_reset",
+            include_str!("../templates/password_reset.html"),
+        )
+        .unwrap();
+    handlebars
+        .register_template_string(
+            "email_verify",
+            include_str!("../templates/email_verify.html"),
+        )
+        .unwrap();
+    handlebars
+        .register_template_string("login_2fa", include_str!("../templates/login_2fa.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("two_factor", include_str!("../templates/two_factor.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("passkeys", include_str!("../templates/passkeys.html"))
+        .unwrap();
 
     let handlebars_ref = web::Data::new(handlebars);
 
@@ -64,15 +131,80 @@ async fn main() -> std::io::Result<()> {
     println!("Serving at {}", &address);
     HttpServer::new(move || {
         App::new()
-            .wrap(CookieSession::signed(&[0; 32]).secure(false))
+            .wrap(CsrfProtect)
+            .wrap(SessionAuth)
+            .wrap(session_config.cookie_session())
+            .wrap(session_config.key_rotation())
             .app_data(handlebars_ref.clone())
+            .app_data(rate_limits.clone())
+            .app_data(mailer.clone())
             .data(pool.clone())
             .service(index)
             .service(handlers::signup)
             .service(handlers::signup_form)
+            .service(handlers::list_users)
             .service(handlers::retrieve_user_by_id)
             .service(handlers::login)
             .service(handlers::login_form)
+            .service(handlers::logout)
+            .service(handlers::logout_all)
+            .service(handlers::list_sessions)
+            .service(handlers::revoke_session)
+            .service(handlers::password::forgot_password_form)
+            .service(handlers::password::forgot_password)
+            .service(handlers::password::reset_password_form)
+            .service(handlers::password::reset_password_submit)
+            .service(handlers::account::account_form)
+            .service(handlers::account::change_password_submit)
+            .service(handlers::account::change_username_submit)
+            .service(handlers::two_factor::login_code_form)
+            .service(handlers::two_factor::login_code_submit)
+            .service(handlers::two_factor::settings)
+            .service(handlers::two_factor::enroll_submit)
+            .service(handlers::two_factor::confirm_submit)
+            .service(handlers::two_factor::recovery_codes_submit)
+            .service(handlers::two_factor::disable_submit)
+            .service(handlers::passkeys::passkeys_page)
+            .service(handlers::passkeys::registration_options)
+            .service(handlers::passkeys::register_passkey)
+            .service(handlers::passkeys::delete_passkey)
+            .service(handlers::passkeys::login_options)
+            .service(handlers::passkeys::login_with_passkey)
+            .service(handlers::email::verification_status)
+            .service(handlers::email::resend_verification)
+            .service(handlers::email::verify_email)
+            .service(handlers::posts::list_posts)
+            .service(handlers::posts::new_post_form)
+            .service(handlers::posts::create_post_form)
+            .service(handlers::posts::view_post)
+            .service(handlers::posts::edit_post_form)
+            .service(handlers::posts::update_post_form)
+            .service(handlers::posts::delete_post_form)
+            .service(handlers::posts::get_post_json)
+            .service(handlers::posts::create_post_json)
+            .service(handlers::posts::update_post_json)
+            .service(handlers::posts::delete_post_json)
+            .service(handlers::comments::create_comment_form)
+            .service(handlers::comments::approve_comment_form)
+            .service(handlers::comments::delete_comment_form)
+            .service(handlers::comments::pending_comments_page)
+            .service(handlers::comments::list_comments_json)
+            .service(handlers::comments::create_comment_json)
+            .service(handlers::comments::update_comment_json)
+            .service(handlers::comments::delete_comment_json)
+            .service(handlers::comments::approve_comment_json)
+            .service(handlers::comments::pending_comments_json)
+            .service(handlers::tags::tag_cloud)
+            .service(handlers::tags::list_tagged_posts)
+            .service(handlers::tags::tag_cloud_json)
+            .service(handlers::admin::dashboard)
+            .service(handlers::admin::list_users_page)
+            .service(handlers::admin::view_user_page)
+            .service(handlers::admin::change_role_form)
+            .service(handlers::admin::disable_user_form)
+            .service(handlers::admin::lock_user_form)
+            .service(handlers::admin::enable_user_form)
+            .service(handlers::admin::delete_user_form)
     })
     .bind(&address)?
     .run()
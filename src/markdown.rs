@@ -0,0 +1,90 @@
+//! Markdown rendering for post bodies.
+//!
+//! Bodies are parsed as CommonMark with tables, footnotes and strikethrough,
+//! rendered to HTML and sanitized with ammonia before they reach a template.
+//! Footnote ids get a `user-content-` prefix so they can't clobber ids of the
+//! page around them.
+
+use ammonia::Builder;
+use pulldown_cmark::{html, Options, Parser};
+use std::borrow::Cow;
+
+const ID_PREFIX: &str = "user-content-";
+
+lazy_static! {
+    static ref SANITIZER: Builder<'static> = {
+        let mut builder = Builder::default();
+        builder
+            .add_tag_attributes("code", &["class"])
+            .add_tag_attributes("div", &["id"])
+            .add_allowed_classes("div", &["footnote-definition"])
+            .add_allowed_classes("sup", &["footnote-reference", "footnote-definition-label"])
+            .id_prefix(Some(ID_PREFIX))
+            .attribute_filter(|element, attribute, value| match (element, attribute) {
+                ("code", "class") => {
+                    let lang = value.strip_prefix("language-")?;
+                    if lang
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-_#".contains(c))
+                    {
+                        Some(value.into())
+                    } else {
+                        None
+                    }
+                }
+                ("a", "href") if value.starts_with('#') => {
+                    Some(Cow::Owned(format!("#{}{}", ID_PREFIX, &value[1..])))
+                }
+                _ => Some(value.into()),
+            });
+        builder
+    };
+}
+
+/// Render `markdown` to sanitized HTML, safe to insert into a template unescaped.
+///
+/// Example:
+///     assert_eq!(render("*hi*"), "<p><em>hi</em></p>\n");
+pub fn render(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let mut unsafe_html = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    SANITIZER.clean(&unsafe_html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn markdown_rendered() {
+        assert_eq!(render("*hi*"), "<p><em>hi</em></p>\n");
+        assert!(render("| a | b |\n|---|---|\n| 1 | 2 |").contains("<td>1</td>"));
+        assert!(render("```rust\nfn main() {}\n```")
+            .contains("<code class=\"language-rust\">fn main() {}"));
+    }
+
+    #[test]
+    fn footnotes_linked() {
+        let html = render("Text[^1]\n\n[^1]: Note");
+        assert!(html.contains("href=\"#user-content-1\""));
+        assert!(html.contains("id=\"user-content-1\""));
+    }
+
+    #[test]
+    fn script_and_handlers_removed() {
+        let html = render("<script>alert(1)</script><img src=x onerror=alert(1)>");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+
+        let html = render("[click](javascript:alert(1))");
+        assert!(!html.contains("javascript:"));
+
+        let html = render("```\" onmouseover=\"alert(1)\n```");
+        assert!(!html.contains("onmouseover=\""));
+    }
+}
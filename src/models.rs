@@ -1,4 +1,5 @@
 use super::auth::Auth;
+use super::markdown;
 use super::schema::*;
 use chrono::{NaiveDateTime, Utc};
 use diesel::{sql_types::*, Expression, Insertable};
@@ -17,6 +18,26 @@ pub struct User {
 
     #[sql_type = "Timestamp"]
     pub created_at: NaiveDateTime,
+
+    /// `false` once the account has been disabled; it can no longer log in.
+    #[sql_type = "Bool"]
+    pub is_active: bool,
+
+    /// The account cannot log in before this time.
+    #[sql_type = "Nullable<Timestamp>"]
+    pub locked_until: Option<NaiveDateTime>,
+
+    /// Why the account was disabled, shown to its owner on login.
+    #[sql_type = "Nullable<Varchar>"]
+    pub disabled_reason: Option<String>,
+
+    /// Where password reset links are sent, stored lowercased.
+    #[sql_type = "Nullable<Varchar>"]
+    pub email: Option<String>,
+
+    /// When the owner confirmed `email`.  Publishing and commenting wait for it.
+    #[sql_type = "Nullable<Timestamp>"]
+    pub email_verified_at: Option<NaiveDateTime>,
 }
 
 impl User {
@@ -26,9 +47,25 @@ impl User {
             username: String::with_capacity(255),
             password: String::with_capacity(255),
             created_at: Utc::now().naive_utc(),
+            is_active: true,
+            locked_until: None,
+            disabled_reason: None,
+            email: None,
+            email_verified_at: None,
         }
     }
 
+    /// Returns `true` if the account's email address still awaits verification.
+    /// Accounts made before addresses were collected have none and aren't held back.
+    pub fn needs_email_verification(&self) -> bool {
+        self.email.is_some() && self.email_verified_at.is_none()
+    }
+
+    /// Returns `true` if the account is locked at `now`.
+    pub fn is_locked(&self, now: NaiveDateTime) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+
     pub fn get_id(&self) -> &i32 {
         &self.id
     }
@@ -53,6 +90,7 @@ impl Auth for User {
 pub struct NewUser<'nu> {
     pub username: &'nu str,
     pub password: &'nu str,
+    pub email: Option<&'nu str>,
 }
 
 #[derive(Debug, Insertable, Serialize)]
@@ -60,6 +98,9 @@ pub struct NewUser<'nu> {
 pub struct NewUserSession {
     pub session_key: String,
     pub user_id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub pending_2fa: bool,
 }
 
 impl NewUserSession {
@@ -67,6 +108,9 @@ impl NewUserSession {
         NewUserSession {
             session_key,
             user_id,
+            user_agent: None,
+            ip_address: None,
+            pending_2fa: false,
         }
     }
 }
@@ -74,8 +118,264 @@ impl NewUserSession {
 #[derive(Debug, Serialize, Queryable, QueryableByName)]
 pub struct UserSession {
     #[sql_type = "Varchar"]
-    session_key: String,
+    pub session_key: String,
 
     #[sql_type = "Integer"]
-    user_id: i32,
+    pub user_id: i32,
+
+    #[sql_type = "Timestamp"]
+    pub created_at: NaiveDateTime,
+
+    #[sql_type = "Nullable<Varchar>"]
+    pub user_agent: Option<String>,
+
+    #[sql_type = "Nullable<Varchar>"]
+    pub ip_address: Option<String>,
+
+    #[sql_type = "Timestamp"]
+    pub last_seen_at: NaiveDateTime,
+
+    /// `true` until the second login step, see `two_factor`.  Such sessions
+    /// don't log anyone in.
+    #[sql_type = "Bool"]
+    pub pending_2fa: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Queryable, QueryableByName)]
+#[table_name = "posts"]
+pub struct Post {
+    pub id: i32,
+    pub author_id: i32,
+    pub title: String,
+    pub slug: String,
+    pub body: String,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// `body` rendered by `markdown::render`; `None` for posts saved before it existed.
+    pub body_html: Option<String>,
+    /// When a scheduled post goes live, or when a published post went live.
+    pub publish_at: Option<NaiveDateTime>,
+}
+
+impl Post {
+    /// Returns `true` if anyone may read the post, not just its author.
+    pub fn is_public(&self) -> bool {
+        self.status == "published"
+    }
+
+    /// Sanitized HTML of the body, rendering it now if it wasn't cached.
+    pub fn html(&self) -> String {
+        match &self.body_html {
+            Some(html) => html.to_owned(),
+            None => markdown::render(&self.body),
+        }
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "posts"]
+pub struct NewPost<'np> {
+    pub author_id: i32,
+    pub title: &'np str,
+    pub slug: &'np str,
+    pub body: &'np str,
+    pub body_html: &'np str,
+    pub status: &'np str,
+    pub publish_at: Option<NaiveDateTime>,
+}
+
+/// Fields an author may change when editing a post.  The slug stays fixed so
+/// links to the post keep working.
+#[derive(Debug, AsChangeset)]
+#[table_name = "posts"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct PostChanges<'pc> {
+    pub title: &'pc str,
+    pub body: &'pc str,
+    pub body_html: &'pc str,
+    pub status: &'pc str,
+    pub publish_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, Queryable)]
+pub struct Comment {
+    pub id: i32,
+    pub post_id: i32,
+    pub author_id: i32,
+    /// The comment this one replies to, `None` for top-level comments.
+    pub parent_id: Option<i32>,
+    pub body: String,
+    /// `pending` until approved by the post's author, then `approved`.
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Comment {
+    /// Returns `true` if anyone may read the comment, not just its author and
+    /// the post's author.
+    pub fn is_approved(&self) -> bool {
+        self.status == "approved"
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "comments"]
+pub struct NewComment<'nc> {
+    pub post_id: i32,
+    pub author_id: i32,
+    pub parent_id: Option<i32>,
+    pub body: &'nc str,
+    pub status: &'nc str,
+}
+
+#[derive(Debug, Clone, Serialize, Queryable)]
+pub struct Tag {
+    pub id: i32,
+    pub name: String,
+    pub slug: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "tags"]
+pub struct NewTag<'nt> {
+    pub name: &'nt str,
+    pub slug: &'nt str,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "post_tags"]
+pub struct NewPostTag {
+    pub post_id: i32,
+    pub tag_id: i32,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "user_roles"]
+pub struct NewUserRole {
+    pub user_id: i32,
+    pub role_id: i32,
+}
+
+/// A token bucket of `ratelimit::MysqlStore`.
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset)]
+#[table_name = "rate_limit_buckets"]
+pub struct RateLimitBucket {
+    pub bucket_key: String,
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A password reset token, of which only the SHA-256 hash is stored.
+#[derive(Debug, Clone, Queryable)]
+pub struct PasswordResetToken {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "password_reset_tokens"]
+pub struct NewPasswordResetToken<'a> {
+    pub token_hash: &'a str,
+    pub user_id: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+/// An email verification token for the address `email`, of which only the
+/// SHA-256 hash is stored.
+#[derive(Debug, Clone, Queryable)]
+pub struct EmailVerificationToken {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "email_verification_tokens"]
+pub struct NewEmailVerificationToken<'a> {
+    pub token_hash: &'a str,
+    pub user_id: i32,
+    pub email: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+/// The TOTP secret of a user, see `two_factor`.  Two-factor authentication is
+/// on once `confirmed_at` is set.
+#[derive(Debug, Clone, Queryable)]
+pub struct UserTotp {
+    pub user_id: i32,
+    /// Base32 encoded, as shown to the user.
+    pub secret: String,
+    pub confirmed_at: Option<NaiveDateTime>,
+    /// Time step of the last code accepted; older codes are refused.
+    pub last_used_step: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "user_totp"]
+pub struct NewUserTotp<'a> {
+    pub user_id: i32,
+    pub secret: &'a str,
+}
+
+/// A one-time recovery code, of which only the SHA-256 hash is stored.
+#[derive(Debug, Insertable)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    pub code_hash: &'a str,
+    pub user_id: i32,
+}
+
+/// A challenge handed to the browser for a WebAuthn ceremony, see `passkeys`.
+/// Only its SHA-256 hash is stored.
+#[derive(Debug, Clone, Queryable)]
+pub struct WebauthnChallenge {
+    pub challenge_hash: String,
+    /// The user registering a passkey; `None` for logins.
+    pub user_id: Option<i32>,
+    pub purpose: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "webauthn_challenges"]
+pub struct NewWebauthnChallenge<'a> {
+    pub challenge_hash: &'a str,
+    pub user_id: Option<i32>,
+    pub purpose: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A passkey registered by a user, see `passkeys`.
+#[derive(Debug, Clone, Serialize, Queryable)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip)]
+    pub credential_id: Vec<u8>,
+    /// COSE encoded, as sent by the authenticator.
+    #[serde(skip)]
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "webauthn_credentials"]
+pub struct NewWebauthnCredential<'a> {
+    pub user_id: i32,
+    pub credential_id: &'a [u8],
+    pub public_key: &'a [u8],
+    pub sign_count: i64,
+    pub name: &'a str,
 }
@@ -0,0 +1,259 @@
+//! Logging in with passkeys, as an alternative to username and password.
+//!
+//! Logged-in users register passkeys on `/account/passkeys`, confirming with
+//! their password.  The login page can then ask the browser for any passkey of
+//! this site and log its owner in, skipping two-factor authentication, as
+//! `webauthn` requires the authenticator to verify the user.  Admins still
+//! have to set up TOTP, see `two_factor`.
+//!
+//! The site is the relying party at `WEBAUTHN_ORIGIN` (default: `APP_URL`),
+//! with the origin's host as id unless `WEBAUTHN_RP_ID` says otherwise, and
+//! `TOTP_ISSUER` as name.  Challenges work once and expire after
+//! `WEBAUTHN_CHALLENGE_TTL` seconds (default: five minutes); as for password
+//! resets only their SHA-256 hashes are stored.
+
+use super::auth::{ensure_active, ensure_unlocked};
+use super::config::app_url;
+use super::db::{
+    create_webauthn_challenge, create_webauthn_credential, get_user_by_id,
+    get_user_webauthn_credentials, lock_webauthn_credential, remove_webauthn_credential,
+    take_webauthn_challenge, use_webauthn_credential,
+};
+use super::errors::PasskeyError;
+use super::forms::{PasskeyRegistration, UserLogin};
+use super::lockout;
+use super::models::{NewWebauthnChallenge, NewWebauthnCredential, User, WebauthnCredential};
+use super::password_reset::hash_token;
+use super::sessions::{create_session, ClientInfo, CurrentSession};
+use super::two_factor;
+use super::webauthn::{
+    self, client_challenge, creation_options, generate_challenge, request_options,
+    AssertionResponse, RelyingParty,
+};
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{Connection, MysqlConnection};
+use std::convert::TryInto;
+use std::env;
+
+/// Challenge purpose of registering a passkey.
+const REGISTER: &str = "register";
+/// Challenge purpose of logging in.
+const LOGIN: &str = "login";
+
+/// This site as relying party, see the module docs.
+pub fn relying_party() -> RelyingParty {
+    let origin = env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| app_url());
+    let mut rp = RelyingParty::new(&origin, &two_factor::issuer());
+    if let Ok(id) = env::var("WEBAUTHN_RP_ID") {
+        rp.id = id;
+    }
+    rp
+}
+
+/// How long the browser has to answer, from `WEBAUTHN_CHALLENGE_TTL` in seconds.
+pub fn challenge_ttl() -> Duration {
+    let secs = env::var("WEBAUTHN_CHALLENGE_TTL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(5 * 60);
+    Duration::seconds(secs)
+}
+
+/// The WebAuthn user handle of user with given `user_id`.
+pub fn user_handle(user_id: i32) -> Vec<u8> {
+    user_id.to_be_bytes().to_vec()
+}
+
+/// Store a new challenge for `purpose` and return it.
+fn issue_challenge(
+    conn: &MysqlConnection,
+    user_id: Option<i32>,
+    purpose: &str,
+    now: NaiveDateTime,
+) -> Result<String, PasskeyError> {
+    let challenge = generate_challenge();
+    let item = NewWebauthnChallenge {
+        challenge_hash: &hash_token(&challenge),
+        user_id,
+        purpose,
+        expires_at: now + challenge_ttl(),
+    };
+    create_webauthn_challenge(conn, item, now)?;
+    Ok(challenge)
+}
+
+/// Use up the challenge `client_data_json` answers, which must have been
+/// issued for `purpose` to `user_id`, and return it.
+fn take_challenge(
+    conn: &MysqlConnection,
+    client_data_json: &str,
+    purpose: &str,
+    user_id: Option<i32>,
+    now: NaiveDateTime,
+) -> Result<String, PasskeyError> {
+    let challenge = client_challenge(client_data_json)?;
+    let issued =
+        conn.transaction(|| take_webauthn_challenge(conn, &hash_token(&challenge), purpose, now))?;
+    match issued {
+        Some(issued) if issued.user_id == user_id => Ok(challenge),
+        _ => Err(PasskeyError::InvalidChallenge),
+    }
+}
+
+/// The options for `navigator.credentials.create()` registering a passkey
+/// for `current`.
+pub fn start_registration(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    now: NaiveDateTime,
+) -> Result<serde_json::Value, PasskeyError> {
+    let user_id = current.user.id;
+    let challenge = issue_challenge(conn, Some(user_id), REGISTER, now)?;
+    let exclude: Vec<Vec<u8>> = get_user_webauthn_credentials(conn, user_id)?
+        .into_iter()
+        .map(|credential| credential.credential_id)
+        .collect();
+    Ok(creation_options(
+        &relying_party(),
+        &challenge,
+        &user_handle(user_id),
+        &current.user.username,
+        &exclude,
+        challenge_ttl().num_milliseconds(),
+    ))
+}
+
+/// Store the passkey in `registration` for `current`, given their password,
+/// and return it.
+pub fn finish_registration(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    registration: &PasskeyRegistration,
+    ip: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<WebauthnCredential, PasskeyError> {
+    registration.validate()?;
+    let credentials = UserLogin {
+        username: current.user.username.clone(),
+        password: registration.password.clone(),
+    };
+    lockout::authenticate(conn, &credentials, ip)?;
+
+    let response = &registration.credential;
+    let user_id = current.user.id;
+    let challenge = take_challenge(
+        conn,
+        &response.response.client_data_json,
+        REGISTER,
+        Some(user_id),
+        now,
+    )?;
+    let verified = webauthn::verify_registration(&relying_party(), &challenge, response)?;
+    conn.transaction(|| {
+        let item = NewWebauthnCredential {
+            user_id,
+            credential_id: &verified.credential_id,
+            public_key: &verified.public_key,
+            sign_count: i64::from(verified.sign_count),
+            name: registration.name.trim(),
+        };
+        create_webauthn_credential(conn, item)?;
+        lock_webauthn_credential(conn, &verified.credential_id)?
+            .ok_or(PasskeyError::UnknownCredential)
+    })
+}
+
+/// Fails unless `account` may log in at `now`: as for passwords, disabled and
+/// locked accounts are turned away.
+fn ensure_may_log_in(account: &User, now: NaiveDateTime) -> Result<(), PasskeyError> {
+    ensure_unlocked(account, now)?;
+    ensure_active(account)?;
+    Ok(())
+}
+
+/// The options for `navigator.credentials.get()` logging in with any passkey.
+pub fn start_login(
+    conn: &MysqlConnection,
+    now: NaiveDateTime,
+) -> Result<serde_json::Value, PasskeyError> {
+    let challenge = issue_challenge(conn, None, LOGIN, now)?;
+    Ok(request_options(
+        &relying_party(),
+        &challenge,
+        challenge_ttl().num_milliseconds(),
+    ))
+}
+
+/// Log in the owner of the passkey that signed `response`, starting a session
+/// from `client`, and return its key.
+pub fn finish_login(
+    conn: &MysqlConnection,
+    response: &AssertionResponse,
+    client: &ClientInfo,
+    now: NaiveDateTime,
+) -> Result<String, PasskeyError> {
+    let challenge = take_challenge(conn, &response.response.client_data_json, LOGIN, None, now)?;
+    let credential_id = webauthn::base64url_decode(&response.raw_id)?;
+    let user_id = conn.transaction(|| {
+        let credential = lock_webauthn_credential(conn, &credential_id)?
+            .ok_or(PasskeyError::UnknownCredential)?;
+        if let Some(handle) = &response.response.user_handle {
+            if webauthn::base64url_decode(handle)? != user_handle(credential.user_id) {
+                return Err(PasskeyError::UnknownCredential);
+            }
+        }
+        let stored_count = credential.sign_count.try_into().unwrap_or(u32::MAX);
+        let count = webauthn::verify_assertion(
+            &relying_party(),
+            &challenge,
+            response,
+            &credential.public_key,
+            stored_count,
+        )?;
+        use_webauthn_credential(conn, credential.id, i64::from(count), now)?;
+        Ok(credential.user_id)
+    })?;
+    ensure_may_log_in(&get_user_by_id(conn, user_id)?, now)?;
+    Ok(create_session(conn, user_id, client)?)
+}
+
+/// Returns the passkeys of user with given `user_id`.
+pub fn list(conn: &MysqlConnection, user_id: i32) -> Result<Vec<WebauthnCredential>, PasskeyError> {
+    Ok(get_user_webauthn_credentials(conn, user_id)?)
+}
+
+/// Remove the passkey with given `id` of user with given `user_id`.
+pub fn remove(conn: &MysqlConnection, user_id: i32, id: i32) -> Result<(), PasskeyError> {
+    match remove_webauthn_credential(conn, user_id, id)? {
+        0 => Err(PasskeyError::UnknownCredential),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::AuthError;
+
+    #[test]
+    fn locked_and_disabled_accounts_refused() {
+        let now = chrono::Utc::now().naive_utc();
+        let mut account = User::new();
+        assert!(ensure_may_log_in(&account, now).is_ok());
+
+        account.locked_until = Some(now + Duration::minutes(1));
+        assert!(matches!(
+            ensure_may_log_in(&account, now),
+            Err(PasskeyError::Auth(AuthError::AccountLocked { .. }))
+        ));
+
+        account.locked_until = None;
+        account.is_active = false;
+        assert!(matches!(
+            ensure_may_log_in(&account, now),
+            Err(PasskeyError::Auth(AuthError::AccountDisabled { .. }))
+        ));
+    }
+}
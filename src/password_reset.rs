@@ -0,0 +1,155 @@
+//! Password reset by mail.
+//!
+//! `POST /password/forgot` mails a link with a random token to the active
+//! account with the given email address.  Only the token's SHA-256 hash is
+//! stored, so the table alone cannot be used to reset passwords.  Tokens
+//! expire after `PASSWORD_RESET_TTL` seconds (default: one hour) and work once:
+//! resetting the password removes all tokens of the account and ends all of
+//! its sessions.
+
+use super::auth::hash_password;
+use super::config::app_url;
+use super::db::{
+    create_password_reset_token, end_all_user_sessions, get_user_by_email, get_user_by_id,
+    lock_password_reset_token, remove_password_reset_tokens, update_user_password,
+};
+use super::errors::ResetError;
+use super::lockout;
+use super::mailer::{Mail, Mailer};
+use super::models::NewPasswordResetToken;
+use super::sessions::generate_session_key;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{Connection, MysqlConnection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// How long a reset link works, from `PASSWORD_RESET_TTL` in seconds.
+pub fn ttl() -> Duration {
+    let secs = env::var("PASSWORD_RESET_TTL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(60 * 60);
+    Duration::seconds(secs)
+}
+
+/// The hex encoded SHA-256 hash of `token`, as stored.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Store a new token for user with given `user_id` and return it.
+pub fn issue(
+    conn: &MysqlConnection,
+    user_id: i32,
+    now: NaiveDateTime,
+) -> Result<String, ResetError> {
+    let token = generate_session_key();
+    let item = NewPasswordResetToken {
+        token_hash: &hash_token(&token),
+        user_id,
+        expires_at: now + ttl(),
+    };
+    create_password_reset_token(conn, item, now)?;
+    Ok(token)
+}
+
+/// Returns `true` if `token` can still be used at `now`.
+pub fn is_valid(
+    conn: &MysqlConnection,
+    token: &str,
+    now: NaiveDateTime,
+) -> Result<bool, ResetError> {
+    Ok(lock_password_reset_token(conn, &hash_token(token), now)?.is_some())
+}
+
+/// The mail sending `link` to `to`.
+pub fn reset_mail(to: &str, link: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: String::from("Reset your password"),
+        body: format!(
+            "Someone asked to reset the password of your account.\n\n\
+             To choose a new password, follow this link within {} minutes:\n\n\
+             {}\n\n\
+             If it wasn't you, you can ignore this mail.",
+            ttl().num_minutes(),
+            link
+        ),
+    }
+}
+
+/// Mail a reset link to the active account with `email`.  Nothing is sent if
+/// there is none, without telling the caller.
+pub fn send_link(
+    conn: &MysqlConnection,
+    mailer: &dyn Mailer,
+    email: &str,
+    now: NaiveDateTime,
+) -> Result<(), ResetError> {
+    let user = match get_user_by_email(conn, email).optional()? {
+        Some(user) if user.is_active => user,
+        _ => return Ok(()),
+    };
+    let token = issue(conn, user.id, now)?;
+    let link = format!("{}/password/reset/{}", app_url(), token);
+    mailer
+        .send(&reset_mail(email, &link))
+        .map_err(ResetError::MailFailed)
+}
+
+/// Set the password of the account `token` was issued for, using it up
+/// together with every other token of the account.  All sessions of the
+/// account are ended and its failed logins forgotten.
+pub fn reset_password(
+    conn: &MysqlConnection,
+    token: &str,
+    password: &str,
+    now: NaiveDateTime,
+) -> Result<(), ResetError> {
+    let hashed = hash_password(password)?;
+    conn.transaction(|| {
+        let token = lock_password_reset_token(conn, &hash_token(token), now)?
+            .ok_or(ResetError::InvalidToken)?;
+        let user = get_user_by_id(conn, token.user_id)?;
+        update_user_password(conn, user.id, &hashed)?;
+        remove_password_reset_tokens(conn, user.id)?;
+        end_all_user_sessions(conn, user.id)?;
+        lockout::reset(conn, &user.username, None)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_stored_hashed() {
+        let token = generate_session_key();
+        let hashed = hash_token(&token);
+        assert_eq!(hashed.len(), 64);
+        assert_ne!(hashed, token);
+        assert_eq!(hash_token(&token), hashed);
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn reset_mail_carries_link() {
+        let mail = reset_mail(
+            "bender@example.com",
+            "http://localhost/password/reset/t0ken",
+        );
+        assert_eq!(mail.to, "bender@example.com");
+        assert!(mail
+            .body
+            .contains("\n\nhttp://localhost/password/reset/t0ken\n\n"));
+    }
+}
@@ -0,0 +1,51 @@
+//! Background job publishing scheduled posts.
+//!
+//! `start` spawns a task on the actix runtime that wakes up every
+//! `PUBLISHER_INTERVAL` seconds (default: one minute) and flips every
+//! `scheduled` post whose `publish_at` has passed to `published`.
+
+use super::db::publish_due_posts;
+use super::DbPool;
+
+use actix_web::{rt, web};
+use chrono::Utc;
+use std::env;
+use std::time::Duration;
+
+/// How often scheduled posts are checked, from `PUBLISHER_INTERVAL` in seconds.
+pub fn interval() -> Duration {
+    let secs = env::var("PUBLISHER_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(60);
+    Duration::from_secs(secs)
+}
+
+/// Publish the posts that are due now.  Returns how many were published.
+pub async fn publish_due(pool: DbPool) -> Result<usize, String> {
+    web::block(move || {
+        let conn = pool.get().map_err(|e| e.to_string())?;
+        publish_due_posts(&conn, Utc::now().naive_utc()).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Spawn the publisher on the current actix runtime.
+///
+/// Must be called from within the runtime, e.g. from `#[actix_web::main]`:
+///     publisher::start(pool.clone());
+pub fn start(pool: DbPool) {
+    rt::spawn(async move {
+        let mut ticks = rt::time::interval(interval());
+        loop {
+            ticks.tick().await;
+            match publish_due(pool.clone()).await {
+                Ok(0) => {}
+                Ok(n) => println!("Published {} scheduled post(s)", n),
+                Err(e) => eprintln!("Publishing scheduled posts failed: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,437 @@
+//! Rate limiting for the authentication endpoints.
+//!
+//! The `RateLimit` middleware gives every client IP address, and optionally
+//! every value of a form field such as `username`, a token bucket holding a
+//! burst of requests that refills steadily.  Requests finding a bucket empty
+//! get `429 Too Many Requests` with a `Retry-After` header.
+//!
+//! Routes are wrapped in one of the constants below, whose quota can be
+//! overridden with `RATE_LIMIT_<NAME>=<burst>/<seconds>`, e.g.
+//! `RATE_LIMIT_LOGIN=10/60`:
+//!     #[post("/login", wrap = "LOGIN_RATE_LIMIT")]
+//!     async fn login(...) -> HttpResponse { ... }
+//!
+//! Field values are compared lowercased and keyed by their SHA-256 hash, so
+//! buckets stay small however long the value.  They are per value as sent:
+//! logging in by username and by email address draws on two buckets of the
+//! same account.  `lockout` is what counts failures per account.
+//!
+//! Buckets are kept in memory unless `RATE_LIMIT_STORE=mysql`, which keeps them
+//! in the `rate_limit_buckets` table shared by every instance of the app.  The
+//! store must be registered as app data:
+//!     App::new().app_data(ratelimit::store_from_env(pool.clone()))
+
+use super::db::{lock_rate_limit_bucket, save_rate_limit_bucket};
+use super::errors::RateLimited;
+use super::forms::peek_form_field;
+use super::models::RateLimitBucket;
+use super::password_reset::hash_token;
+use super::sessions::client_ip;
+use super::DbPool;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::{self, BlockingError},
+    web, Error,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::Connection;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+pub const LOGIN_RATE_LIMIT: RateLimit =
+    RateLimit::new("login", Quota::new(10, 60)).by_field("username");
+pub const SIGNUP_RATE_LIMIT: RateLimit =
+    RateLimit::new("signup", Quota::new(5, 60 * 60)).by_field("username");
+pub const PASSWORD_RESET_RATE_LIMIT: RateLimit =
+    RateLimit::new("password_reset", Quota::new(5, 60 * 60)).by_field("email");
+pub const ACCOUNT_RATE_LIMIT: RateLimit = RateLimit::new("account", Quota::new(10, 60 * 60));
+pub const EMAIL_VERIFICATION_RATE_LIMIT: RateLimit =
+    RateLimit::new("email_verification", Quota::new(5, 60 * 60));
+pub const TWO_FACTOR_RATE_LIMIT: RateLimit = RateLimit::new("two_factor", Quota::new(10, 60));
+pub const PASSKEY_RATE_LIMIT: RateLimit = RateLimit::new("passkey", Quota::new(10, 60));
+
+/// Buckets kept in memory by `MemoryStore` before full ones are dropped.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// A burst of `burst` requests, refilled over `period_secs` seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period_secs: u32,
+}
+
+impl Quota {
+    pub const fn new(burst: u32, period_secs: u32) -> Self {
+        Quota { burst, period_secs }
+    }
+
+    /// Parse `<burst>/<seconds>`, e.g. `10/60`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (burst, period) = s.split_once('/')?;
+        let quota = Quota::new(burst.trim().parse().ok()?, period.trim().parse().ok()?);
+        Some(quota).filter(|q| q.burst > 0 && q.period_secs > 0)
+    }
+
+    /// Tokens added to a bucket per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.burst) / f64::from(self.period_secs)
+    }
+}
+
+/// State of one token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Bucket {
+    pub fn full(quota: &Quota, now: NaiveDateTime) -> Self {
+        Bucket {
+            tokens: f64::from(quota.burst),
+            updated_at: now,
+        }
+    }
+
+    /// Refill the bucket for the time since it was last updated and take a
+    /// token.  Returns how long until there is one if the bucket is empty.
+    pub fn take(&mut self, quota: &Quota, now: NaiveDateTime) -> Option<Duration> {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * quota.rate()).min(f64::from(quota.burst));
+        self.updated_at = self.updated_at.max(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        let wait = (1.0 - self.tokens) / quota.rate();
+        Some(Duration::milliseconds((wait * 1000.0).ceil() as i64))
+    }
+
+    /// When the bucket will be full again, after which it can be forgotten.
+    fn full_at(&self, quota: &Quota) -> NaiveDateTime {
+        let missing = f64::from(quota.burst) - self.tokens;
+        self.updated_at + Duration::milliseconds((missing / quota.rate() * 1000.0).ceil() as i64)
+    }
+}
+
+/// Where token buckets are kept.
+pub trait Store: Send + Sync {
+    /// Take a token from bucket `key` at `now`.  Returns how long until there
+    /// is one if the bucket is empty.
+    fn take(
+        &self,
+        key: &str,
+        quota: &Quota,
+        now: NaiveDateTime,
+    ) -> Result<Option<Duration>, String>;
+}
+
+/// Buckets in the memory of this process.  Full buckets are dropped once
+/// there are more than `MAX_MEMORY_BUCKETS`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, (Bucket, NaiveDateTime)>>,
+}
+
+impl Store for MemoryStore {
+    fn take(
+        &self,
+        key: &str,
+        quota: &Quota,
+        now: NaiveDateTime,
+    ) -> Result<Option<Duration>, String> {
+        let mut buckets = self.buckets.lock().map_err(|e| e.to_string())?;
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            buckets.retain(|_, (_, full_at)| *full_at > now);
+        }
+        let (bucket, full_at) = buckets
+            .entry(key.to_owned())
+            .or_insert_with(|| (Bucket::full(quota, now), now));
+        let wait = bucket.take(quota, now);
+        *full_at = bucket.full_at(quota);
+        Ok(wait)
+    }
+}
+
+/// Buckets in the `rate_limit_buckets` table, shared by every instance.
+pub struct MysqlStore {
+    pub pool: DbPool,
+}
+
+impl Store for MysqlStore {
+    fn take(
+        &self,
+        key: &str,
+        quota: &Quota,
+        now: NaiveDateTime,
+    ) -> Result<Option<Duration>, String> {
+        let conn = self.pool.get().map_err(|e| e.to_string())?;
+        let fresh = Bucket::full(quota, now);
+        conn.transaction(|| {
+            let row = lock_rate_limit_bucket(
+                &conn,
+                &RateLimitBucket {
+                    bucket_key: key.to_owned(),
+                    tokens: fresh.tokens,
+                    updated_at: fresh.updated_at,
+                },
+            )?;
+            let mut bucket = Bucket {
+                tokens: row.tokens,
+                updated_at: row.updated_at,
+            };
+            let wait = bucket.take(quota, now);
+            save_rate_limit_bucket(
+                &conn,
+                &RateLimitBucket {
+                    tokens: bucket.tokens,
+                    updated_at: bucket.updated_at,
+                    ..row
+                },
+            )?;
+            Ok(wait)
+        })
+        .map_err(|e: diesel::result::Error| e.to_string())
+    }
+}
+
+/// The store picked by `RATE_LIMIT_STORE` (`memory` or `mysql`), as app data.
+pub fn store_from_env(pool: DbPool) -> web::Data<dyn Store> {
+    let store: Arc<dyn Store> = match env::var("RATE_LIMIT_STORE").as_deref() {
+        Ok("mysql") => Arc::new(MysqlStore { pool }),
+        _ => Arc::new(MemoryStore::default()),
+    };
+    web::Data::from(store)
+}
+
+/// Middleware limiting requests per client IP address, and per value of a
+/// form field if one is given.
+///
+/// Needs a `web::Data<dyn Store>` in the app data, see `store_from_env`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    name: &'static str,
+    quota: Quota,
+    field: Option<&'static str>,
+}
+
+impl RateLimit {
+    /// Limit requests to `quota`, with buckets prefixed by `name`.
+    pub const fn new(name: &'static str, quota: Quota) -> Self {
+        RateLimit {
+            name,
+            quota,
+            field: None,
+        }
+    }
+
+    /// Also limit requests per value of urlencoded form field `field`.
+    pub const fn by_field(self, field: &'static str) -> Self {
+        RateLimit {
+            field: Some(field),
+            ..self
+        }
+    }
+
+    /// The quota, unless overridden by `RATE_LIMIT_<NAME>`.
+    pub fn quota(&self) -> Quota {
+        env::var(format!("RATE_LIMIT_{}", self.name.to_uppercase()))
+            .ok()
+            .and_then(|v| Quota::parse(&v))
+            .unwrap_or(self.quota)
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            limit: *self,
+            quota: self.quota(),
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    limit: RateLimit,
+    quota: Quota,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let (limit, quota) = (self.limit, self.quota);
+
+        Box::pin(async move {
+            let mut keys = Vec::new();
+            if let Some(ip) = client_ip(&req.connection_info(), req.peer_addr()) {
+                keys.push(format!("{}:ip:{}", limit.name, ip));
+            }
+            if let Some(field) = limit.field {
+                if let Some(value) = peek_form_field(&mut req, field).await? {
+                    keys.push(field_key(&limit, field, &value));
+                }
+            }
+
+            let store = req
+                .app_data::<web::Data<dyn Store>>()
+                .cloned()
+                .ok_or_else(|| {
+                    error::ErrorInternalServerError("No rate limit store configured.")
+                })?;
+            let wait =
+                web::block(move || take_all(&**store, &keys, &quota, Utc::now().naive_utc()))
+                    .await
+                    .map_err(|e| match e {
+                        BlockingError::Error(e) => error::ErrorInternalServerError(e),
+                        e => error::ErrorInternalServerError(e),
+                    })?;
+            if let Some(wait) = wait {
+                let retry_after = (wait.num_milliseconds() as u64).div_ceil(1000);
+                return Err(RateLimited {
+                    retry_after: retry_after.max(1),
+                }
+                .into());
+            }
+
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Key of the bucket for `value` of form field `field`, of fixed length to fit
+/// `rate_limit_buckets.bucket_key`.
+fn field_key(limit: &RateLimit, field: &str, value: &str) -> String {
+    format!(
+        "{}:{}:{}",
+        limit.name,
+        field,
+        hash_token(&value.to_lowercase())
+    )
+}
+
+/// Take a token from each bucket in `keys`, stopping at the first empty one.
+fn take_all(
+    store: &dyn Store,
+    keys: &[String],
+    quota: &Quota,
+    now: NaiveDateTime,
+) -> Result<Option<Duration>, String> {
+    for key in keys {
+        if let Some(wait) = store.take(key, quota, now)? {
+            return Ok(Some(wait));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header, StatusCode},
+        rt, test, App, HttpResponse,
+    };
+
+    #[test]
+    fn buckets_refill_steadily() {
+        let quota = Quota::new(2, 60);
+        let now = Utc::now().naive_utc();
+        let mut bucket = Bucket::full(&quota, now);
+        assert_eq!(bucket.take(&quota, now), None);
+        assert_eq!(bucket.take(&quota, now), None);
+        assert_eq!(bucket.take(&quota, now), Some(Duration::seconds(30)));
+        assert_eq!(bucket.take(&quota, now + Duration::seconds(30)), None);
+        assert_eq!(Quota::parse("10/60"), Some(Quota::new(10, 60)));
+        assert_eq!(Quota::parse("10/0"), None);
+    }
+
+    #[test]
+    fn long_field_values_hashed() {
+        let username = "b".repeat(16 * 1024);
+        let key = field_key(&LOGIN_RATE_LIMIT, "username", &username);
+        assert!(key.len() < 255);
+        assert!(!key.contains("bbbb"));
+        assert_eq!(
+            key,
+            field_key(&LOGIN_RATE_LIMIT, "username", &username.to_uppercase())
+        );
+    }
+
+    #[test]
+    fn limited_by_ip_and_username() {
+        rt::System::new("test").block_on(async {
+            let store: web::Data<dyn Store> =
+                web::Data::from(Arc::new(MemoryStore::default()) as Arc<dyn Store>);
+            let limit = RateLimit::new("test", Quota::new(2, 60)).by_field("username");
+            let mut app =
+                test::init_service(App::new().app_data(store).service(
+                    web::resource("/").wrap(limit).to(
+                        |form: web::Form<HashMap<String, String>>| {
+                            HttpResponse::Ok().body(form["username"].clone())
+                        },
+                    ),
+                ))
+                .await;
+
+            let login = |ip: &str, username: &str| {
+                test::TestRequest::post()
+                    .peer_addr(format!("{}:4242", ip).parse().unwrap())
+                    .set_form(&[("username", username)])
+                    .to_request()
+            };
+            for _ in 0..2 {
+                let res = test::call_service(&mut app, login("192.0.2.1", "bender")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(test::read_body(res).await, "bender");
+            }
+            let e = app.call(login("192.0.2.1", "fry")).await.err().unwrap();
+            let res = e.as_response_error().error_response();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+            // Another address can't keep guessing the same username.
+            let e = app.call(login("192.0.2.2", "BENDER")).await.err().unwrap();
+            assert_eq!(
+                e.as_response_error().status_code(),
+                StatusCode::TOO_MANY_REQUESTS
+            );
+            let res = test::call_service(&mut app, login("192.0.2.2", "fry")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        });
+    }
+}
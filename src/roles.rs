@@ -0,0 +1,237 @@
+//! Roles, permissions and the `RoleGuard` middleware enforcing them.
+//!
+//! Roles and the permissions each grants live in the `roles`, `permissions` and
+//! `role_permissions` tables; users get roles through `user_roles`.  The stock
+//! roles, each including everything the previous one may do, are:
+//!     reader   write comments
+//!     author   write posts
+//!     editor   edit anyone's posts, moderate comments
+//!     admin    browse and manage users
+//!
+//! Routes are guarded with one of the `RoleGuard` constants below:
+//!     #[post("/posts", wrap = "CAN_WRITE_POSTS")]
+//!     async fn create_post_form(...) -> HttpResponse { ... }
+
+use super::errors::{AccessDenied, LoginRequired, TwoFactorRequired};
+use super::extractors::wants_html;
+use super::sessions::CurrentSession;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures::future::{err, ok, Either, Ready};
+use std::task::{Context, Poll};
+
+pub const READER: &str = "reader";
+pub const AUTHOR: &str = "author";
+pub const EDITOR: &str = "editor";
+pub const ADMIN: &str = "admin";
+
+pub const WRITE_COMMENTS: &str = "comments:write";
+pub const WRITE_POSTS: &str = "posts:write";
+pub const EDIT_ANY_POST: &str = "posts:edit_any";
+pub const MODERATE_COMMENTS: &str = "comments:moderate";
+pub const READ_USERS: &str = "users:read";
+pub const MANAGE_USERS: &str = "users:manage";
+
+/// The stock roles, from least to most privileged.
+pub const ROLES: [&str; 4] = [READER, AUTHOR, EDITOR, ADMIN];
+
+/// Role given to every new account.
+pub const DEFAULT_ROLE: &str = READER;
+
+pub const CAN_WRITE_COMMENTS: RoleGuard = RoleGuard::permission(WRITE_COMMENTS);
+pub const CAN_WRITE_POSTS: RoleGuard = RoleGuard::permission(WRITE_POSTS);
+pub const CAN_MODERATE_COMMENTS: RoleGuard = RoleGuard::permission(MODERATE_COMMENTS);
+pub const CAN_READ_USERS: RoleGuard = RoleGuard::permission(READ_USERS);
+pub const CAN_MANAGE_USERS: RoleGuard = RoleGuard::permission(MANAGE_USERS);
+pub const ADMIN_ONLY: RoleGuard = RoleGuard::role(ADMIN);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Requirement {
+    Role(&'static str),
+    Permission(&'static str),
+}
+
+/// Middleware letting only users with a given role or permission through.
+///
+/// Anonymous requests fail with `LoginRequired`, users lacking the role or
+/// permission with `AccessDenied`, and users who must set up two-factor
+/// authentication first with `TwoFactorRequired`.  Must run inside
+/// `SessionAuth`, which is the case for any route of an `App` wrapped in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoleGuard(Requirement);
+
+impl RoleGuard {
+    /// Require the role named `role`.
+    pub const fn role(role: &'static str) -> Self {
+        RoleGuard(Requirement::Role(role))
+    }
+
+    /// Require a role granting `permission`.
+    pub const fn permission(permission: &'static str) -> Self {
+        RoleGuard(Requirement::Permission(permission))
+    }
+
+    /// Returns `true` if `current` meets the requirement.
+    pub fn allows(&self, current: &CurrentSession) -> bool {
+        match self.0 {
+            Requirement::Role(role) => current.has_role(role),
+            Requirement::Permission(permission) => current.can(permission),
+        }
+    }
+
+    /// Check the `CurrentSession` of `req` against the requirement.  Users who
+    /// still have to set up two-factor authentication are sent to do so first.
+    fn check(&self, req: &ServiceRequest) -> Result<(), Error> {
+        match req.extensions().get::<CurrentSession>() {
+            Some(current) if current.needs_two_factor() => Err(TwoFactorRequired {
+                redirect: wants_html(req),
+            }
+            .into()),
+            Some(current) if self.allows(current) => Ok(()),
+            Some(_) => Err(AccessDenied.into()),
+            None => Err(LoginRequired {
+                redirect: wants_html(req),
+            }
+            .into()),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RoleGuard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RoleGuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RoleGuardMiddleware {
+            guard: *self,
+            service,
+        })
+    }
+}
+
+pub struct RoleGuardMiddleware<S> {
+    guard: RoleGuard,
+    service: S,
+}
+
+impl<S, B> Service for RoleGuardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.guard.check(&req) {
+            Ok(()) => Either::Left(self.service.call(req)),
+            Err(e) => Either::Right(err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::UserResponse;
+    use actix_web::{dev::Service, http::StatusCode, rt, test, web, App, HttpResponse};
+
+    fn session(roles: &[&str], permissions: &[&str]) -> CurrentSession {
+        CurrentSession {
+            session_key: String::from("test-token"),
+            user: UserResponse::new(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            email_verified: true,
+            two_factor: false,
+        }
+    }
+
+    /// Status of the response to `req`, including errors raised by middleware.
+    async fn status<S, R>(app: &mut S, req: R) -> StatusCode
+    where
+        S: Service<Request = R, Response = ServiceResponse, Error = Error>,
+    {
+        match app.call(req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn guard_checks_roles_and_permissions() {
+        let editor = session(&[READER, EDITOR], &[WRITE_COMMENTS, MODERATE_COMMENTS]);
+        assert!(CAN_MODERATE_COMMENTS.allows(&editor));
+        assert!(RoleGuard::role(EDITOR).allows(&editor));
+        assert!(!ADMIN_ONLY.allows(&editor));
+        assert!(!CAN_READ_USERS.allows(&editor));
+    }
+
+    #[test]
+    fn guarded_route_rejects_missing_permission() {
+        rt::System::new("test").block_on(async {
+            let mut app = test::init_service(
+                App::new().service(
+                    web::resource("/")
+                        .wrap(CAN_WRITE_POSTS)
+                        .to(HttpResponse::Ok),
+                ),
+            )
+            .await;
+
+            let req = test::TestRequest::default().to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::UNAUTHORIZED);
+
+            let req = test::TestRequest::default().to_request();
+            req.extensions_mut()
+                .insert(session(&[READER], &[WRITE_COMMENTS]));
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+
+            let req = test::TestRequest::default().to_request();
+            req.extensions_mut()
+                .insert(session(&[AUTHOR], &[WRITE_POSTS]));
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn admins_without_two_factor_sent_to_set_it_up() {
+        rt::System::new("test").block_on(async {
+            let mut app = test::init_service(
+                App::new().service(web::resource("/").wrap(ADMIN_ONLY).to(HttpResponse::Ok)),
+            )
+            .await;
+
+            let mut admin = session(&[ADMIN], &[]);
+            let req = test::TestRequest::default()
+                .header("accept", "text/html")
+                .to_request();
+            req.extensions_mut().insert(admin.clone());
+            assert_eq!(status(&mut app, req).await, StatusCode::SEE_OTHER);
+
+            admin.two_factor = true;
+            let req = test::TestRequest::default().to_request();
+            req.extensions_mut().insert(admin);
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+        });
+    }
+}
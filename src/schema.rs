@@ -1,8 +1,139 @@
+table! {
+    comments (id) {
+        id -> Integer,
+        post_id -> Integer,
+        author_id -> Integer,
+        parent_id -> Nullable<Integer>,
+        body -> Text,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    email_verification_tokens (token_hash) {
+        token_hash -> Char,
+        user_id -> Integer,
+        email -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    login_failures (scope, subject) {
+        scope -> Varchar,
+        subject -> Varchar,
+        failures -> Integer,
+        locked_until -> Nullable<Timestamp>,
+        last_failed_at -> Timestamp,
+    }
+}
+
+table! {
+    password_reset_tokens (token_hash) {
+        token_hash -> Char,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    permissions (id) {
+        id -> Integer,
+        name -> Varchar,
+    }
+}
+
+table! {
+    post_tags (post_id, tag_id) {
+        post_id -> Integer,
+        tag_id -> Integer,
+    }
+}
+
+table! {
+    posts (id) {
+        id -> Integer,
+        author_id -> Integer,
+        title -> Varchar,
+        slug -> Varchar,
+        body -> Text,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        body_html -> Nullable<Text>,
+        publish_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    rate_limit_buckets (bucket_key) {
+        bucket_key -> Varchar,
+        tokens -> Double,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_codes (code_hash) {
+        code_hash -> Char,
+        user_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Integer,
+        permission_id -> Integer,
+    }
+}
+
+table! {
+    roles (id) {
+        id -> Integer,
+        name -> Varchar,
+    }
+}
+
 table! {
     sessions (session_key) {
         session_key -> Varchar,
         user_id -> Integer,
         created_at -> Timestamp,
+        user_agent -> Nullable<Varchar>,
+        ip_address -> Nullable<Varchar>,
+        last_seen_at -> Timestamp,
+        pending_2fa -> Bool,
+    }
+}
+
+table! {
+    tags (id) {
+        id -> Integer,
+        name -> Varchar,
+        slug -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_roles (user_id, role_id) {
+        user_id -> Integer,
+        role_id -> Integer,
+    }
+}
+
+table! {
+    user_totp (user_id) {
+        user_id -> Integer,
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Bigint>,
+        created_at -> Timestamp,
     }
 }
 
@@ -12,9 +143,71 @@ table! {
         username -> Varchar,
         password -> Varchar,
         created_at -> Timestamp,
+        is_active -> Bool,
+        locked_until -> Nullable<Timestamp>,
+        disabled_reason -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webauthn_challenges (challenge_hash) {
+        challenge_hash -> Char,
+        user_id -> Nullable<Integer>,
+        purpose -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    webauthn_credentials (id) {
+        id -> Integer,
+        user_id -> Integer,
+        credential_id -> Varbinary,
+        public_key -> Blob,
+        sign_count -> Bigint,
+        name -> Varchar,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
     }
 }
 
+joinable!(comments -> posts (post_id));
+joinable!(comments -> users (author_id));
+joinable!(email_verification_tokens -> users (user_id));
+joinable!(password_reset_tokens -> users (user_id));
+joinable!(post_tags -> posts (post_id));
+joinable!(post_tags -> tags (tag_id));
+joinable!(posts -> users (author_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(role_permissions -> permissions (permission_id));
+joinable!(role_permissions -> roles (role_id));
 joinable!(sessions -> users (user_id));
+joinable!(user_roles -> roles (role_id));
+joinable!(user_roles -> users (user_id));
+joinable!(user_totp -> users (user_id));
+joinable!(webauthn_challenges -> users (user_id));
+joinable!(webauthn_credentials -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(sessions, users,);
+allow_tables_to_appear_in_same_query!(
+    comments,
+    email_verification_tokens,
+    login_failures,
+    password_reset_tokens,
+    permissions,
+    post_tags,
+    posts,
+    rate_limit_buckets,
+    recovery_codes,
+    role_permissions,
+    roles,
+    sessions,
+    tags,
+    user_roles,
+    user_totp,
+    users,
+    webauthn_challenges,
+    webauthn_credentials,
+);
@@ -0,0 +1,355 @@
+//! Server-side user sessions backed by the `sessions` table.
+//!
+//! On login a random session key is stored in `sessions` and only that opaque
+//! key goes into the cookie.  `SessionAuth` resolves the key back to its user
+//! on every request and stores a `CurrentSession` in the request extensions.
+//! API clients may send the key as `Authorization: Bearer <session key>`
+//! instead of a cookie.  Rows older than `SESSION_MAX_AGE` seconds (default:
+//! one week) are treated as expired.
+
+use super::csrf::CSRF_KEY;
+use super::db::{
+    create_user_session, delete_sessions_before, get_user_by_session_key, get_user_permissions,
+    get_user_roles, touch_user_session,
+};
+use super::errors::AuthError;
+use super::models::NewUserSession;
+use super::password_reset::hash_token;
+use super::two_factor;
+use super::users::UserResponse;
+use super::DbPool;
+
+use actix_session::{Session, UserSession};
+use actix_web::{
+    dev::{ConnectionInfo, Service, ServiceRequest, ServiceResponse, Transform},
+    error::BlockingError,
+    http::header,
+    web, Error, HttpMessage, HttpRequest,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{result::Error as DieselError, MysqlConnection};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::env;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Name of the cookie session field holding the session key.
+pub const SESSION_KEY: &str = "session-key";
+/// Name of the cookie session field holding the key of a pending session
+/// waiting for the second login step.
+pub const PENDING_SESSION_KEY: &str = "pending-session-key";
+
+/// The logged-in user resolved from the session key of the current request.
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentSession {
+    pub session_key: String,
+    pub user: UserResponse,
+    /// Names of the user's roles, see `roles`.
+    pub roles: Vec<String>,
+    /// Names of the permissions granted by those roles.
+    pub permissions: Vec<String>,
+    /// `false` while the user's email address awaits verification, see
+    /// `email_verification`.
+    pub email_verified: bool,
+    /// `true` if the user has set up two-factor authentication, see `two_factor`.
+    pub two_factor: bool,
+}
+
+impl CurrentSession {
+    /// Returns `true` if the user has the role named `role`.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Returns `true` if any of the user's roles grants `permission`.
+    pub fn can(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+
+    /// Returns `true` if one of the user's roles requires two-factor
+    /// authentication they haven't set up yet.
+    pub fn needs_two_factor(&self) -> bool {
+        !self.two_factor && two_factor::is_required(&self.roles)
+    }
+}
+
+/// Where a session was started from, as shown in the session list.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl ClientInfo {
+    /// Read the `User-Agent` header and client address of `request`.
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.chars().take(255).collect());
+        let ip_address = client_ip(&request.connection_info(), request.peer_addr());
+
+        ClientInfo {
+            user_agent,
+            ip_address,
+        }
+    }
+}
+
+/// Address of the client connected from `peer`, as shown in the session list
+/// and used for lockouts and rate limits.
+///
+/// The `Forwarded` and `X-Forwarded-For` headers in `info` are only believed if
+/// `TRUST_PROXY_HEADERS` is `true` or `1`, i.e. when a reverse proxy sets them;
+/// otherwise clients could claim any address.
+pub fn client_ip(info: &ConnectionInfo, peer: Option<SocketAddr>) -> Option<String> {
+    let trust_proxy = env::var("TRUST_PROXY_HEADERS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    let addr = if trust_proxy {
+        info.realip_remote_addr().map(str::to_owned)
+    } else {
+        peer.map(|peer| peer.ip().to_string())
+    };
+    addr.map(|addr| addr.chars().take(45).collect())
+}
+
+/// Returns a 256-bit random session key from the OS rng, hex encoded.
+pub fn generate_session_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The id under which session `session_key` is listed and revoked.
+///
+/// Session keys are credentials, so users only ever see their SHA-256 hash.
+pub fn session_id(session_key: &str) -> String {
+    hash_token(session_key)
+}
+
+/// How long a session row stays valid, from `SESSION_MAX_AGE` in seconds.
+pub fn max_age() -> Duration {
+    let secs = env::var("SESSION_MAX_AGE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(7 * 24 * 60 * 60);
+    Duration::seconds(secs)
+}
+
+/// Sessions created before this instant are expired.
+pub fn expiry_cutoff() -> NaiveDateTime {
+    Utc::now().naive_utc() - max_age()
+}
+
+/// Persist a new session row for `user_id` started from `client` and return its key.
+///
+/// Expired rows are purged on the way.
+pub fn create_session(
+    conn: &MysqlConnection,
+    user_id: i32,
+    client: &ClientInfo,
+) -> Result<String, AuthError> {
+    insert_session(conn, user_id, client, false)
+}
+
+/// Like `create_session`, but the session logs no one in until the second
+/// login step of `two_factor` completes it.
+pub fn create_pending_session(
+    conn: &MysqlConnection,
+    user_id: i32,
+    client: &ClientInfo,
+) -> Result<String, AuthError> {
+    insert_session(conn, user_id, client, true)
+}
+
+fn insert_session(
+    conn: &MysqlConnection,
+    user_id: i32,
+    client: &ClientInfo,
+    pending_2fa: bool,
+) -> Result<String, AuthError> {
+    delete_sessions_before(conn, expiry_cutoff())?;
+    let key = generate_session_key();
+    let item = NewUserSession {
+        user_agent: client.user_agent.clone(),
+        ip_address: client.ip_address.clone(),
+        pending_2fa,
+        ..NewUserSession::new(key.clone(), user_id)
+    };
+    create_user_session(conn, &item)?;
+    Ok(key)
+}
+
+/// Put `session_key` into the cookie session, renewing it to avoid fixation.
+/// The CSRF token is dropped as well, so the next page issues a fresh one.
+pub fn remember(session: &Session, session_key: &str) -> Result<(), Error> {
+    session.renew();
+    session.remove(CSRF_KEY);
+    session.set(SESSION_KEY, session_key)
+}
+
+/// Put the key of a pending session into the cookie session, where
+/// `SessionAuth` won't look for it.
+pub fn remember_pending(session: &Session, pending_key: &str) -> Result<(), Error> {
+    session.renew();
+    session.remove(SESSION_KEY);
+    session.set(PENDING_SESSION_KEY, pending_key)
+}
+
+/// Middleware resolving the session key in the cookie to a `CurrentSession`.
+///
+/// Must be wrapped inside `CookieSession`:
+///     App::new().wrap(SessionAuth).wrap(CookieSession::signed(&key))
+pub struct SessionAuth;
+
+impl<S, B> Transform<S> for SessionAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SessionAuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct SessionAuthMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for SessionAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let session = req.get_session();
+            let bearer = bearer_token(&req);
+            let from_cookie = bearer.is_none();
+            let key = bearer.or_else(|| session.get::<String>(SESSION_KEY).unwrap_or(None));
+            let pool = req.app_data::<web::Data<DbPool>>().cloned();
+
+            if let (Some(key), Some(pool)) = (key, pool) {
+                match resolve(pool, key).await {
+                    Ok(current) => {
+                        req.extensions_mut().insert(current);
+                    }
+                    Err(BlockingError::Error(AuthError::UserNotFound)) if from_cookie => {
+                        session.remove(SESSION_KEY)
+                    }
+                    Err(BlockingError::Error(AuthError::UserNotFound)) => {}
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Session key sent as `Authorization: Bearer <session key>`, if any.
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
+/// Look up the unexpired session row for `session_key`, its user and their
+/// roles, and record that the session was seen.
+///
+/// Fails with `AuthError::UserNotFound` if the session is unknown or expired.
+async fn resolve(
+    pool: web::Data<DbPool>,
+    session_key: String,
+) -> Result<CurrentSession, BlockingError<AuthError>> {
+    web::block(move || {
+        let conn = pool
+            .get()
+            .map_err(|e| AuthError::DatabaseError(e.to_string()))?;
+        let usr =
+            get_user_by_session_key(&conn, &session_key, expiry_cutoff()).map_err(|e| match e {
+                DieselError::NotFound => AuthError::UserNotFound,
+                e => AuthError::from(e),
+            })?;
+        let now = Utc::now().naive_utc();
+        touch_user_session(&conn, &session_key, now, now - Duration::minutes(1))?;
+        Ok(CurrentSession {
+            session_key,
+            roles: get_user_roles(&conn, usr.id)?,
+            permissions: get_user_permissions(&conn, usr.id)?,
+            email_verified: !usr.needs_email_verification(),
+            two_factor: two_factor::is_enabled(&conn, usr.id)?,
+            user: UserResponse {
+                id: usr.id,
+                username: usr.username,
+                created_at: usr.created_at,
+            },
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_headers_ignored_by_default() {
+        use actix_web::test::TestRequest;
+        let req = TestRequest::default()
+            .header("x-forwarded-for", "198.51.100.7")
+            .peer_addr("192.0.2.1:4242".parse().unwrap())
+            .to_http_request();
+        let ip = client_ip(&req.connection_info(), req.peer_addr());
+        assert_eq!(ip.as_deref(), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn session_ids_hide_keys() {
+        let key = generate_session_key();
+        let id = session_id(&key);
+        assert_eq!(id.len(), 64);
+        assert_ne!(id, key);
+        assert_eq!(session_id(&key), id);
+    }
+
+    #[test]
+    fn session_keys_are_random_hex() {
+        let a = generate_session_key();
+        let b = generate_session_key();
+        assert_eq!(a.len(), 64);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+}
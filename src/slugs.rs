@@ -0,0 +1,64 @@
+//! URL slugs for posts and tags.
+
+/// Lowercase `text`, keep ASCII letters and digits and join the words with `-`.
+///
+/// Example:
+///     assert_eq!(slugify("Hello, World! 2021"), "hello-world-2021");
+pub fn slugify(text: &str) -> String {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .take(200)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_owned()
+}
+
+/// Returns `base`, or `base-2`, `base-3`, ... whichever `taken` reports free first.
+/// Empty slugs fall back to `fallback`.
+pub fn unique_slug<F, E>(base: &str, fallback: &str, mut taken: F) -> Result<String, E>
+where
+    F: FnMut(&str) -> Result<bool, E>,
+{
+    let base = if base.is_empty() { fallback } else { base };
+    if !taken(base)? {
+        return Ok(base.to_owned());
+    }
+    for n in 2.. {
+        let slug = format!("{}-{}", base, n);
+        if !taken(&slug)? {
+            return Ok(slug);
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_slugified() {
+        assert_eq!(slugify("Hello, World! 2021"), "hello-world-2021");
+        assert_eq!(slugify("  Rust --- and   Diesel "), "rust-and-diesel");
+        assert_eq!(slugify("¿Qué?"), "qu");
+        assert_eq!(slugify("!!!"), "");
+    }
+
+    #[test]
+    fn taken_slug_numbered() {
+        let taken = |s: &str| Ok::<_, ()>(["hello", "hello-2"].contains(&s));
+        assert_eq!(
+            unique_slug("hello", "post", taken),
+            Ok(String::from("hello-3"))
+        );
+        assert_eq!(
+            unique_slug("world", "post", taken),
+            Ok(String::from("world"))
+        );
+        assert_eq!(unique_slug("", "post", taken), Ok(String::from("post")));
+    }
+}
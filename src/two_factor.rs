@@ -0,0 +1,296 @@
+//! Optional two-factor authentication with TOTP codes, see `totp`.
+//!
+//! Users set it up on `/account/2fa`: `enroll` stores a new secret and hands
+//! out its `otpauth://` URI for the authenticator app, and `confirm` turns it
+//! on once the app produced a valid code, returning ten one-time recovery
+//! codes.  Only the codes' SHA-256 hashes are stored.
+//!
+//! With two-factor authentication on, the right password only starts a
+//! pending session (`sessions.pending_2fa`), which logs no one in.
+//! `complete_login` replaces it by a full session once a code or recovery
+//! code is entered within `TWO_FACTOR_LOGIN_TTL` seconds (default: five
+//! minutes).  Wrong codes count towards the lockout like wrong passwords.
+//!
+//! Users with one of the roles in `TWO_FACTOR_REQUIRED_ROLES` (comma separated,
+//! default: `admin`) must set it up; until they do `roles::RoleGuard` turns
+//! them away from guarded routes, and they can't turn it off.
+
+use super::auth::ensure_active;
+use super::db::{
+    create_user_totp, end_other_user_sessions, end_user_session, get_pending_user_session,
+    get_user_by_id, get_user_totp, lock_user_totp, remove_user_totp, replace_recovery_codes,
+    use_recovery_code, use_user_totp,
+};
+use super::errors::TwoFactorError;
+use super::lockout::{self, LockoutPolicy};
+use super::models::{NewRecoveryCode, NewUserTotp};
+use super::password_reset::hash_token;
+use super::roles::ADMIN;
+use super::sessions::{create_session, ClientInfo, CurrentSession};
+use super::totp;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{result::Error as DieselError, Connection, MysqlConnection, OptionalExtension};
+use std::env;
+
+/// Recovery codes handed out at a time.
+pub const RECOVERY_CODES: usize = 10;
+
+/// A secret to set up in an authenticator app.
+#[derive(Debug, Clone)]
+pub struct Enrollment {
+    /// Base32 encoded, for typing in by hand.
+    pub secret: String,
+    /// The `otpauth://` URI, for scanning as QR code.
+    pub uri: String,
+}
+
+/// Name of the site in authenticator apps, from `TOTP_ISSUER`.
+pub fn issuer() -> String {
+    env::var("TOTP_ISSUER").unwrap_or_else(|_| String::from("blog-user"))
+}
+
+/// How long the second login step may take, from `TWO_FACTOR_LOGIN_TTL` in seconds.
+pub fn login_ttl() -> Duration {
+    let secs = env::var("TWO_FACTOR_LOGIN_TTL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(5 * 60);
+    Duration::seconds(secs)
+}
+
+/// Roles whose users must set up two-factor authentication, from
+/// `TWO_FACTOR_REQUIRED_ROLES`.
+pub fn required_roles() -> Vec<String> {
+    env::var("TWO_FACTOR_REQUIRED_ROLES")
+        .unwrap_or_else(|_| ADMIN.to_string())
+        .split(',')
+        .map(|role| role.trim().to_string())
+        .filter(|role| !role.is_empty())
+        .collect()
+}
+
+/// Returns `true` if any of `roles` requires two-factor authentication.
+pub fn is_required(roles: &[String]) -> bool {
+    let required = required_roles();
+    roles.iter().any(|role| required.contains(role))
+}
+
+/// Returns `true` if user with given `user_id` has two-factor authentication on.
+pub fn is_enabled(conn: &MysqlConnection, user_id: i32) -> Result<bool, DieselError> {
+    Ok(get_user_totp(conn, user_id)?.is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+/// Store a new secret for user with given `user_id`, shown in apps as
+/// `account`.  It has no effect until confirmed.
+pub fn enroll(
+    conn: &MysqlConnection,
+    user_id: i32,
+    account: &str,
+) -> Result<Enrollment, TwoFactorError> {
+    conn.transaction(|| {
+        if lock_user_totp(conn, user_id)?.is_some_and(|totp| totp.confirmed_at.is_some()) {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+        let secret = totp::generate_secret();
+        let encoded = totp::base32_encode(&secret);
+        let item = NewUserTotp {
+            user_id,
+            secret: &encoded,
+        };
+        create_user_totp(conn, item)?;
+        Ok(Enrollment {
+            uri: totp::otpauth_uri(&issuer(), account, &secret),
+            secret: encoded,
+        })
+    })
+}
+
+/// The enrollment of user with given `user_id` waiting for confirmation, if any.
+pub fn pending_enrollment(
+    conn: &MysqlConnection,
+    user_id: i32,
+    account: &str,
+) -> Result<Option<Enrollment>, TwoFactorError> {
+    let pending = get_user_totp(conn, user_id)?.filter(|totp| totp.confirmed_at.is_none());
+    Ok(pending.and_then(|totp| {
+        let secret = totp::base32_decode(&totp.secret)?;
+        Some(Enrollment {
+            uri: totp::otpauth_uri(&issuer(), account, &secret),
+            secret: totp.secret,
+        })
+    }))
+}
+
+/// `code` as stored: lowercased, without dashes and spaces.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// A new random recovery code such as `k3jf9-2mxq7`.
+fn generate_recovery_code() -> String {
+    let code = totp::base32_encode(&totp::generate_secret()[..7]).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+/// Replace the recovery codes of user with given `user_id` by new ones and
+/// return them.
+fn issue_recovery_codes(
+    conn: &MysqlConnection,
+    user_id: i32,
+) -> Result<Vec<String>, TwoFactorError> {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect();
+    let items: Vec<NewRecoveryCode> = hashes
+        .iter()
+        .map(|hash| NewRecoveryCode {
+            code_hash: hash,
+            user_id,
+        })
+        .collect();
+    replace_recovery_codes(conn, user_id, &items)?;
+    Ok(codes)
+}
+
+/// Turn two-factor authentication on for `current` once `code` shows their
+/// app has the secret from `enroll`.  Their other sessions are ended, and the
+/// recovery codes returned.
+pub fn confirm(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    code: &str,
+    now: NaiveDateTime,
+) -> Result<Vec<String>, TwoFactorError> {
+    let user_id = current.user.id;
+    conn.transaction(|| {
+        let pending = lock_user_totp(conn, user_id)?.ok_or(TwoFactorError::NotEnabled)?;
+        if pending.confirmed_at.is_some() {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+        let secret = totp::base32_decode(&pending.secret).ok_or(TwoFactorError::NotEnabled)?;
+        let step = totp::verify(&secret, code, now.timestamp(), None)
+            .ok_or(TwoFactorError::InvalidCode)?;
+        use_user_totp(conn, user_id, step, now)?;
+        end_other_user_sessions(conn, user_id, &current.session_key)?;
+        issue_recovery_codes(conn, user_id)
+    })
+}
+
+/// Check `code`, from the app or one of the recovery codes, for user with
+/// given `user_id`, using it up.  Must run in a transaction.
+fn check_code(
+    conn: &MysqlConnection,
+    user_id: i32,
+    code: &str,
+    now: NaiveDateTime,
+) -> Result<(), TwoFactorError> {
+    let enabled = lock_user_totp(conn, user_id)?.filter(|totp| totp.confirmed_at.is_some());
+    let enabled = enabled.ok_or(TwoFactorError::NotEnabled)?;
+    let secret = totp::base32_decode(&enabled.secret).ok_or(TwoFactorError::NotEnabled)?;
+    if let Some(step) = totp::verify(&secret, code, now.timestamp(), enabled.last_used_step) {
+        use_user_totp(conn, user_id, step, now)?;
+        return Ok(());
+    }
+    let hash = hash_token(&normalize_recovery_code(code));
+    if use_recovery_code(conn, user_id, &hash)? == 1 {
+        return Ok(());
+    }
+    Err(TwoFactorError::InvalidCode)
+}
+
+/// Replace the recovery codes of `current`, given a valid `code`, and return
+/// the new ones.
+pub fn regenerate_recovery_codes(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    code: &str,
+    now: NaiveDateTime,
+) -> Result<Vec<String>, TwoFactorError> {
+    conn.transaction(|| {
+        check_code(conn, current.user.id, code, now)?;
+        issue_recovery_codes(conn, current.user.id)
+    })
+}
+
+/// Turn two-factor authentication off for `current`, given a valid `code`.
+/// Fails with `Required` if one of their roles requires it.
+pub fn disable(
+    conn: &MysqlConnection,
+    current: &CurrentSession,
+    code: &str,
+    now: NaiveDateTime,
+) -> Result<(), TwoFactorError> {
+    if is_required(&current.roles) {
+        return Err(TwoFactorError::Required);
+    }
+    conn.transaction(|| {
+        check_code(conn, current.user.id, code, now)?;
+        remove_user_totp(conn, current.user.id)?;
+        Ok(())
+    })
+}
+
+/// The second login step: replace the pending session `pending_key` by a
+/// full session started from `client` if `code` is valid, and return its key.
+///
+/// Fails with `LoginExpired` if there is no such pending session younger
+/// than `login_ttl`.  Wrong codes are counted by `lockout`.
+pub fn complete_login(
+    conn: &MysqlConnection,
+    pending_key: &str,
+    code: &str,
+    client: &ClientInfo,
+    now: NaiveDateTime,
+) -> Result<String, TwoFactorError> {
+    let pending = get_pending_user_session(conn, pending_key, now - login_ttl())
+        .optional()?
+        .ok_or(TwoFactorError::LoginExpired)?;
+    let user = get_user_by_id(conn, pending.user_id)?;
+    let ip = client.ip_address.as_deref();
+    lockout::check(conn, &user.username, ip, now)?;
+    ensure_active(&user)?;
+    match conn.transaction(|| check_code(conn, user.id, code, now)) {
+        Err(TwoFactorError::InvalidCode) => {
+            lockout::record_failure(conn, &LockoutPolicy::from_env(), &user.username, ip, now)?;
+            return Err(TwoFactorError::InvalidCode);
+        }
+        checked => checked?,
+    }
+    lockout::reset(conn, &user.username, ip)?;
+    end_user_session(conn, pending_key)?;
+    Ok(create_session(conn, user.id, client)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_codes_normalized() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(&code[5..6], "-");
+        assert_eq!(normalize_recovery_code(&code).len(), 10);
+        assert_eq!(normalize_recovery_code(" K3JF9-2mxq7 "), "k3jf92mxq7");
+        assert_ne!(generate_recovery_code(), code);
+    }
+
+    #[test]
+    fn admins_require_two_factor() {
+        assert!(is_required(&[
+            String::from("reader"),
+            String::from("admin")
+        ]));
+        assert!(!is_required(&[String::from("editor")]));
+        assert!(!is_required(&[]));
+    }
+}
@@ -10,6 +10,12 @@ pub struct BaseUser {
     pub id: i32,
     pub username: String,
     pub password: String,
+    /// Names of the user's roles, filled in by `Auth::authenticate`.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Names of the permissions granted by those roles.
+    #[serde(default)]
+    pub permissions: Vec<String>,
 }
 
 impl BaseUser {
@@ -46,12 +52,23 @@ impl Default for BaseUser {
             id: -1,
             username: String::with_capacity(255),
             password: String::with_capacity(255),
+            roles: Vec::new(),
+            permissions: Vec::new(),
         }
     }
 }
 
+/// The form email addresses are stored and looked up in: trimmed and
+/// lowercased, so they are unique regardless of case.
+///
+/// Example:
+///     assert_eq!(normalize_email(" Bender@Example.COM"), "bender@example.com");
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
 /// Response for `GET /usrs/{id}`
-#[derive(Debug, Serialize, Deserialize, QueryableByName, Clone)]
+#[derive(Debug, Serialize, Deserialize, Queryable, QueryableByName, Clone)]
 #[table_name = "users"]
 pub struct UserResponse {
     #[sql_type = "Integer"]
@@ -87,3 +104,164 @@ impl Default for UserResponse {
         }
     }
 }
+
+/// Column to sort `GET /users` by.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    #[default]
+    CreatedAt,
+    Username,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query string of `GET /users`, e.g. `?prefix=ben&sort=username&limit=20&offset=40`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct UserQuery {
+    /// Only users whose username starts with this.
+    pub prefix: Option<String>,
+    /// Only users created at or after this time.
+    pub created_after: Option<NaiveDateTime>,
+    /// Only users created before this time.
+    pub created_before: Option<NaiveDateTime>,
+    pub sort: UserSort,
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl UserQuery {
+    pub const DEFAULT_LIMIT: i64 = 50;
+    pub const MAX_LIMIT: i64 = 200;
+
+    /// Page size, between 1 and `MAX_LIMIT`.
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+
+    /// Number of users to skip, at least 0.
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// `LIKE` pattern matching usernames that start with `prefix`, with the
+    /// wildcards in `prefix` escaped.
+    pub fn like_pattern(&self) -> Option<String> {
+        let prefix = self.prefix.as_deref().filter(|p| !p.is_empty())?;
+        let mut pattern = String::with_capacity(prefix.len() + 1);
+        for c in prefix.chars() {
+            if c == '%' || c == '_' || c == '\\' {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('%');
+        Some(pattern)
+    }
+}
+
+/// Response for `GET /users`: one page of users plus what's needed to fetch
+/// the next one.
+#[derive(Serialize, Debug)]
+pub struct UserPage {
+    pub items: Vec<UserResponse>,
+    /// Number of users matching the filters, across all pages.
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    /// `offset` of the next page, or `None` on the last page.
+    pub next_offset: Option<i64>,
+}
+
+impl UserPage {
+    pub fn new(items: Vec<UserResponse>, total: i64, query: &UserQuery) -> Self {
+        let (limit, offset) = (query.limit(), query.offset());
+        let next_offset = Some(offset + items.len() as i64).filter(|next| *next < total);
+        UserPage {
+            items,
+            total,
+            limit,
+            offset,
+            next_offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_limits_clamped() {
+        let query = UserQuery {
+            limit: Some(10_000),
+            offset: Some(-5),
+            ..UserQuery::default()
+        };
+        assert_eq!(query.limit(), UserQuery::MAX_LIMIT);
+        assert_eq!(query.offset(), 0);
+        assert_eq!(UserQuery::default().limit(), UserQuery::DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn prefix_wildcards_escaped() {
+        let query = UserQuery {
+            prefix: Some(String::from("be_n%")),
+            ..UserQuery::default()
+        };
+        assert_eq!(query.like_pattern().unwrap(), "be\\_n\\%%");
+        assert_eq!(UserQuery::default().like_pattern(), None);
+    }
+
+    #[test]
+    fn next_offset_stops_at_total() {
+        let query = UserQuery {
+            limit: Some(2),
+            offset: Some(2),
+            ..UserQuery::default()
+        };
+        let items = vec![UserResponse::new(), UserResponse::new()];
+        assert_eq!(UserPage::new(items.clone(), 5, &query).next_offset, Some(4));
+        assert_eq!(UserPage::new(items, 4, &query).next_offset, None);
+    }
+
+    #[test]
+    fn signup_emails_checked_and_normalized() {
+        use crate::forms::{is_email, UserSignup};
+        assert_eq!(
+            normalize_email(" Bender@Example.COM "),
+            "bender@example.com"
+        );
+        assert!(is_email("bender@example.com"));
+        for email in &[
+            "",
+            "bender",
+            "@example.com",
+            "bender@localhost",
+            "a@b@c.com",
+            "be nder@x.io",
+        ] {
+            assert!(!is_email(email), "{:?} accepted", email);
+        }
+
+        let mut usr = UserSignup {
+            username: String::from("bender"),
+            email: String::from("Bender@Example.com"),
+            password: String::from("password123"),
+            password_confirm: String::from("password123"),
+        };
+        assert!(usr.clean_identity().is_ok());
+        usr.username = String::from("bender@example.com");
+        assert!(usr.clean_identity().is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! The parts of WebAuthn (https://www.w3.org/TR/webauthn-2/) needed to log in
+//! with passkeys, see `passkeys`.
+//!
+//! The browser gets options with a random challenge, has an authenticator
+//! create a credential or sign the challenge, and sends back the response.
+//! `verify_registration` and `verify_assertion` check those responses against
+//! the relying party, that is this site.  Both require user verification, so
+//! a passkey counts as password and second factor at once.
+//!
+//! Attestation is not asked for and attestation statements are ignored, so
+//! any authenticator is accepted.  Keys must be ES256 or RS256, which covers
+//! the common platform and security key authenticators.
+//!
+//! Binary fields travel base64url encoded without padding, as produced by
+//! `PublicKeyCredential.toJSON()`.
+
+use super::errors::PasskeyError;
+
+use rand::{rngs::OsRng, RngCore};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// COSE algorithm of ECDSA with P-256 and SHA-256.
+pub const ES256: i128 = -7;
+/// COSE algorithm of RSASSA-PKCS1-v1_5 with SHA-256.
+pub const RS256: i128 = -257;
+
+/// Authenticator data flag: the user was present.
+const FLAG_UP: u8 = 0x01;
+/// Authenticator data flag: the user was verified, e.g. by PIN or fingerprint.
+const FLAG_UV: u8 = 0x04;
+/// Authenticator data flag: attested credential data follows.
+const FLAG_AT: u8 = 0x40;
+
+/// This site as WebAuthn relying party.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelyingParty {
+    /// Domain the credentials are scoped to.
+    pub id: String,
+    /// Shown by the browser when creating a credential.
+    pub name: String,
+    /// Origin the browser reports, such as `https://blog.example.com`.
+    pub origin: String,
+}
+
+impl RelyingParty {
+    /// The relying party for pages served from `origin`, its id being the
+    /// origin's host.
+    ///
+    /// Example:
+    ///     let rp = RelyingParty::new("https://blog.example.com:8443", "Blog");
+    ///     assert_eq!(rp.id, "blog.example.com");
+    pub fn new(origin: &str, name: &str) -> Self {
+        let origin = origin.trim_end_matches('/');
+        let host = origin.splitn(2, "://").last().unwrap_or_default();
+        let host = host.split([':', '/']).next().unwrap_or_default();
+        RelyingParty {
+            id: host.to_string(),
+            name: name.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+}
+
+/// `data` base64url encoded without padding.
+pub fn base64url_encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decode base64url `text`, with or without padding.
+pub fn base64url_decode(text: &str) -> Result<Vec<u8>, PasskeyError> {
+    base64::decode_config(text.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| PasskeyError::InvalidResponse(String::from("invalid base64url")))
+}
+
+/// A new random challenge, base64url encoded.
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    base64url_encode(&challenge)
+}
+
+/// The `PublicKeyCredentialCreationOptions` asking the browser to create a
+/// passkey for the account `user_handle` called `username`, with `challenge`
+/// and valid for `timeout_ms`.  Authenticators already holding one of
+/// `exclude` won't create another.
+pub fn creation_options(
+    rp: &RelyingParty,
+    challenge: &str,
+    user_handle: &[u8],
+    username: &str,
+    exclude: &[Vec<u8>],
+    timeout_ms: i64,
+) -> serde_json::Value {
+    let exclude: Vec<_> = exclude
+        .iter()
+        .map(|id| json!({ "type": "public-key", "id": base64url_encode(id) }))
+        .collect();
+    json!({
+        "challenge": challenge,
+        "rp": { "id": rp.id, "name": rp.name },
+        "user": {
+            "id": base64url_encode(user_handle),
+            "name": username,
+            "displayName": username,
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": ES256 as i64 },
+            { "type": "public-key", "alg": RS256 as i64 },
+        ],
+        "timeout": timeout_ms,
+        "excludeCredentials": exclude,
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "requireResidentKey": true,
+            "userVerification": "required",
+        },
+        "attestation": "none",
+    })
+}
+
+/// The `PublicKeyCredentialRequestOptions` asking the browser to sign
+/// `challenge` with any passkey of this site, valid for `timeout_ms`.
+pub fn request_options(rp: &RelyingParty, challenge: &str, timeout_ms: i64) -> serde_json::Value {
+    json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "timeout": timeout_ms,
+        "allowCredentials": [],
+        "userVerification": "required",
+    })
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.create()`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationResponse {
+    #[serde(rename = "rawId")]
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.get()`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "rawId")]
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AuthenticatorAssertion,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatorAssertion {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle", default)]
+    pub user_handle: Option<String>,
+}
+
+/// A passkey that passed `verify_registration`, ready to be stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedCredential {
+    pub credential_id: Vec<u8>,
+    /// COSE encoded.
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// The client data the browser collected and the authenticator signed.
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+/// The challenge `client_data_json`, base64url encoded as sent, claims to
+/// answer.  It must still be checked to have been issued.
+pub fn client_challenge(client_data_json: &str) -> Result<String, PasskeyError> {
+    Ok(parse_client_data(&base64url_decode(client_data_json)?)?.challenge)
+}
+
+fn parse_client_data(raw: &[u8]) -> Result<ClientData, PasskeyError> {
+    serde_json::from_slice(raw).map_err(|e| PasskeyError::InvalidResponse(e.to_string()))
+}
+
+/// Check the decoded client data `raw` is of `type_`, answers `challenge`
+/// and comes from a page of `rp`.
+fn check_client_data(
+    rp: &RelyingParty,
+    raw: &[u8],
+    type_: &str,
+    challenge: &str,
+) -> Result<(), PasskeyError> {
+    let client_data = parse_client_data(raw)?;
+    if client_data.type_ != type_ {
+        return Err(PasskeyError::InvalidResponse(format!(
+            "expected type {}",
+            type_
+        )));
+    }
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return Err(PasskeyError::InvalidChallenge);
+    }
+    if client_data.origin != rp.origin || client_data.cross_origin {
+        return Err(PasskeyError::InvalidOrigin);
+    }
+    Ok(())
+}
+
+/// Authenticator data, see WebAuthn section 6.1.
+#[derive(Debug)]
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and COSE key, present when registering.
+    attested: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<Self, PasskeyError> {
+        let malformed = || PasskeyError::InvalidResponse(String::from("short authenticator data"));
+        if data.len() < 37 {
+            return Err(malformed());
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+        let mut attested = None;
+        if flags & FLAG_AT != 0 {
+            // 16 bytes AAGUID, then the length of the credential id.
+            let rest = data.get(37 + 16..).ok_or_else(malformed)?;
+            let len = rest.get(..2).ok_or_else(malformed)?;
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            let credential_id = rest.get(2..2 + len).ok_or_else(malformed)?.to_vec();
+            let key = &rest[2 + len..];
+            // Extensions may follow the key, so take only what the key spans.
+            let mut de = serde_cbor::Deserializer::from_slice(key);
+            Value::deserialize(&mut de)
+                .map_err(|e| PasskeyError::InvalidResponse(e.to_string()))?;
+            attested = Some((credential_id, key[..de.byte_offset()].to_vec()));
+        }
+        Ok(AuthenticatorData {
+            rp_id_hash: data[..32].to_vec(),
+            flags,
+            sign_count,
+            attested,
+        })
+    }
+
+    /// Check the data is scoped to `rp` and the user was verified.
+    fn check(&self, rp: &RelyingParty) -> Result<(), PasskeyError> {
+        if self.rp_id_hash[..] != Sha256::digest(rp.id.as_bytes())[..] {
+            return Err(PasskeyError::InvalidOrigin);
+        }
+        if self.flags & FLAG_UP == 0 || self.flags & FLAG_UV == 0 {
+            return Err(PasskeyError::UserNotVerified);
+        }
+        Ok(())
+    }
+}
+
+/// A public key decoded from COSE (RFC 8152 section 13).
+#[derive(Debug)]
+enum PublicKey {
+    /// Uncompressed P-256 point.
+    Es256(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl PublicKey {
+    fn from_cose(cose: &[u8]) -> Result<Self, PasskeyError> {
+        let map = match serde_cbor::from_slice(cose) {
+            Ok(Value::Map(map)) => map,
+            _ => return Err(PasskeyError::InvalidResponse(String::from("invalid key"))),
+        };
+        let int = |key: i128| match map.get(&Value::Integer(key)) {
+            Some(Value::Integer(value)) => Some(*value),
+            _ => None,
+        };
+        let bytes = |key: i128| match map.get(&Value::Integer(key)) {
+            Some(Value::Bytes(value)) => Ok(value.clone()),
+            _ => Err(PasskeyError::InvalidResponse(String::from("invalid key"))),
+        };
+        // kty 2 is EC2 with crv 1 for P-256, kty 3 RSA.
+        match (int(1), int(3)) {
+            (Some(2), Some(ES256)) if int(-1) == Some(1) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(PasskeyError::InvalidResponse(String::from("invalid key")));
+                }
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+                Ok(PublicKey::Es256(point))
+            }
+            (Some(3), Some(RS256)) => Ok(PublicKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            _ => Err(PasskeyError::UnsupportedAlgorithm),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), PasskeyError> {
+        let verified = match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+            }
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+                &RSA_PKCS1_2048_8192_SHA256,
+                message,
+                signature,
+            ),
+        };
+        verified.map_err(|_| PasskeyError::InvalidSignature)
+    }
+}
+
+/// Check the response to `creation_options` with `challenge` and return the
+/// new credential.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential: &RegistrationResponse,
+) -> Result<VerifiedCredential, PasskeyError> {
+    if credential.type_ != "public-key" {
+        return Err(PasskeyError::InvalidResponse(String::from(
+            "expected type public-key",
+        )));
+    }
+    let client_data = base64url_decode(&credential.response.client_data_json)?;
+    check_client_data(rp, &client_data, "webauthn.create", challenge)?;
+
+    let attestation = base64url_decode(&credential.response.attestation_object)?;
+    let attestation: BTreeMap<Value, Value> = serde_cbor::from_slice(&attestation)
+        .map_err(|e| PasskeyError::InvalidResponse(e.to_string()))?;
+    let auth_data = match attestation.get(&Value::Text(String::from("authData"))) {
+        Some(Value::Bytes(data)) => AuthenticatorData::parse(data)?,
+        _ => return Err(PasskeyError::InvalidResponse(String::from("no authData"))),
+    };
+    auth_data.check(rp)?;
+    let (credential_id, public_key) = auth_data
+        .attested
+        .ok_or_else(|| PasskeyError::InvalidResponse(String::from("no credential")))?;
+    if credential_id != base64url_decode(&credential.raw_id)? {
+        return Err(PasskeyError::InvalidResponse(String::from(
+            "credential id mismatch",
+        )));
+    }
+    PublicKey::from_cose(&public_key)?;
+    Ok(VerifiedCredential {
+        credential_id,
+        public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Check the response to `request_options` with `challenge` was signed with
+/// `public_key`, and return the authenticator's new signature counter.
+///
+/// Counters that don't move past `stored_count` point to a cloned
+/// authenticator and are refused, unless both are zero: many passkeys
+/// synced between devices always report zero.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential: &AssertionResponse,
+    public_key: &[u8],
+    stored_count: u32,
+) -> Result<u32, PasskeyError> {
+    if credential.type_ != "public-key" {
+        return Err(PasskeyError::InvalidResponse(String::from(
+            "expected type public-key",
+        )));
+    }
+    let client_data = base64url_decode(&credential.response.client_data_json)?;
+    check_client_data(rp, &client_data, "webauthn.get", challenge)?;
+
+    let raw_auth_data = base64url_decode(&credential.response.authenticator_data)?;
+    let auth_data = AuthenticatorData::parse(&raw_auth_data)?;
+    auth_data.check(rp)?;
+
+    let mut signed = raw_auth_data;
+    signed.extend(Sha256::digest(&client_data));
+    let signature = base64url_decode(&credential.response.signature)?;
+    PublicKey::from_cose(public_key)?.verify(&signed, &signature)?;
+
+    let count = auth_data.sign_count;
+    if (count != 0 || stored_count != 0) && count <= stored_count {
+        return Err(PasskeyError::CounterRegressed);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relying_party_derived_from_origin() {
+        let rp = RelyingParty::new("https://blog.example.com:8443/", "Blog");
+        assert_eq!(rp.id, "blog.example.com");
+        assert_eq!(rp.origin, "https://blog.example.com:8443");
+        assert_eq!(
+            RelyingParty::new("http://127.0.0.1:8000", "Blog").id,
+            "127.0.0.1"
+        );
+
+        let challenge = generate_challenge();
+        assert_eq!(challenge.len(), 43);
+        assert_eq!(base64url_decode(&challenge).unwrap().len(), 32);
+        assert_eq!(base64url_decode("AQI=").unwrap(), vec![1, 2]);
+        assert!(base64url_decode("not base64!").is_err());
+    }
+
+    #[test]
+    fn truncated_authenticator_data_refused() {
+        let mut data = vec![0; 37 + 16 + 1];
+        data[32] = FLAG_UP | FLAG_UV | FLAG_AT;
+        assert!(AuthenticatorData::parse(&data).is_err());
+        // Credential id length 4, but only 2 bytes of it.
+        data.extend(&[4, 1, 2]);
+        assert!(AuthenticatorData::parse(&data).is_err());
+        // No COSE key after the credential id.
+        data.extend(&[3, 4]);
+        assert!(AuthenticatorData::parse(&data).is_err());
+    }
+
+    #[test]
+    fn keys_and_data_checked() {
+        assert!(AuthenticatorData::parse(&[0; 36]).is_err());
+        let data = AuthenticatorData::parse(&[0; 37]).unwrap();
+        assert!(matches!(
+            data.check(&RelyingParty::new("https://example.com", "Blog")),
+            Err(PasskeyError::InvalidOrigin)
+        ));
+
+        let mut ed25519 = BTreeMap::new();
+        ed25519.insert(Value::Integer(1), Value::Integer(1));
+        ed25519.insert(Value::Integer(3), Value::Integer(-8));
+        let cose = serde_cbor::to_vec(&Value::Map(ed25519)).unwrap();
+        assert!(matches!(
+            PublicKey::from_cose(&cose),
+            Err(PasskeyError::UnsupportedAlgorithm)
+        ));
+    }
+}
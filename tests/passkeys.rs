@@ -0,0 +1,229 @@
+//! Registering and logging in with a passkey held by a software authenticator.
+
+#[macro_use]
+extern crate serde_json;
+
+use blog_user::errors::PasskeyError;
+use blog_user::webauthn::{
+    base64url_encode, generate_challenge, verify_assertion, verify_registration, AssertionResponse,
+    RegistrationResponse, RelyingParty,
+};
+
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+const ORIGIN: &str = "https://blog.example.com";
+
+/// User present.
+const UP: u8 = 0x01;
+/// User verified.
+const UV: u8 = 0x04;
+/// Attested credential data included.
+const AT: u8 = 0x40;
+
+fn relying_party() -> RelyingParty {
+    RelyingParty::new(ORIGIN, "Blog")
+}
+
+/// An authenticator with a single P-256 key, as a platform passkey would be.
+struct SoftAuthenticator {
+    key: EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    rp_id: String,
+    origin: String,
+    flags: u8,
+    sign_count: u32,
+}
+
+impl SoftAuthenticator {
+    fn new() -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        SoftAuthenticator {
+            key,
+            credential_id: (0..16).collect(),
+            rp_id: relying_party().id,
+            origin: String::from(ORIGIN),
+            flags: UP | UV,
+            sign_count: 0,
+        }
+    }
+
+    /// The public key as COSE_Key.
+    fn cose_key(&self) -> Vec<u8> {
+        // Uncompressed point: 0x04 || x || y.
+        let point = self.key.public_key().as_ref();
+        let mut key = BTreeMap::new();
+        key.insert(Value::Integer(1), Value::Integer(2));
+        key.insert(Value::Integer(3), Value::Integer(-7));
+        key.insert(Value::Integer(-1), Value::Integer(1));
+        key.insert(Value::Integer(-2), Value::Bytes(point[1..33].to_vec()));
+        key.insert(Value::Integer(-3), Value::Bytes(point[33..].to_vec()));
+        serde_cbor::to_vec(&Value::Map(key)).unwrap()
+    }
+
+    fn authenticator_data(&self, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        let mut flags = self.flags;
+        if attested {
+            flags |= AT;
+        }
+        data.push(flags);
+        data.extend(&self.sign_count.to_be_bytes());
+        if attested {
+            data.extend(&[0; 16]);
+            data.extend(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend(&self.credential_id);
+            data.extend(self.cose_key());
+        }
+        data
+    }
+
+    fn client_data(&self, type_: &str, challenge: &str) -> Vec<u8> {
+        json!({ "type": type_, "challenge": challenge, "origin": self.origin })
+            .to_string()
+            .into_bytes()
+    }
+
+    /// What `navigator.credentials.create()` resolves to.
+    fn create(&mut self, challenge: &str) -> RegistrationResponse {
+        let mut attestation = BTreeMap::new();
+        attestation.insert(
+            Value::Text(String::from("fmt")),
+            Value::Text(String::from("none")),
+        );
+        attestation.insert(
+            Value::Text(String::from("attStmt")),
+            Value::Map(BTreeMap::new()),
+        );
+        attestation.insert(
+            Value::Text(String::from("authData")),
+            Value::Bytes(self.authenticator_data(true)),
+        );
+        let attestation = serde_cbor::to_vec(&Value::Map(attestation)).unwrap();
+        serde_json::from_value(json!({
+            "rawId": base64url_encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": base64url_encode(&self.client_data("webauthn.create", challenge)),
+                "attestationObject": base64url_encode(&attestation),
+            },
+        }))
+        .unwrap()
+    }
+
+    /// What `navigator.credentials.get()` resolves to, counting the use.
+    fn get(&mut self, challenge: &str) -> AssertionResponse {
+        self.sign_count += 1;
+        let auth_data = self.authenticator_data(false);
+        let client_data = self.client_data("webauthn.get", challenge);
+        let mut signed = auth_data.clone();
+        signed.extend(Sha256::digest(&client_data));
+        let signature = self.key.sign(&SystemRandom::new(), &signed).unwrap();
+        serde_json::from_value(json!({
+            "rawId": base64url_encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": base64url_encode(&client_data),
+                "authenticatorData": base64url_encode(&auth_data),
+                "signature": base64url_encode(signature.as_ref()),
+                "userHandle": base64url_encode(&1i32.to_be_bytes()),
+            },
+        }))
+        .unwrap()
+    }
+}
+
+#[test]
+fn register_and_log_in() {
+    let rp = relying_party();
+    let mut authenticator = SoftAuthenticator::new();
+
+    let challenge = generate_challenge();
+    let credential =
+        verify_registration(&rp, &challenge, &authenticator.create(&challenge)).unwrap();
+    assert_eq!(credential.credential_id, authenticator.credential_id);
+    assert_eq!(credential.sign_count, 0);
+
+    let mut stored_count = credential.sign_count;
+    for expected in 1..=2 {
+        let challenge = generate_challenge();
+        let assertion = authenticator.get(&challenge);
+        stored_count = verify_assertion(
+            &rp,
+            &challenge,
+            &assertion,
+            &credential.public_key,
+            stored_count,
+        )
+        .unwrap();
+        assert_eq!(stored_count, expected);
+    }
+}
+
+#[test]
+fn cloned_authenticator_refused() {
+    let rp = relying_party();
+    let mut authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let credential =
+        verify_registration(&rp, &challenge, &authenticator.create(&challenge)).unwrap();
+
+    let challenge = generate_challenge();
+    let assertion = authenticator.get(&challenge);
+    let result = verify_assertion(&rp, &challenge, &assertion, &credential.public_key, 5);
+    assert!(matches!(result, Err(PasskeyError::CounterRegressed)));
+}
+
+#[test]
+fn wrong_challenge_or_site_refused() {
+    let rp = relying_party();
+    let mut authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let credential = authenticator.create(&challenge);
+    let result = verify_registration(&rp, &generate_challenge(), &credential);
+    assert!(matches!(result, Err(PasskeyError::InvalidChallenge)));
+
+    authenticator.origin = String::from("https://evil.example.com");
+    let result = verify_registration(&rp, &challenge, &authenticator.create(&challenge));
+    assert!(matches!(result, Err(PasskeyError::InvalidOrigin)));
+
+    authenticator.origin = String::from(ORIGIN);
+    authenticator.rp_id = String::from("evil.example.com");
+    let result = verify_registration(&rp, &challenge, &authenticator.create(&challenge));
+    assert!(matches!(result, Err(PasskeyError::InvalidOrigin)));
+}
+
+#[test]
+fn forged_signature_refused() {
+    let rp = relying_party();
+    let mut authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let credential =
+        verify_registration(&rp, &challenge, &authenticator.create(&challenge)).unwrap();
+
+    let other = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let mut assertion = authenticator.get(&challenge);
+    let result = verify_assertion(&rp, &challenge, &assertion, &other.cose_key(), 0);
+    assert!(matches!(result, Err(PasskeyError::InvalidSignature)));
+
+    assertion.response.signature = base64url_encode(&[0x30, 0x06, 2, 1, 1, 2, 1, 1]);
+    let result = verify_assertion(&rp, &challenge, &assertion, &credential.public_key, 0);
+    assert!(matches!(result, Err(PasskeyError::InvalidSignature)));
+}
+
+#[test]
+fn unverified_user_refused() {
+    let rp = relying_party();
+    let mut authenticator = SoftAuthenticator::new();
+    authenticator.flags = UP;
+    let challenge = generate_challenge();
+    let result = verify_registration(&rp, &challenge, &authenticator.create(&challenge));
+    assert!(matches!(result, Err(PasskeyError::UserNotVerified)));
+}